@@ -0,0 +1,173 @@
+use crate::config::{ActionTrigger, TriggerTarget};
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use crate::webhook;
+use chrono::{DateTime, Utc};
+use rumqttc::{AsyncClient, QoS};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::Value;
+
+/// A button of a remote was pressed
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ActionEvent {
+    pub(crate) device_id: String,
+    /// e.g. `single`, `double` or `hold`
+    pub(crate) action: String,
+    pub(crate) received_at: DateTime<Utc>,
+}
+
+pub fn record(conn: &Connection, event: &ActionEvent) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO action_events (device_id, action, received_at) VALUES (?1, ?2, ?3)",
+        params![
+            event.device_id,
+            event.action,
+            timestamps::to_millis(event.received_at)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Events since `modifier`, e.g. `-7 days`, of one device or every device, latest first
+pub fn events(
+    pool: &SqlitePool,
+    device_id: Option<&str>,
+    modifier: &str,
+) -> rusqlite::Result<Vec<ActionEvent>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT device_id, action, received_at FROM action_events
+        WHERE (?1 IS NULL OR device_id = ?1) AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at DESC, id DESC",
+    )?;
+    let events = stmt
+        .query_map(params![device_id, modifier], |row| {
+            Ok(ActionEvent {
+                device_id: row.get(0)?,
+                action: row.get(1)?,
+                received_at: timestamps::get(row, 2)?,
+            })
+        })?
+        .collect();
+    events
+}
+
+/// Triggers configured for the device and action of the event
+pub fn matching<'a, 'b>(
+    triggers: &'a [ActionTrigger],
+    event: &'b ActionEvent,
+) -> impl Iterator<Item = &'a ActionTrigger> + 'b
+where
+    'a: 'b,
+{
+    triggers
+        .iter()
+        .filter(|trigger| trigger.device_id == event.device_id && trigger.action == event.action)
+}
+
+/// Strings are published as they are, anything else as JSON
+fn mqtt_payload(payload: &Value) -> String {
+    match payload {
+        Value::String(payload) => payload.clone(),
+        payload => payload.to_string(),
+    }
+}
+
+/// Runs the trigger without waiting for it. MQTT messages are queued without blocking
+/// because this is called from the writer, which runs with `spawn_blocking`, webhooks
+/// are sent in a task.
+pub fn run(trigger: &ActionTrigger, event: &ActionEvent, client: &AsyncClient) {
+    match &trigger.target {
+        TriggerTarget::Mqtt { topic, payload } => {
+            match client.try_publish(topic, QoS::AtLeastOnce, false, mqtt_payload(payload)) {
+                Ok(()) => println!(
+                    "Published {} for {} {}",
+                    topic, event.device_id, event.action
+                ),
+                Err(e) => println!("Failed to publish {}: {:?}", topic, e),
+            }
+        }
+        TriggerTarget::Webhook { url, payload } => {
+            let body = match payload {
+                Some(payload) => payload.to_string(),
+                None => serde_json::to_string(event).expect("Failed to serialize action event"),
+            };
+            let url = url.clone();
+            tokio::spawn(async move {
+                match webhook::post(&url, body).await {
+                    Ok(()) => println!("Sent webhook {}", url),
+                    Err(e) => println!("Failed to send webhook {}: {}", url, e),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+    use serde_json::json;
+
+    fn event(device_id: &str, action: &str) -> ActionEvent {
+        ActionEvent {
+            device_id: device_id.to_string(),
+            action: action.to_string(),
+            received_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_triggers_match_device_and_action() {
+        let triggers: Vec<ActionTrigger> = serde_json::from_value(json!([
+            {
+                "device_id": "hall_remote",
+                "action": "single",
+                "mqtt": { "topic": "zigbee2mqtt/hall_light/set", "payload": { "state": "TOGGLE" } }
+            },
+            {
+                "device_id": "hall_remote",
+                "action": "hold",
+                "webhook": { "url": "http://localhost:8123/api/webhook/hall" }
+            }
+        ]))
+        .unwrap();
+
+        let single = event("hall_remote", "single");
+        let matched: Vec<_> = matching(&triggers, &single).collect();
+        assert_eq!(matched.len(), 1);
+        match &matched[0].target {
+            TriggerTarget::Mqtt { topic, payload } => {
+                assert_eq!(topic, "zigbee2mqtt/hall_light/set");
+                assert_eq!(mqtt_payload(payload), r#"{"state":"TOGGLE"}"#);
+            }
+            target => panic!("Unexpected target {:?}", target),
+        }
+        assert_eq!(mqtt_payload(&json!("ON")), "ON");
+        assert_eq!(
+            matching(&triggers, &event("hall_remote", "double")).count(),
+            0
+        );
+        assert_eq!(
+            matching(&triggers, &event("kitchen_remote", "hold")).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_event_log() {
+        let pool = get_test_pool();
+        record(&get_conn(&pool), &event("hall_remote", "single")).unwrap();
+        record(&get_conn(&pool), &event("hall_remote", "double")).unwrap();
+        record(&get_conn(&pool), &event("kitchen_remote", "hold")).unwrap();
+
+        let hall: Vec<String> = events(&pool, Some("hall_remote"), "-1 day")
+            .unwrap()
+            .into_iter()
+            .map(|event| event.action)
+            .collect();
+        assert_eq!(hall, vec!["double", "single"]);
+        assert_eq!(events(&pool, None, "-1 day").unwrap().len(), 3);
+    }
+}
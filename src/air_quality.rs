@@ -0,0 +1,236 @@
+use crate::alerts::Metric;
+use crate::config::{AirQualityConfig, AirQualityThresholds};
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+/// Values of an air quality sensor, whichever of them the sensor reports
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct AirQualityReport {
+    /// ppm
+    pub(crate) co2: Option<f64>,
+    /// ppb
+    pub(crate) voc: Option<f64>,
+    /// µg/m³
+    pub(crate) pm25: Option<f64>,
+    /// µg/m³, spelled like zigbee2mqtt does
+    pub(crate) formaldehyd: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Band {
+    Good,
+    Moderate,
+    Poor,
+}
+
+impl AirQualityThresholds {
+    pub fn band(&self, value: f64) -> Band {
+        if value >= self.poor {
+            Band::Poor
+        } else if value >= self.moderate {
+            Band::Moderate
+        } else {
+            Band::Good
+        }
+    }
+}
+
+/// A value of a report with its band
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BandedValue {
+    pub(crate) metric: &'static str,
+    pub(crate) value: f64,
+    pub(crate) unit: &'static str,
+    pub(crate) band: Band,
+}
+
+/// Banded values of a report and whether the room should be aired
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AirQualityAssessment {
+    pub(crate) values: Vec<BandedValue>,
+    /// The worst band of the values
+    pub(crate) band: Band,
+    /// Any value is poor
+    pub(crate) ventilation_needed: bool,
+}
+
+impl AirQualityReport {
+    /// Values with their thresholds and units
+    fn metrics(
+        &self,
+        config: &AirQualityConfig,
+    ) -> [(Metric, Option<f64>, AirQualityThresholds, &'static str); 4] {
+        [
+            (Metric::Co2, self.co2, config.co2, "ppm"),
+            (Metric::Voc, self.voc, config.voc, "ppb"),
+            (Metric::Pm25, self.pm25, config.pm25, "µg/m³"),
+            (Metric::Formaldehyd, self.formaldehyd, config.formaldehyd, "µg/m³"),
+        ]
+    }
+
+    /// None when the report has no values
+    pub fn assess(&self, config: &AirQualityConfig) -> Option<AirQualityAssessment> {
+        let values: Vec<BandedValue> = self
+            .metrics(config)
+            .into_iter()
+            .filter_map(|(metric, value, thresholds, unit)| {
+                let value = value?;
+                Some(BandedValue {
+                    metric: metric.as_str(),
+                    value,
+                    unit,
+                    band: thresholds.band(value),
+                })
+            })
+            .collect();
+        let band = values
+            .iter()
+            .map(|value| value.band)
+            .reduce(|worst, band| if band > worst { band } else { worst })?;
+
+        Some(AirQualityAssessment {
+            values,
+            band,
+            ventilation_needed: band == Band::Poor,
+        })
+    }
+
+    /// Values for the alert rules, `ventilation_needed` is 1 when the room should be aired
+    pub fn values(&self, config: &AirQualityConfig) -> Vec<(Metric, f64)> {
+        let mut values: Vec<(Metric, f64)> = self
+            .metrics(config)
+            .into_iter()
+            .filter_map(|(metric, value, _, _)| value.map(|value| (metric, value)))
+            .collect();
+        if let Some(assessment) = self.assess(config) {
+            values.push((
+                Metric::VentilationNeeded,
+                if assessment.ventilation_needed { 1.0 } else { 0.0 },
+            ));
+        }
+        values
+    }
+}
+
+pub fn record(
+    conn: &Connection,
+    device_id: &str,
+    report: &AirQualityReport,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO air_quality_data (device_id, co2, voc, pm25, formaldehyd, received_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            device_id,
+            report.co2,
+            report.voc,
+            report.pm25,
+            report.formaldehyd,
+            timestamps::to_millis(received_at)
+        ],
+    )?;
+    Ok(())
+}
+
+fn report_from_row(row: &rusqlite::Row) -> rusqlite::Result<(DateTime<Utc>, AirQualityReport)> {
+    Ok((
+        timestamps::get(row, 0)?,
+        AirQualityReport {
+            co2: row.get(1)?,
+            voc: row.get(2)?,
+            pm25: row.get(3)?,
+            formaldehyd: row.get(4)?,
+        },
+    ))
+}
+
+/// Reports of a sensor since `modifier`, e.g. `-7 days`, oldest first
+pub fn history(
+    pool: &SqlitePool,
+    device_id: &str,
+    modifier: &str,
+) -> rusqlite::Result<Vec<(DateTime<Utc>, AirQualityReport)>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT received_at, co2, voc, pm25, formaldehyd FROM air_quality_data
+        WHERE device_id = ?1 AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at, id",
+    )?;
+    let history = stmt
+        .query_map(params![device_id, modifier], report_from_row)?
+        .collect();
+    history
+}
+
+pub fn latest(
+    pool: &SqlitePool,
+    device_id: &str,
+) -> rusqlite::Result<Option<(DateTime<Utc>, AirQualityReport)>> {
+    get_conn(pool)
+        .query_row(
+            "SELECT received_at, co2, voc, pm25, formaldehyd FROM air_quality_data
+            WHERE device_id = ?1 ORDER BY received_at DESC, id DESC LIMIT 1",
+            [device_id],
+            report_from_row,
+        )
+        .optional()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+
+    #[test]
+    fn test_bands_and_ventilation() {
+        let config = AirQualityConfig::default();
+        let report = AirQualityReport {
+            co2: Some(1200.0),
+            pm25: Some(5.0),
+            ..Default::default()
+        };
+        let assessment = report.assess(&config).unwrap();
+        assert_eq!(assessment.band, Band::Moderate);
+        assert!(!assessment.ventilation_needed);
+        assert_eq!(assessment.values[0].band, Band::Moderate);
+        assert_eq!(assessment.values[1].band, Band::Good);
+        assert_eq!(
+            report.values(&config).last(),
+            Some(&(Metric::VentilationNeeded, 0.0))
+        );
+
+        let report = AirQualityReport {
+            co2: Some(1400.0),
+            ..Default::default()
+        };
+        assert!(report.assess(&config).unwrap().ventilation_needed);
+        assert_eq!(
+            report.values(&config),
+            vec![(Metric::Co2, 1400.0), (Metric::VentilationNeeded, 1.0)]
+        );
+
+        assert!(AirQualityReport::default().assess(&config).is_none());
+    }
+
+    #[test]
+    fn test_history() {
+        let pool = get_test_pool();
+        let report = AirQualityReport {
+            co2: Some(800.0),
+            voc: Some(120.0),
+            pm25: Some(4.0),
+            formaldehyd: Some(10.0),
+        };
+        record(&get_conn(&pool), "bedroom", &report, Utc::now()).unwrap();
+
+        assert_eq!(history(&pool, "bedroom", "-1 day").unwrap().len(), 1);
+        let (_, latest) = latest(&pool, "bedroom").unwrap().unwrap();
+        assert_eq!(latest, report);
+        assert!(super::latest(&pool, "kitchen").unwrap().is_none());
+    }
+}
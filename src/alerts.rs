@@ -0,0 +1,340 @@
+use crate::config::AlertRule;
+use crate::conn::{get_conn, SqlitePool};
+use crate::events::LiveEvent;
+use crate::model::{Alert, SensorData};
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Temperature,
+    Humidity,
+    Linkquality,
+    DewPoint,
+    AbsoluteHumidity,
+    HeatIndex,
+    VaporPressureDeficit,
+    /// Relative humidity at the coldest surface of the room
+    SurfaceHumidity,
+    /// Degrees between the surface temperature and the dew point, negative when condensing
+    CondensationMargin,
+    /// 0 low, 1 elevated, 2 high
+    MouldRisk,
+    /// %
+    Battery,
+    /// mV
+    Voltage,
+    /// Estimated from the discharge trend
+    BatteryDaysRemaining,
+    /// ppm
+    Co2,
+    /// ppb
+    Voc,
+    /// µg/m³
+    Pm25,
+    /// µg/m³
+    Formaldehyd,
+    /// 1 when any air quality value is poor, otherwise 0
+    VentilationNeeded,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 18] = [
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::Linkquality,
+        Metric::DewPoint,
+        Metric::AbsoluteHumidity,
+        Metric::HeatIndex,
+        Metric::VaporPressureDeficit,
+        Metric::SurfaceHumidity,
+        Metric::CondensationMargin,
+        Metric::MouldRisk,
+        Metric::Battery,
+        Metric::Voltage,
+        Metric::BatteryDaysRemaining,
+        Metric::Co2,
+        Metric::Voc,
+        Metric::Pm25,
+        Metric::Formaldehyd,
+        Metric::VentilationNeeded,
+    ];
+
+    /// None for metrics that are not part of a temperature and humidity reading
+    pub fn value(&self, data: &SensorData) -> Option<f64> {
+        match self {
+            Metric::Temperature => Some(data.temperature as f64),
+            Metric::Humidity => Some(data.humidity as f64),
+            Metric::Linkquality => Some(data.linkquality as f64),
+            Metric::DewPoint => Some(data.derived.dew_point),
+            Metric::AbsoluteHumidity => Some(data.derived.absolute_humidity),
+            Metric::HeatIndex => Some(data.derived.heat_index),
+            Metric::VaporPressureDeficit => Some(data.derived.vapor_pressure_deficit),
+            Metric::SurfaceHumidity
+            | Metric::CondensationMargin
+            | Metric::MouldRisk
+            | Metric::Battery
+            | Metric::Voltage
+            | Metric::BatteryDaysRemaining
+            | Metric::Co2
+            | Metric::Voc
+            | Metric::Pm25
+            | Metric::Formaldehyd
+            | Metric::VentilationNeeded => None,
+        }
+    }
+
+    /// Values in °C that are converted to the display unit
+    pub fn is_temperature(&self) -> bool {
+        matches!(self, Metric::Temperature | Metric::DewPoint | Metric::HeatIndex)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::Linkquality => "linkquality",
+            Metric::DewPoint => "dew_point",
+            Metric::AbsoluteHumidity => "absolute_humidity",
+            Metric::HeatIndex => "heat_index",
+            Metric::VaporPressureDeficit => "vapor_pressure_deficit",
+            Metric::SurfaceHumidity => "surface_humidity",
+            Metric::CondensationMargin => "condensation_margin",
+            Metric::MouldRisk => "mould_risk",
+            Metric::Battery => "battery",
+            Metric::Voltage => "voltage",
+            Metric::BatteryDaysRemaining => "battery_days_remaining",
+            Metric::Co2 => "co2",
+            Metric::Voc => "voc",
+            Metric::Pm25 => "pm25",
+            Metric::Formaldehyd => "formaldehyd",
+            Metric::VentilationNeeded => "ventilation_needed",
+        }
+    }
+
+    pub fn from_db(value: &str) -> Option<Metric> {
+        Metric::ALL.into_iter().find(|m| m.as_str() == value)
+    }
+}
+
+/// Values of every metric that a single reading has
+pub fn reading_values(data: &SensorData) -> Vec<(Metric, f64)> {
+    Metric::ALL
+        .into_iter()
+        .filter_map(|metric| metric.value(data).map(|value| (metric, value)))
+        .collect()
+}
+
+pub fn outside_limits(value: f64, min: Option<f64>, max: Option<f64>) -> bool {
+    min.is_some_and(|min| value < min) || max.is_some_and(|max| value > max)
+}
+
+impl AlertRule {
+    fn applies_to(&self, device_id: &str) -> bool {
+        self.device_id.as_deref().is_none_or(|id| id == device_id)
+    }
+
+    fn is_violated(&self, value: f64) -> bool {
+        outside_limits(value, self.min, self.max)
+    }
+}
+
+/// Check the alert rules against new values of a device and record the alerts that start or end
+/// at `received_at`, the time of the values. Rules for metrics without a value are skipped.
+/// Returns an event for every alert that changed.
+pub fn evaluate(
+    conn: &Connection,
+    rules: &[AlertRule],
+    device_id: &str,
+    values: &[(Metric, f64)],
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<Vec<LiveEvent>> {
+    let mut events = Vec::new();
+
+    for rule in rules.iter().filter(|r| r.applies_to(device_id)) {
+        let value = match values.iter().find(|(metric, _)| *metric == rule.metric) {
+            Some((_, value)) => *value,
+            None => continue,
+        };
+
+        let active_alert: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM alerts WHERE rule = ?1 AND device_id = ?2 AND ended_at IS NULL",
+                params![rule.name, device_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match (rule.is_violated(value), active_alert) {
+            (true, None) => conn.execute(
+                "INSERT INTO alerts (rule, device_id, value, started_at) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    rule.name,
+                    device_id,
+                    value,
+                    timestamps::to_millis(received_at)
+                ],
+            )?,
+            (false, Some(id)) => conn.execute(
+                "UPDATE alerts SET ended_at = ?2 WHERE id = ?1",
+                params![id, timestamps::to_millis(received_at)],
+            )?,
+            _ => continue,
+        };
+
+        events.push(LiveEvent::Alert {
+            rule: rule.name.clone(),
+            device_id: device_id.to_string(),
+            value,
+            active: active_alert.is_none(),
+        });
+    }
+
+    Ok(events)
+}
+
+pub fn active_alerts(pool: &SqlitePool) -> rusqlite::Result<Vec<Alert>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT rule, device_id, value, started_at FROM alerts
+        WHERE ended_at IS NULL AND started_at IS NOT NULL ORDER BY started_at",
+    )?;
+    let alerts = stmt.query_map([], Alert::from_row)?.collect();
+    alerts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comfort::DerivedMetrics;
+    use crate::conn::get_test_pool;
+
+    fn reading(device_id: &str, temperature: f32, humidity: i32) -> SensorData {
+        SensorData {
+            temperature,
+            humidity,
+            linkquality: 100,
+            device_id: device_id.to_string(),
+            received_at: chrono::Utc::now(),
+            derived: DerivedMetrics::new(temperature as f64, humidity as f64),
+        }
+    }
+
+    fn check(pool: &SqlitePool, rules: &[AlertRule], data: SensorData) -> Vec<LiveEvent> {
+        evaluate(
+            &get_conn(pool),
+            rules,
+            &data.device_id,
+            &reading_values(&data),
+            data.received_at,
+        )
+        .unwrap()
+    }
+
+    fn humidity_rule() -> AlertRule {
+        AlertRule {
+            name: "Bathroom humidity".to_string(),
+            device_id: Some("bathroom".to_string()),
+            metric: Metric::Humidity,
+            min: None,
+            max: Some(70.0),
+        }
+    }
+
+    #[test]
+    fn test_alert_starts_and_ends() {
+        let pool = get_test_pool();
+        let rules = vec![humidity_rule()];
+
+        // The alert starts at the time of the reading, not when it is handled
+        let mut data = reading("bathroom", 22.0, 80);
+        data.received_at = timestamps::parse("2024-07-01T12:00:00Z").unwrap();
+        let events = check(&pool, &rules, data);
+        assert!(matches!(
+            events.as_slice(),
+            [LiveEvent::Alert { active: true, .. }]
+        ));
+        let alerts = active_alerts(&pool).unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(
+            alerts[0].started_at,
+            timestamps::parse("2024-07-01T12:00:00Z").unwrap()
+        );
+
+        // Still above the limit, the alert is already active
+        let events = check(&pool, &rules, reading("bathroom", 22.0, 85));
+        assert!(events.is_empty());
+
+        let events = check(&pool, &rules, reading("bathroom", 22.0, 60));
+        assert!(matches!(
+            events.as_slice(),
+            [LiveEvent::Alert { active: false, .. }]
+        ));
+        assert!(active_alerts(&pool).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_alert_rule_device_filter() {
+        let pool = get_test_pool();
+        let rules = vec![
+            humidity_rule(),
+            AlertRule {
+                name: "Freezing".to_string(),
+                device_id: None,
+                metric: Metric::Temperature,
+                min: Some(0.0),
+                max: None,
+            },
+        ];
+
+        let events = check(&pool, &rules, reading("kitchen", -1.0, 80));
+        assert_eq!(events.len(), 1);
+        let alerts = active_alerts(&pool).unwrap();
+        assert_eq!(alerts[0].rule, "Freezing");
+        assert_eq!(alerts[0].device_id, "kitchen");
+    }
+
+    #[test]
+    fn test_alert_on_derived_metric() {
+        let pool = get_test_pool();
+        let rules = vec![AlertRule {
+            name: "Condensation".to_string(),
+            device_id: None,
+            metric: Metric::DewPoint,
+            min: None,
+            max: Some(12.0),
+        }];
+
+        // Dew point is about 9.3 °C
+        assert!(check(&pool, &rules, reading("kitchen", 20.0, 50)).is_empty());
+        // Dew point is about 13.9 °C
+        assert_eq!(check(&pool, &rules, reading("kitchen", 20.0, 75)).len(), 1);
+        assert_eq!(Metric::from_db("dew_point"), Some(Metric::DewPoint));
+    }
+
+    #[test]
+    fn test_rule_without_value_is_skipped() {
+        let pool = get_test_pool();
+        let rules = vec![AlertRule {
+            name: "Mould".to_string(),
+            device_id: None,
+            metric: Metric::MouldRisk,
+            min: None,
+            max: Some(0.0),
+        }];
+
+        assert!(check(&pool, &rules, reading("kitchen", 20.0, 50)).is_empty());
+        let events = evaluate(
+            &get_conn(&pool),
+            &rules,
+            "kitchen",
+            &[(Metric::MouldRisk, 2.0)],
+            chrono::Utc::now(),
+        )
+        .unwrap();
+        assert_eq!(events.len(), 1);
+    }
+}
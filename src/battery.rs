@@ -0,0 +1,221 @@
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+/// Days of battery levels that the discharge trend is calculated from
+const TREND_DAYS: u32 = 30;
+/// Shorter trends are not estimated, battery levels are reported in coarse steps
+const MIN_TREND_HOURS: f64 = 24.0;
+/// Percentage points that the level has to rise by to count as a new battery
+const REPLACEMENT_INCREASE: f64 = 10.0;
+
+/// Latest battery report of a device
+#[derive(Serialize, Debug)]
+pub struct BatteryStatus {
+    pub(crate) device_id: String,
+    /// %
+    pub(crate) battery: f64,
+    /// mV
+    pub(crate) voltage: Option<i64>,
+    pub(crate) received_at: DateTime<Utc>,
+    /// None when the battery is not discharging or there isn't enough history
+    pub(crate) days_remaining: Option<f64>,
+}
+
+pub fn record(
+    conn: &Connection,
+    device_id: &str,
+    battery: f64,
+    voltage: Option<i64>,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO battery_data (device_id, battery, voltage, received_at) VALUES (?1, ?2, ?3, ?4)",
+        params![device_id, battery, voltage, timestamps::to_millis(received_at)],
+    )?;
+    Ok(())
+}
+
+/// Battery levels of a device since `modifier`, e.g. `-7 days`, oldest first
+pub fn levels(
+    conn: &Connection,
+    device_id: &str,
+    modifier: &str,
+) -> rusqlite::Result<Vec<(DateTime<Utc>, f64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT received_at, battery FROM battery_data
+        WHERE device_id = ?1 AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at",
+    )?;
+    let levels = stmt
+        .query_map(params![device_id, modifier], |row| {
+            Ok((timestamps::get(row, 0)?, row.get(1)?))
+        })?
+        .collect();
+    levels
+}
+
+/// Percentage points lost per day, from a least squares fit of the levels
+/// since the battery was last replaced
+pub fn discharge_rate(levels: &[(DateTime<Utc>, f64)]) -> Option<f64> {
+    let replaced = levels
+        .windows(2)
+        .rposition(|pair| pair[1].1 - pair[0].1 >= REPLACEMENT_INCREASE)
+        .map_or(0, |i| i + 1);
+    let levels = &levels[replaced..];
+    let (first, last) = (levels.first()?.0, levels.last()?.0);
+    if ((last - first).num_seconds() as f64) < MIN_TREND_HOURS * 3600.0 {
+        return None;
+    }
+
+    let points: Vec<(f64, f64)> = levels
+        .iter()
+        .map(|(time, level)| ((*time - first).num_seconds() as f64 / 86400.0, *level))
+        .collect();
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+
+    Some(-covariance / variance)
+}
+
+/// Days until the battery is empty at the current discharge rate
+pub fn days_remaining(conn: &Connection, device_id: &str) -> rusqlite::Result<Option<f64>> {
+    let levels = levels(conn, device_id, &format!("-{} days", TREND_DAYS))?;
+    let latest = match levels.last() {
+        Some((_, level)) => *level,
+        None => return Ok(None),
+    };
+
+    Ok(discharge_rate(&levels)
+        .filter(|rate| *rate > 0.0)
+        .map(|rate| (latest / rate * 10.0).round() / 10.0))
+}
+
+/// Latest report of every device that reports its battery, lowest battery first
+pub fn overview(pool: &SqlitePool) -> rusqlite::Result<Vec<BatteryStatus>> {
+    let latest = {
+        let conn = get_conn(pool);
+        let mut stmt = conn.prepare(
+            "SELECT device_id, battery, voltage, received_at FROM battery_data AS b
+            WHERE id = (
+                SELECT id FROM battery_data WHERE device_id = b.device_id
+                ORDER BY received_at DESC LIMIT 1
+            )
+            ORDER BY battery, device_id",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(BatteryStatus {
+                    device_id: row.get(0)?,
+                    battery: row.get(1)?,
+                    voltage: row.get(2)?,
+                    received_at: timestamps::get(row, 3)?,
+                    days_remaining: None,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    };
+
+    latest
+        .into_iter()
+        .map(|mut status| {
+            status.days_remaining = days_remaining(&get_conn(pool), &status.device_id)?;
+            Ok(status)
+        })
+        .collect()
+}
+
+/// Latest battery report of a device
+pub fn latest(pool: &SqlitePool, device_id: &str) -> rusqlite::Result<Option<BatteryStatus>> {
+    let status = get_conn(pool)
+        .query_row(
+            "SELECT device_id, battery, voltage, received_at FROM battery_data
+            WHERE device_id = ?1 ORDER BY received_at DESC LIMIT 1",
+            [device_id],
+            |row| {
+                Ok(BatteryStatus {
+                    device_id: row.get(0)?,
+                    battery: row.get(1)?,
+                    voltage: row.get(2)?,
+                    received_at: timestamps::get(row, 3)?,
+                    days_remaining: None,
+                })
+            },
+        )
+        .optional()?;
+
+    match status {
+        Some(mut status) => {
+            status.days_remaining = days_remaining(&get_conn(pool), device_id)?;
+            Ok(Some(status))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+
+    fn days_ago(days: i64) -> DateTime<Utc> {
+        Utc::now() - chrono::Duration::days(days)
+    }
+
+    #[test]
+    fn test_discharge_rate() {
+        let levels = vec![
+            (days_ago(10), 100.0),
+            (days_ago(5), 95.0),
+            (days_ago(0), 90.0),
+        ];
+        assert!((discharge_rate(&levels).unwrap() - 1.0).abs() < 0.001);
+
+        // Too short to tell
+        assert_eq!(discharge_rate(&[(days_ago(0), 100.0)]), None);
+    }
+
+    #[test]
+    fn test_discharge_rate_after_replacement() {
+        let levels = vec![
+            (days_ago(25), 30.0),
+            (days_ago(20), 20.0),
+            (days_ago(10), 100.0),
+            (days_ago(5), 96.0),
+            (days_ago(0), 90.0),
+        ];
+        assert!((discharge_rate(&levels).unwrap() - 1.0).abs() < 0.001);
+
+        // Until there is a day of levels of the new battery
+        let replaced_today = vec![(days_ago(5), 20.0), (days_ago(0), 100.0)];
+        assert_eq!(discharge_rate(&replaced_today), None);
+    }
+
+    #[test]
+    fn test_overview_is_sorted_by_lowest_battery() {
+        let pool = get_test_pool();
+        record(&get_conn(&pool), "kitchen", 100.0, Some(3000), days_ago(20)).unwrap();
+        record(&get_conn(&pool), "kitchen", 80.0, Some(2900), days_ago(0)).unwrap();
+        record(&get_conn(&pool), "bathroom", 50.0, None, days_ago(0)).unwrap();
+        record(&get_conn(&pool), "garage", 95.0, None, days_ago(0)).unwrap();
+
+        let overview = overview(&pool).unwrap();
+        let devices: Vec<&str> = overview.iter().map(|s| s.device_id.as_str()).collect();
+        assert_eq!(devices, vec!["bathroom", "kitchen", "garage"]);
+
+        // 1 % a day
+        assert_eq!(overview[1].days_remaining, Some(80.0));
+        assert_eq!(overview[1].voltage, Some(2900));
+        assert_eq!(overview[0].days_remaining, None);
+        assert!(latest(&pool, "attic").unwrap().is_none());
+    }
+}
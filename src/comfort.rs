@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+
+/// Magnus formula constants over water (Sonntag 1990)
+const MAGNUS_A: f64 = 17.62;
+const MAGNUS_B: f64 = 243.12;
+
+/// Metrics calculated from the temperature and relative humidity of a reading.
+/// Values that can't be calculated, e.g. dew point at 0 % humidity, are serialized as null.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct DerivedMetrics {
+    /// °C
+    pub(crate) dew_point: f64,
+    /// g/m³
+    pub(crate) absolute_humidity: f64,
+    /// Apparent temperature in °C, the same as the temperature when it's not warm
+    pub(crate) heat_index: f64,
+    /// kPa
+    pub(crate) vapor_pressure_deficit: f64,
+}
+
+impl DerivedMetrics {
+    pub fn new(temperature: f64, humidity: f64) -> DerivedMetrics {
+        DerivedMetrics {
+            dew_point: round(dew_point(temperature, humidity)),
+            absolute_humidity: round(absolute_humidity(temperature, humidity)),
+            heat_index: round(heat_index(temperature, humidity)),
+            vapor_pressure_deficit: round(vapor_pressure_deficit(temperature, humidity)),
+        }
+    }
+}
+
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Saturation vapor pressure in hPa
+pub fn saturation_vapor_pressure(temperature: f64) -> f64 {
+    6.112 * (MAGNUS_A * temperature / (MAGNUS_B + temperature)).exp()
+}
+
+/// Actual vapor pressure in hPa
+pub fn vapor_pressure(temperature: f64, humidity: f64) -> f64 {
+    humidity / 100.0 * saturation_vapor_pressure(temperature)
+}
+
+pub fn dew_point(temperature: f64, humidity: f64) -> f64 {
+    let gamma = (vapor_pressure(temperature, humidity) / 6.112).ln();
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+pub fn absolute_humidity(temperature: f64, humidity: f64) -> f64 {
+    216.7 * vapor_pressure(temperature, humidity) / (273.15 + temperature)
+}
+
+/// NOAA heat index (Rothfusz regression with its adjustments), calculated in °F
+pub fn heat_index(temperature: f64, humidity: f64) -> f64 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let index = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut index = -42.379 + 2.04901523 * t + 10.14333127 * rh
+            - 0.22475541 * t * rh
+            - 0.00683783 * t * t
+            - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh
+            + 0.00085282 * t * rh * rh
+            - 0.00000199 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            index += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        index
+    };
+
+    // The formula is only meant for warm weather
+    if index < t {
+        temperature
+    } else {
+        (index - 32.0) * 5.0 / 9.0
+    }
+}
+
+/// How much more water the air could hold, in kPa
+pub fn vapor_pressure_deficit(temperature: f64, humidity: f64) -> f64 {
+    (saturation_vapor_pressure(temperature) - vapor_pressure(temperature, humidity)) / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.1,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_derived_metrics() {
+        let metrics = DerivedMetrics::new(20.0, 50.0);
+        assert_close(metrics.dew_point, 9.3);
+        assert_close(metrics.absolute_humidity, 8.6);
+        assert_close(metrics.vapor_pressure_deficit, 1.17);
+        assert_eq!(metrics.heat_index, 20.0);
+
+        // Saturated air
+        assert_close(dew_point(15.0, 100.0), 15.0);
+        assert_close(vapor_pressure_deficit(15.0, 100.0), 0.0);
+    }
+
+    #[test]
+    fn test_heat_index() {
+        // NOAA table: 90 °F at 70 % feels like 106 °F
+        assert_close(heat_index(32.22, 70.0), 41.1);
+        // 80 °F at 40 % feels like 80 °F
+        assert_close(heat_index(26.67, 40.0), 26.67);
+    }
+
+    #[test]
+    fn test_no_humidity() {
+        let metrics = DerivedMetrics::new(20.0, 0.0);
+        assert!(!metrics.dew_point.is_finite());
+        assert_eq!(
+            serde_json::to_value(metrics).unwrap()["dew_point"],
+            serde_json::Value::Null
+        );
+    }
+}
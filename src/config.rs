@@ -1,4 +1,9 @@
+use crate::alerts::Metric;
+use crate::web::auth::Scope;
+use chrono::{NaiveTime, Weekday};
 use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -7,13 +12,443 @@ pub struct Config {
 
     pub(crate) mqtt_ip: String,
     pub(crate) mqtt_port: u16,
-    /// JSON array of strings
+    /// JSON array of strings, topic filters with `+` and `#` wildcards are supported
     pub(crate) mqtt_topics: Vec<String>,
+    /// Topic filters of messages that are ignored, e.g. the bridge topics and `/set` echoes
+    /// of zigbee2mqtt when subscribing to `zigbee2mqtt/#`
+    #[serde(default)]
+    pub(crate) mqtt_exclude_topics: Option<Vec<String>>,
+    /// Base topic of zigbee2mqtt for requests to the bridge
+    #[serde(default = "default_zigbee2mqtt_topic")]
+    pub(crate) zigbee2mqtt_topic: String,
 
     pub(crate) sqlite_database: String,
+    /// Messages that wait for the database writer before the MQTT client stops reading more
+    #[serde(default = "default_ingest_queue_size")]
+    pub(crate) ingest_queue_size: usize,
+    /// File for the messages that can't be written to the database, `<sqlite_database>.spool` when missing
+    #[serde(default)]
+    pub(crate) spool_file: Option<String>,
 
     pub(crate) web_server_ip: String,
     pub(crate) web_server_port: u16,
+
+    /// Authentication for the web server, disabled when missing
+    #[serde(default)]
+    pub(crate) auth: Option<AuthConfig>,
+
+    /// Serve HTTPS instead of HTTP, disabled when missing
+    #[serde(default)]
+    pub(crate) tls: Option<TlsConfig>,
+
+    /// Minutes without messages before a device is considered offline
+    #[serde(default = "default_device_offline_minutes")]
+    pub(crate) device_offline_minutes: u32,
+
+    /// Units, number and date formatting of the web pages
+    #[serde(default)]
+    pub(crate) display: DisplayConfig,
+
+    /// Directory with templates that replace the built-in ones with the same name
+    #[serde(default)]
+    pub(crate) templates_dir: Option<String>,
+
+    /// Surface temperatures for the mould and condensation risk, disabled when missing
+    #[serde(default)]
+    pub(crate) mould: Option<MouldConfig>,
+
+    /// Limits for sensor readings that raise alerts
+    #[serde(default)]
+    pub(crate) alert_rules: Vec<AlertRule>,
+
+    /// Values that are rejected instead of stored
+    #[serde(default)]
+    pub(crate) plausibility_rules: Vec<PlausibilityRule>,
+
+    /// MQTT messages or webhooks sent when a button is pressed
+    #[serde(default)]
+    pub(crate) action_triggers: Vec<ActionTrigger>,
+
+    /// Setpoints that are sent to the radiator valves at the given local times
+    #[serde(default)]
+    pub(crate) heating_schedule: Vec<HeatingSchedule>,
+
+    /// Price of electricity for the energy reports, costs are not shown when missing
+    #[serde(default)]
+    pub(crate) tariff: Option<TariffConfig>,
+
+    /// Thresholds of the air quality bands
+    #[serde(default)]
+    pub(crate) air_quality: AirQualityConfig,
+
+    /// Notifications of water leak, smoke and tamper alarms
+    #[serde(default)]
+    pub(crate) critical_alarms: CriticalAlarmConfig,
+
+    /// Device ids and values of messages that are not from zigbee2mqtt, the first matching topic applies
+    #[serde(default)]
+    pub(crate) payload_mappings: Vec<PayloadMapping>,
+}
+
+fn default_device_offline_minutes() -> u32 {
+    120
+}
+
+fn default_ingest_queue_size() -> usize {
+    1000
+}
+
+fn default_zigbee2mqtt_topic() -> String {
+    "zigbee2mqtt".to_string()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AuthConfig {
+    /// Users that can log in with HTTP basic authentication
+    #[serde(default)]
+    pub(crate) users: Vec<UserConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UserConfig {
+    pub(crate) username: String,
+    /// bcrypt hash of the password
+    pub(crate) password_hash: String,
+    #[serde(default)]
+    pub(crate) scope: Scope,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain
+    pub(crate) cert_path: String,
+    /// PEM file with the private key
+    pub(crate) key_path: String,
+    /// Port for a plain HTTP listener that redirects to HTTPS
+    #[serde(default)]
+    pub(crate) redirect_http_port: Option<u16>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DisplayConfig {
+    #[serde(default)]
+    pub(crate) temperature_unit: TemperatureUnit,
+    /// Decimals of temperatures and calculated values
+    #[serde(default = "default_precision")]
+    pub(crate) precision: usize,
+    /// chrono format string, see https://docs.rs/chrono/latest/chrono/format/strftime
+    #[serde(default = "default_datetime_format")]
+    pub(crate) datetime_format: String,
+    /// IANA time zone like Europe/Helsinki, the time zone of the system when missing
+    #[serde(default)]
+    pub(crate) timezone: Option<String>,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            temperature_unit: TemperatureUnit::default(),
+            precision: default_precision(),
+            datetime_format: default_datetime_format(),
+            timezone: None,
+        }
+    }
+}
+
+fn default_precision() -> usize {
+    1
+}
+
+fn default_datetime_format() -> String {
+    "%Y-%m-%d %H:%M:%S".to_string()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MouldConfig {
+    /// Device measuring the outdoor temperature
+    #[serde(default)]
+    pub(crate) outdoor_device_id: Option<String>,
+    /// Outdoor temperature to use when there are no readings from `outdoor_device_id`
+    #[serde(default)]
+    pub(crate) outdoor_temperature: Option<f64>,
+    /// Temperature factor of the coldest surface, 0 is as cold as outside and 1 as warm as the room
+    #[serde(default = "default_temperature_factor")]
+    pub(crate) temperature_factor: f64,
+    /// Measured surface temperatures by device id, used instead of the outdoor temperature
+    #[serde(default)]
+    pub(crate) surface_temperatures: HashMap<String, f64>,
+}
+
+fn default_temperature_factor() -> f64 {
+    // Minimum for new buildings in DIN 4108-2 to avoid mould
+    0.7
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AlertRule {
+    /// Identifies the rule in alerts
+    pub(crate) name: String,
+    /// Device the rule applies to, all devices when missing
+    #[serde(default)]
+    pub(crate) device_id: Option<String>,
+    pub(crate) metric: Metric,
+    #[serde(default)]
+    pub(crate) min: Option<f64>,
+    #[serde(default)]
+    pub(crate) max: Option<f64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PlausibilityRule {
+    /// Device the rule applies to, all devices when missing
+    #[serde(default)]
+    pub(crate) device_id: Option<String>,
+    pub(crate) metric: Metric,
+    #[serde(default)]
+    pub(crate) min: Option<f64>,
+    #[serde(default)]
+    pub(crate) max: Option<f64>,
+    /// Largest change per minute since the last accepted value
+    #[serde(default)]
+    pub(crate) max_change_per_minute: Option<f64>,
+    /// Largest difference to the median of the last `spike_window` values
+    #[serde(default)]
+    pub(crate) max_spike: Option<f64>,
+    #[serde(default = "default_spike_window")]
+    pub(crate) spike_window: usize,
+}
+
+fn default_spike_window() -> usize {
+    5
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ActionTrigger {
+    /// Remote or button that sends the action
+    pub(crate) device_id: String,
+    /// Value of `action`, e.g. `single`, `double` or `hold`
+    pub(crate) action: String,
+    #[serde(flatten)]
+    pub(crate) target: TriggerTarget,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerTarget {
+    /// Publishes `payload`, strings as they are and anything else as JSON
+    Mqtt { topic: String, payload: Value },
+    /// POSTs `payload` as JSON, or the action event when missing
+    Webhook {
+        url: String,
+        #[serde(default)]
+        payload: Option<Value>,
+    },
+}
+
+/// Sets the heating setpoint of a radiator valve at `time` in the local time of `display`
+#[derive(Deserialize, Clone, Debug)]
+pub struct HeatingSchedule {
+    pub(crate) device_id: String,
+    pub(crate) time: NaiveTime,
+    /// °C
+    pub(crate) setpoint: f64,
+    /// Every day when empty
+    #[serde(default)]
+    pub(crate) days: Vec<Weekday>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TariffConfig {
+    /// Shown after the costs
+    #[serde(default)]
+    pub(crate) currency: String,
+    /// Price of a kWh outside of `periods`
+    pub(crate) price: f64,
+    /// Time-of-use prices, the first period that contains the time applies
+    #[serde(default)]
+    pub(crate) periods: Vec<TariffPeriod>,
+}
+
+/// Price of a kWh from `start` until `end` in the local time of `display`,
+/// periods that end before they start continue over midnight
+#[derive(Deserialize, Clone, Debug)]
+pub struct TariffPeriod {
+    pub(crate) start: NaiveTime,
+    pub(crate) end: NaiveTime,
+    pub(crate) price: f64,
+}
+
+impl TariffConfig {
+    /// Price of a kWh at the local time
+    pub fn price_at(&self, time: NaiveTime) -> f64 {
+        self.periods
+            .iter()
+            .find(|period| {
+                if period.start <= period.end {
+                    period.start <= time && time < period.end
+                } else {
+                    period.start <= time || time < period.end
+                }
+            })
+            .map_or(self.price, |period| period.price)
+    }
+}
+
+/// Values from `moderate` are moderate and from `poor` are poor, lower values are good
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AirQualityThresholds {
+    pub(crate) moderate: f64,
+    pub(crate) poor: f64,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct AirQualityConfig {
+    /// ppm
+    #[serde(default = "default_co2")]
+    pub(crate) co2: AirQualityThresholds,
+    /// ppb
+    #[serde(default = "default_voc")]
+    pub(crate) voc: AirQualityThresholds,
+    /// µg/m³
+    #[serde(default = "default_pm25")]
+    pub(crate) pm25: AirQualityThresholds,
+    /// µg/m³
+    #[serde(default = "default_formaldehyd")]
+    pub(crate) formaldehyd: AirQualityThresholds,
+}
+
+impl Default for AirQualityConfig {
+    fn default() -> Self {
+        AirQualityConfig {
+            co2: default_co2(),
+            voc: default_voc(),
+            pm25: default_pm25(),
+            formaldehyd: default_formaldehyd(),
+        }
+    }
+}
+
+fn default_co2() -> AirQualityThresholds {
+    // Pettenkofer limit and the upper limit of good indoor air in EN 16798-1
+    AirQualityThresholds {
+        moderate: 1000.0,
+        poor: 1400.0,
+    }
+}
+
+fn default_voc() -> AirQualityThresholds {
+    // TVOC levels of the German Environment Agency converted to ppb
+    AirQualityThresholds {
+        moderate: 220.0,
+        poor: 660.0,
+    }
+}
+
+fn default_pm25() -> AirQualityThresholds {
+    // Annual and 24-hour limits of the US EPA
+    AirQualityThresholds {
+        moderate: 12.0,
+        poor: 35.0,
+    }
+}
+
+fn default_formaldehyd() -> AirQualityThresholds {
+    // WHO guideline for 30 minutes is 100 µg/m³
+    AirQualityThresholds {
+        moderate: 50.0,
+        poor: 100.0,
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CriticalAlarmConfig {
+    /// Where every alarm is sent when it starts or ends, and again until it is acknowledged
+    #[serde(default)]
+    pub(crate) notify: Vec<NotificationTarget>,
+    /// Minutes between the reminders of an alarm that hasn't been acknowledged
+    #[serde(default = "default_repeat_minutes")]
+    pub(crate) repeat_minutes: u32,
+}
+
+impl Default for CriticalAlarmConfig {
+    fn default() -> Self {
+        CriticalAlarmConfig {
+            notify: Vec::new(),
+            repeat_minutes: default_repeat_minutes(),
+        }
+    }
+}
+
+fn default_repeat_minutes() -> u32 {
+    5
+}
+
+/// The alarm is sent as JSON
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationTarget {
+    Mqtt { topic: String },
+    Webhook { url: String },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PayloadMapping {
+    /// MQTT topic filter, `+` matches one level and `#` any number of levels at the end
+    pub(crate) topic: String,
+    /// The last level of the topic when missing
+    #[serde(default)]
+    pub(crate) device_id: Option<DeviceIdSource>,
+    /// Values of the message by the name that ru-berry uses for them
+    pub(crate) fields: Vec<FieldMapping>,
+    /// Values added when the message doesn't have them, e.g. `{"linkquality": 0}`
+    #[serde(default)]
+    pub(crate) defaults: serde_json::Map<String, Value>,
+    /// Minutes that plain values of the topic are combined with the other topics of the device
+    #[serde(default = "default_plain_max_age_minutes")]
+    pub(crate) max_age_minutes: u32,
+}
+
+fn default_plain_max_age_minutes() -> u32 {
+    60
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceIdSource {
+    /// Level of the topic, 0 is the first
+    Level(usize),
+    /// Level matched by a wildcard of `topic`, 0 is the first wildcard
+    Capture(usize),
+    /// JSON pointer to a string in the payload
+    Pointer(String),
+    /// The same device id for every message
+    Fixed(String),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct FieldMapping {
+    /// e.g. `temperature`
+    pub(crate) field: String,
+    /// JSON pointer like `/AM2301/Temperature`, the whole payload when missing
+    #[serde(default)]
+    pub(crate) pointer: Option<String>,
+    /// The value is multiplied by `scale` and `offset` is added to it
+    #[serde(default = "default_scale")]
+    pub(crate) scale: f64,
+    #[serde(default)]
+    pub(crate) offset: f64,
+}
+
+fn default_scale() -> f64 {
+    1.0
 }
 
 impl Config {
@@ -24,9 +459,44 @@ impl Config {
             mqtt_ip: self.mqtt_ip.clone(),
             mqtt_port: self.mqtt_port,
             mqtt_topics: self.mqtt_topics.clone(),
+            mqtt_exclude_topics: self.mqtt_exclude_topics.clone(),
+            zigbee2mqtt_topic: self.zigbee2mqtt_topic.clone(),
             sqlite_database: self.sqlite_database.clone(),
+            ingest_queue_size: self.ingest_queue_size,
+            spool_file: self.spool_file.clone(),
             web_server_ip: self.web_server_ip.clone(),
             web_server_port: self.web_server_port,
+            auth: self.auth.clone(),
+            tls: self.tls.clone(),
+            device_offline_minutes: self.device_offline_minutes,
+            display: self.display.clone(),
+            templates_dir: self.templates_dir.clone(),
+            mould: self.mould.clone(),
+            alert_rules: self.alert_rules.clone(),
+            plausibility_rules: self.plausibility_rules.clone(),
+            action_triggers: self.action_triggers.clone(),
+            heating_schedule: self.heating_schedule.clone(),
+            tariff: self.tariff.clone(),
+            air_quality: self.air_quality.clone(),
+            critical_alarms: self.critical_alarms.clone(),
+            payload_mappings: self.payload_mappings.clone(),
+        }
+    }
+
+    pub fn spool_file(&self) -> String {
+        self.spool_file
+            .clone()
+            .unwrap_or_else(|| format!("{}.spool", self.sqlite_database))
+    }
+
+    /// `mqtt_exclude_topics`, or the topics of zigbee2mqtt that are not device states
+    pub fn exclude_topics(&self) -> Vec<String> {
+        match &self.mqtt_exclude_topics {
+            Some(topics) => topics.clone(),
+            None => ["bridge/#", "+/set", "+/get", "+/availability"]
+                .iter()
+                .map(|topic| format!("{}/{}", self.zigbee2mqtt_topic, topic))
+                .collect(),
         }
     }
 }
@@ -30,24 +30,22 @@ pub fn create_pool(database_url: &str) -> Result<SqlitePool, &'static str> {
 }
 
 fn is_database_locked(database_url: &str) -> bool {
-    match Connection::open_with_flags(
+    Connection::open_with_flags(
         database_url,
         OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
-    ) {
-        Ok(_) => false,
-        Err(_) => true,
-    }
+    )
+    .is_err()
 }
 
 /// Set up the database by creating the necessary tables
 fn setup_database(pool: &SqlitePool) {
-    let conn = get_conn(&pool);
+    let conn = get_conn(pool);
     conn.execute(
         "CREATE TABLE IF NOT EXISTS messages (
             id INTEGER PRIMARY KEY,
             topic TEXT NOT NULL,
             payload TEXT NOT NULL,
-            received_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            received_at INTEGER
         )",
         [],
     )
@@ -60,12 +58,163 @@ fn setup_database(pool: &SqlitePool) {
             humidity INTEGER NOT NULL,
             linkquality INTEGER NOT NULL,
             device_id TEXT NOT NULL,
-            received_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            received_at INTEGER
         )",
         [],
     )
     .expect("Failed to create sensor_data table");
 
+    migrate(&conn, 1, |conn| {
+        convert_timestamps_to_millis(conn, "messages", "received_at");
+        convert_timestamps_to_millis(conn, "sensor_data", "received_at");
+    });
+    if add_column_if_missing(&conn, "messages", "device_id", "TEXT") {
+        set_device_id_of_messages(&conn);
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS messages_device_id ON messages (device_id, id)",
+        [],
+    )
+    .expect("Failed to create index on messages");
+
+    let battery_data_exists = table_exists(&conn, "battery_data");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS battery_data (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            battery REAL NOT NULL,
+            voltage INTEGER,
+            received_at INTEGER
+        )",
+        [],
+    )
+    .expect("Failed to create battery_data table");
+    if !battery_data_exists {
+        copy_battery_data_from_messages(&conn);
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS network_map (
+            id INTEGER PRIMARY KEY,
+            map TEXT NOT NULL,
+            received_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create network_map table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS contact_events (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            open INTEGER NOT NULL,
+            received_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create contact_events table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS occupancy_periods (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            started_at INTEGER NOT NULL,
+            ended_at INTEGER
+        )",
+        [],
+    )
+    .expect("Failed to create occupancy_periods table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS illuminance_data (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            illuminance REAL NOT NULL,
+            received_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create illuminance_data table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS energy_data (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            power REAL,
+            energy REAL,
+            voltage REAL,
+            current REAL,
+            received_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create energy_data table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS action_events (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            action TEXT NOT NULL,
+            received_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create action_events table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS thermostat_data (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            local_temperature REAL,
+            setpoint REAL,
+            heating_demand REAL,
+            received_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create thermostat_data table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS air_quality_data (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            co2 REAL,
+            voc REAL,
+            pm25 REAL,
+            formaldehyd REAL,
+            received_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create air_quality_data table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS critical_alarms (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            started_at INTEGER NOT NULL,
+            ended_at INTEGER,
+            acknowledged_at INTEGER,
+            notified_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create critical_alarms table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rejected_readings (
+            id INTEGER PRIMARY KEY,
+            device_id TEXT NOT NULL,
+            metric TEXT NOT NULL,
+            value REAL NOT NULL,
+            reason TEXT NOT NULL,
+            received_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .expect("Failed to create rejected_readings table");
+
     conn.execute(
         "CREATE TABLE IF NOT EXISTS topic_configuration (
         id INTEGER PRIMARY KEY,
@@ -75,10 +224,186 @@ fn setup_database(pool: &SqlitePool) {
         [],
     )
     .expect("Failed to create topic_configuration table");
+
+    add_column_if_missing(&conn, "topic_configuration", "display_name", "TEXT");
+    add_column_if_missing(&conn, "topic_configuration", "limit_metric", "TEXT");
+    add_column_if_missing(&conn, "topic_configuration", "min_limit", "REAL");
+    add_column_if_missing(&conn, "topic_configuration", "max_limit", "REAL");
+    add_column_if_missing(&conn, "topic_configuration", "unit", "TEXT");
+    add_column_if_missing(&conn, "topic_configuration", "position", "INTEGER NOT NULL DEFAULT 0");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS api_tokens (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        scope TEXT NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        last_used_at TIMESTAMP
+    )",
+        [],
+    )
+    .expect("Failed to create api_tokens table");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS devices (
+        device_id TEXT PRIMARY KEY,
+        online INTEGER NOT NULL DEFAULT 1,
+        last_seen INTEGER
+    )",
+        [],
+    )
+    .expect("Failed to create devices table");
+    add_column_if_missing(&conn, "devices", "topic", "TEXT");
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS alerts (
+        id INTEGER PRIMARY KEY,
+        rule TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        value REAL NOT NULL,
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER
+    )",
+        [],
+    )
+    .expect("Failed to create alerts table");
+
+    migrate(&conn, 2, |conn| {
+        convert_timestamps_to_millis(conn, "devices", "last_seen");
+        convert_timestamps_to_millis(conn, "alerts", "started_at");
+        convert_timestamps_to_millis(conn, "alerts", "ended_at");
+    });
+}
+
+/// Databases created by an older version don't have the columns added since.
+/// Returns whether the column was added.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> bool {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")
+        .and_then(|mut stmt| stmt.exists([table, column]))
+        .expect("Failed to read table info");
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )
+        .unwrap_or_else(|e| panic!("Failed to add column {} to {}: {:?}", column, table, e));
+    }
+    !exists
+}
+
+fn table_exists(conn: &Connection, table: &str) -> bool {
+    conn.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")
+        .and_then(|mut stmt| stmt.exists([table]))
+        .expect("Failed to read the schema")
+}
+
+/// Messages used to be stored without the device, it was taken from the part of the topic
+/// after the last '/' like it still is for zigbee2mqtt
+fn set_device_id_of_messages(conn: &Connection) {
+    conn.execute(
+        "UPDATE messages SET device_id = substr(topic, length(rtrim(topic, replace(topic, '/', ''))) + 1)",
+        [],
+    )
+    .expect("Failed to set the device id of messages");
+}
+
+/// Battery levels used to be only in the raw messages
+fn copy_battery_data_from_messages(conn: &Connection) {
+    // The device id is the part of the topic after the last '/'
+    let copied = conn
+        .execute(
+            "INSERT INTO battery_data (device_id, battery, voltage, received_at)
+            SELECT substr(topic, length(rtrim(topic, replace(topic, '/', ''))) + 1),
+                json_extract(payload, '$.battery'), json_extract(payload, '$.voltage'), received_at
+            FROM messages
+            WHERE json_valid(payload) AND json_extract(payload, '$.battery') IS NOT NULL
+            ORDER BY id",
+            [],
+        )
+        .expect("Failed to copy battery levels from messages");
+    if copied > 0 {
+        println!("Copied {} battery levels from messages", copied);
+    }
+}
+
+/// Runs a migration that rewrites existing rows once. `PRAGMA user_version` is the
+/// last migration the database has, so that startup doesn't scan the tables again.
+fn migrate(conn: &Connection, version: i64, migration: impl FnOnce(&Connection)) {
+    let current: i64 = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .expect("Failed to read the database version");
+    if current >= version {
+        return;
+    }
+
+    let tx = conn
+        .unchecked_transaction()
+        .expect("Failed to start a migration");
+    migration(&tx);
+    tx.pragma_update(None, "user_version", version)
+        .expect("Failed to update the database version");
+    tx.commit().expect("Failed to commit a migration");
+}
+
+/// Timestamps used to be `CURRENT_TIMESTAMP` text, they're milliseconds since the epoch now.
+/// Text that SQLite can't read as a time is kept in `invalid_<column>` and the column is set
+/// to NULL, which leaves the row out of every time range.
+fn convert_timestamps_to_millis(conn: &Connection, table: &str, column: &str) {
+    let invalid: i64 = conn
+        .query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {0} WHERE typeof({1}) = 'text'
+                AND unixepoch({1}, 'subsec') IS NULL",
+                table, column
+            ),
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or_else(|e| panic!("Failed to count timestamps of {}: {:?}", table, e));
+    if invalid > 0 {
+        let backup = format!("invalid_{}", column);
+        add_column_if_missing(conn, table, &backup, "TEXT");
+        conn.execute(
+            &format!(
+                "UPDATE {0} SET {2} = {1}
+                WHERE typeof({1}) = 'text' AND unixepoch({1}, 'subsec') IS NULL",
+                table, column, backup
+            ),
+            [],
+        )
+        .unwrap_or_else(|e| panic!("Failed to back up timestamps of {}: {:?}", table, e));
+        println!(
+            "{} rows of {} have no valid {}, it is kept in {}",
+            invalid, table, column, backup
+        );
+    }
+
+    let converted = conn
+        .execute(
+            &format!(
+                "UPDATE {0} SET {1} = CAST(unixepoch({1}, 'subsec') * 1000 AS INTEGER)
+                WHERE typeof({1}) = 'text'",
+                table, column
+            ),
+            [],
+        )
+        .unwrap_or_else(|e| panic!("Failed to convert timestamps of {}: {:?}", table, e));
+    if converted > 0 {
+        println!("Converted {} timestamps of {} to milliseconds", converted, table);
+    }
 }
 
 pub fn get_conn(pool: &SqlitePool) -> SqlitePooledConnection {
-    pool.get().expect("Failed to get connection.")
+    try_get_conn(pool).expect("Failed to get connection.")
+}
+
+/// For the writer and the background tasks, which spool or log instead of panicking when the
+/// database is unavailable
+pub fn try_get_conn(pool: &SqlitePool) -> Result<SqlitePooledConnection, r2d2::Error> {
+    pool.get()
 }
 
 impl Debug for RetryConnectionCustomizer {
@@ -108,9 +433,104 @@ impl CustomizeConnection<Connection, rusqlite::Error> for RetryConnectionCustomi
 #[cfg(test)]
 pub fn get_test_pool() -> SqlitePool {
     let manager = SqliteConnectionManager::memory();
-    let pool = Pool::builder().build(manager).unwrap();
+    // Every in-memory connection is a separate database, so the tests must share a single one
+    // Tests of an unavailable database hold the connection, they don't wait as long as the default
+    let pool = Pool::builder()
+        .max_size(1)
+        .connection_timeout(Duration::from_secs(1))
+        .build(manager)
+        .unwrap();
 
     setup_database(&pool);
 
     pool
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_timestamps_are_converted_to_millis() {
+        let pool = get_test_pool();
+        let conn = get_conn(&pool);
+        for received_at in ["2024-07-01 12:00:00", "garbage"] {
+            conn.execute(
+                "INSERT INTO messages (topic, payload, received_at) VALUES ('topic', '{}', ?1)",
+                [received_at],
+            )
+            .unwrap();
+        }
+
+        convert_timestamps_to_millis(&conn, "messages", "received_at");
+
+        let converted: Vec<Option<i64>> = conn
+            .prepare("SELECT received_at FROM messages ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(converted, vec![Some(1719835200000), None]);
+
+        let invalid: Option<String> = conn
+            .query_row(
+                "SELECT invalid_received_at FROM messages WHERE received_at IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(invalid.as_deref(), Some("garbage"));
+    }
+
+    #[test]
+    fn test_migrations_run_once() {
+        let pool = get_test_pool();
+        let conn = get_conn(&pool);
+        let mut runs = 0;
+        migrate(&conn, 100, |_| runs += 1);
+        migrate(&conn, 100, |_| runs += 1);
+        assert_eq!(runs, 1);
+
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 100);
+    }
+
+    #[test]
+    fn test_battery_data_is_copied_from_messages() {
+        let pool = get_test_pool();
+        let conn = get_conn(&pool);
+        for (topic, payload) in [
+            ("zigbee2mqtt/kitchen", r#"{"battery": 90, "voltage": 2900}"#),
+            ("zigbee2mqtt/kitchen", r#"{"temperature": 21}"#),
+            ("zigbee2mqtt/plug", r#"{"voltage": 230}"#),
+            ("zigbee2mqtt/kitchen", "not json"),
+            ("bathroom", r#"{"battery": 50}"#),
+        ] {
+            conn.execute(
+                "INSERT INTO messages (topic, payload, received_at) VALUES (?1, ?2, 0)",
+                [topic, payload],
+            )
+            .unwrap();
+        }
+
+        copy_battery_data_from_messages(&conn);
+
+        let copied: Vec<(String, f64, Option<i64>)> = conn
+            .prepare("SELECT device_id, battery, voltage FROM battery_data ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            copied,
+            vec![
+                ("kitchen".to_string(), 90.0, Some(2900)),
+                ("bathroom".to_string(), 50.0, None),
+            ]
+        );
+    }
+}
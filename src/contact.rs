@@ -0,0 +1,114 @@
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+/// A door or window was opened or closed.
+/// Only changes are stored, repeated reports of the same state are not.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ContactEvent {
+    pub(crate) device_id: String,
+    pub(crate) open: bool,
+    pub(crate) received_at: DateTime<Utc>,
+}
+
+impl ContactEvent {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(ContactEvent {
+            device_id: row.get(0)?,
+            open: row.get(1)?,
+            received_at: timestamps::get(row, 2)?,
+        })
+    }
+}
+
+/// zigbee2mqtt reports `contact: true` when the magnet is next to the sensor, i.e. closed.
+/// Returns the event if the state changed.
+pub fn record(
+    conn: &Connection,
+    device_id: &str,
+    contact: bool,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<Option<ContactEvent>> {
+    let open = !contact;
+    if latest(conn, device_id)?.is_some_and(|latest| latest.open == open) {
+        return Ok(None);
+    }
+
+    conn.execute(
+        "INSERT INTO contact_events (device_id, open, received_at) VALUES (?1, ?2, ?3)",
+        params![device_id, open, timestamps::to_millis(received_at)],
+    )?;
+    Ok(Some(ContactEvent {
+        device_id: device_id.to_string(),
+        open,
+        received_at,
+    }))
+}
+
+/// Current state of the device and when it started
+pub fn latest(conn: &Connection, device_id: &str) -> rusqlite::Result<Option<ContactEvent>> {
+    conn.query_row(
+        "SELECT device_id, open, received_at FROM contact_events
+        WHERE device_id = ?1 ORDER BY received_at DESC, id DESC LIMIT 1",
+        [device_id],
+        ContactEvent::from_row,
+    )
+    .optional()
+}
+
+/// Events since `modifier`, e.g. `-7 days`, of one device or every device, latest first
+pub fn events(
+    pool: &SqlitePool,
+    device_id: Option<&str>,
+    modifier: &str,
+) -> rusqlite::Result<Vec<ContactEvent>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT device_id, open, received_at FROM contact_events
+        WHERE (?1 IS NULL OR device_id = ?1) AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at DESC, id DESC",
+    )?;
+    let events = stmt
+        .query_map(params![device_id, modifier], ContactEvent::from_row)?
+        .collect();
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+    use chrono::SubsecRound;
+
+    #[test]
+    fn test_only_changes_are_recorded() {
+        let pool = get_test_pool();
+        let now = Utc::now().trunc_subsecs(3);
+        let minutes_ago = |minutes| now - chrono::Duration::minutes(minutes);
+
+        assert!(record(&get_conn(&pool), "door", true, minutes_ago(30))
+            .unwrap()
+            .is_some());
+        assert!(record(&get_conn(&pool), "door", true, minutes_ago(20))
+            .unwrap()
+            .is_none());
+        let opened = record(&get_conn(&pool), "door", false, minutes_ago(10))
+            .unwrap()
+            .unwrap();
+        assert!(opened.open);
+        assert!(record(&get_conn(&pool), "window", false, minutes_ago(5))
+            .unwrap()
+            .is_some());
+
+        assert_eq!(latest(&get_conn(&pool), "door").unwrap(), Some(opened));
+        let door: Vec<bool> = events(&pool, Some("door"), "-1 day")
+            .unwrap()
+            .iter()
+            .map(|event| event.open)
+            .collect();
+        assert_eq!(door, vec![true, false]);
+        assert_eq!(events(&pool, None, "-1 day").unwrap().len(), 3);
+    }
+}
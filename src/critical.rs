@@ -0,0 +1,315 @@
+use crate::config::{CriticalAlarmConfig, NotificationTarget};
+use crate::conn::{get_conn, try_get_conn, SqlitePool};
+use crate::timestamps;
+use crate::webhook;
+use chrono::{DateTime, Utc};
+use rumqttc::{AsyncClient, QoS};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Values of zigbee2mqtt that raise a critical alarm when they are true
+pub const KINDS: [&str; 3] = ["water_leak", "smoke", "tamper"];
+
+/// How often alarms that haven't been acknowledged are checked for reminders
+const REMINDER_INTERVAL: Duration = Duration::from_secs(30);
+
+const SELECT_ALARM: &str =
+    "SELECT id, device_id, kind, started_at, ended_at, acknowledged_at FROM critical_alarms";
+
+/// A water leak, smoke or tamper alarm of a device
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CriticalAlarm {
+    pub(crate) id: i64,
+    pub(crate) device_id: String,
+    /// One of `KINDS`
+    pub(crate) kind: String,
+    pub(crate) started_at: DateTime<Utc>,
+    /// None while the sensor still reports the alarm
+    pub(crate) ended_at: Option<DateTime<Utc>>,
+    pub(crate) acknowledged_at: Option<DateTime<Utc>>,
+}
+
+impl CriticalAlarm {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let ended_at: Option<i64> = row.get(4)?;
+        let acknowledged_at: Option<i64> = row.get(5)?;
+        Ok(CriticalAlarm {
+            id: row.get(0)?,
+            device_id: row.get(1)?,
+            kind: row.get(2)?,
+            started_at: timestamps::get(row, 3)?,
+            ended_at: ended_at.and_then(DateTime::from_timestamp_millis),
+            acknowledged_at: acknowledged_at.and_then(DateTime::from_timestamp_millis),
+        })
+    }
+}
+
+/// An alarm that started while the database couldn't be written. It's stored when the
+/// spooled message is written, until then it has no id and can't be acknowledged.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UnstoredAlarm {
+    pub(crate) device_id: String,
+    pub(crate) kind: String,
+    pub(crate) started_at: DateTime<Utc>,
+}
+
+/// Body of the notifications
+#[derive(Serialize)]
+struct Notification<'a, T> {
+    priority: &'static str,
+    /// The alarm was already sent and hasn't been acknowledged
+    reminder: bool,
+    #[serde(flatten)]
+    alarm: &'a T,
+}
+
+fn get(conn: &Connection, id: i64) -> rusqlite::Result<Option<CriticalAlarm>> {
+    conn.query_row(
+        &format!("{} WHERE id = ?1", SELECT_ALARM),
+        [id],
+        CriticalAlarm::from_row,
+    )
+    .optional()
+}
+
+fn current(conn: &Connection, device_id: &str, kind: &str) -> rusqlite::Result<Option<i64>> {
+    conn.query_row(
+        "SELECT id FROM critical_alarms WHERE device_id = ?1 AND kind = ?2 AND ended_at IS NULL
+        ORDER BY started_at DESC LIMIT 1",
+        params![device_id, kind],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Starts an alarm when the sensor reports it and ends it when the sensor is clear again.
+/// Returns the alarm if it started or ended.
+pub fn record(
+    conn: &Connection,
+    device_id: &str,
+    kind: &str,
+    active: bool,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<Option<CriticalAlarm>> {
+    let millis = timestamps::to_millis(received_at);
+    let id = match (current(conn, device_id, kind)?, active) {
+        (None, true) => {
+            conn.execute(
+                "INSERT INTO critical_alarms (device_id, kind, started_at, notified_at)
+                VALUES (?1, ?2, ?3, ?3)",
+                params![device_id, kind, millis],
+            )?;
+            conn.last_insert_rowid()
+        }
+        (Some(id), false) => {
+            conn.execute(
+                "UPDATE critical_alarms SET ended_at = MAX(started_at, ?1) WHERE id = ?2",
+                params![millis, id],
+            )?;
+            id
+        }
+        _ => return Ok(None),
+    };
+    get(conn, id)
+}
+
+/// Alarms that are still reported or haven't been acknowledged, oldest first
+pub fn pending(pool: &SqlitePool) -> rusqlite::Result<Vec<CriticalAlarm>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE ended_at IS NULL OR acknowledged_at IS NULL ORDER BY started_at, id",
+        SELECT_ALARM
+    ))?;
+    let alarms = stmt.query_map([], CriticalAlarm::from_row)?.collect();
+    alarms
+}
+
+/// Stops the reminders of an alarm, acknowledging it again keeps the first time.
+/// Returns None if there is no such alarm.
+pub fn acknowledge(
+    pool: &SqlitePool,
+    id: i64,
+    at: DateTime<Utc>,
+) -> rusqlite::Result<Option<CriticalAlarm>> {
+    let conn = get_conn(pool);
+    conn.execute(
+        "UPDATE critical_alarms SET acknowledged_at = COALESCE(acknowledged_at, ?2) WHERE id = ?1",
+        params![id, timestamps::to_millis(at)],
+    )?;
+    get(&conn, id)
+}
+
+/// Alarms that haven't been acknowledged and were last sent before `notified_before`
+fn due(conn: &Connection, notified_before: DateTime<Utc>) -> rusqlite::Result<Vec<CriticalAlarm>> {
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE acknowledged_at IS NULL AND notified_at <= ?1 ORDER BY started_at, id",
+        SELECT_ALARM
+    ))?;
+    let alarms = stmt
+        .query_map(
+            [timestamps::to_millis(notified_before)],
+            CriticalAlarm::from_row,
+        )?
+        .collect();
+    alarms
+}
+
+fn mark_notified(conn: &Connection, id: i64, at: DateTime<Utc>) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE critical_alarms SET notified_at = ?2 WHERE id = ?1",
+        params![id, timestamps::to_millis(at)],
+    )?;
+    Ok(())
+}
+
+/// Sends the alarm to every target without waiting, like `actions::run`
+pub fn notify(
+    targets: &[NotificationTarget],
+    alarm: &CriticalAlarm,
+    reminder: bool,
+    client: &AsyncClient,
+) {
+    let notification = Notification {
+        priority: "high",
+        reminder,
+        alarm,
+    };
+    send(targets, &notification, client);
+}
+
+/// Sends an alarm that isn't stored yet, see `UnstoredAlarm`
+pub fn notify_unstored(
+    targets: &[NotificationTarget],
+    alarm: &UnstoredAlarm,
+    client: &AsyncClient,
+) {
+    let notification = Notification {
+        priority: "high",
+        reminder: false,
+        alarm,
+    };
+    send(targets, &notification, client);
+}
+
+fn send<T: Serialize>(
+    targets: &[NotificationTarget],
+    notification: &Notification<T>,
+    client: &AsyncClient,
+) {
+    let body = serde_json::to_string(notification).expect("Failed to serialize critical alarm");
+
+    for target in targets {
+        match target {
+            NotificationTarget::Mqtt { topic } => {
+                if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, false, body.clone()) {
+                    println!("Failed to publish {}: {:?}", topic, e);
+                }
+            }
+            NotificationTarget::Webhook { url } => {
+                let url = url.clone();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = webhook::post(&url, body).await {
+                        println!("Failed to send webhook {}: {}", url, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Sends the alarms that haven't been acknowledged again every `repeat_minutes`
+pub async fn run_reminders(pool: SqlitePool, config: CriticalAlarmConfig, client: AsyncClient) {
+    let repeat = chrono::Duration::minutes(config.repeat_minutes as i64);
+    loop {
+        tokio::time::sleep(REMINDER_INTERVAL).await;
+        let conn = match try_get_conn(&pool) {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!(
+                    "Database is not available for critical alarm reminders: {}",
+                    e
+                );
+                continue;
+            }
+        };
+        let now = Utc::now();
+        let alarms = match due(&conn, now - repeat) {
+            Ok(alarms) => alarms,
+            Err(e) => {
+                println!("Failed to query critical alarms: {:?}", e);
+                continue;
+            }
+        };
+        for alarm in alarms {
+            println!("Reminding of {} on {}", alarm.kind, alarm.device_id);
+            notify(&config.notify, &alarm, true, &client);
+            if let Err(e) = mark_notified(&conn, alarm.id, now) {
+                println!("Failed to update critical alarm: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+    use chrono::{Duration, SubsecRound};
+
+    #[test]
+    fn test_alarm_starts_ends_and_is_acknowledged() {
+        let pool = get_test_pool();
+        let start = Utc::now().trunc_subsecs(3) - Duration::minutes(10);
+
+        let alarm = record(&get_conn(&pool), "kitchen_leak", "water_leak", true, start)
+            .unwrap()
+            .unwrap();
+        assert_eq!(alarm.started_at, start);
+        assert!(record(&get_conn(&pool), "kitchen_leak", "water_leak", true, Utc::now())
+            .unwrap()
+            .is_none());
+        assert!(record(&get_conn(&pool), "kitchen_leak", "tamper", false, Utc::now())
+            .unwrap()
+            .is_none());
+
+        let ended = record(&get_conn(&pool), "kitchen_leak", "water_leak", false, Utc::now())
+            .unwrap()
+            .unwrap();
+        assert_eq!(ended.id, alarm.id);
+        assert!(ended.ended_at.is_some());
+        // Ended but not acknowledged
+        assert_eq!(pending(&pool).unwrap(), vec![ended]);
+
+        let at = Utc::now().trunc_subsecs(3);
+        let acknowledged = acknowledge(&pool, alarm.id, at).unwrap().unwrap();
+        assert_eq!(acknowledged.acknowledged_at, Some(at));
+        let again = acknowledge(&pool, alarm.id, Utc::now()).unwrap().unwrap();
+        assert_eq!(again.acknowledged_at, Some(at));
+        assert!(pending(&pool).unwrap().is_empty());
+        assert!(acknowledge(&pool, alarm.id + 1, at).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reminders_until_acknowledged() {
+        let pool = get_test_pool();
+        let now = Utc::now();
+        let alarm = record(&get_conn(&pool), "hall_smoke", "smoke", true, now - Duration::minutes(6))
+            .unwrap()
+            .unwrap();
+        record(&get_conn(&pool), "attic_smoke", "smoke", true, now - Duration::minutes(1)).unwrap();
+
+        let reminders = due(&get_conn(&pool), now - Duration::minutes(5)).unwrap();
+        assert_eq!(reminders, vec![alarm.clone()]);
+        mark_notified(&get_conn(&pool), alarm.id, now).unwrap();
+        assert!(due(&get_conn(&pool), now - Duration::minutes(5))
+            .unwrap()
+            .is_empty());
+
+        acknowledge(&pool, alarm.id, now).unwrap();
+        let reminders = due(&get_conn(&pool), now + Duration::minutes(5)).unwrap();
+        assert_eq!(reminders.len(), 1);
+        assert_eq!(reminders[0].device_id, "attic_smoke");
+    }
+}
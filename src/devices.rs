@@ -0,0 +1,194 @@
+use crate::conn::{get_conn, try_get_conn, SqlitePool};
+use crate::events::{broadcast, EventSender, LiveEvent};
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::time::Duration;
+
+const OFFLINE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Devices that haven't sent anything since are offline
+fn offline_before(offline_after_minutes: u32) -> DateTime<Utc> {
+    Utc::now() - chrono::Duration::minutes(offline_after_minutes as i64)
+}
+
+/// Record that a message was received from the device on the topic, `seen_at` is the time
+/// of the message. A message that is too old to count, e.g. a spooled one, doesn't bring
+/// the device online. Returns an event if the device is new or was offline and is online now.
+pub fn mark_seen(
+    conn: &Connection,
+    device_id: &str,
+    topic: &str,
+    seen_at: DateTime<Utc>,
+    offline_after_minutes: u32,
+) -> rusqlite::Result<Option<LiveEvent>> {
+    let was_online: Option<bool> = conn
+        .query_row(
+            "SELECT online FROM devices WHERE device_id = ?1",
+            [device_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    let online = seen_at >= offline_before(offline_after_minutes);
+    conn.execute(
+        "INSERT INTO devices (device_id, online, last_seen, topic) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(device_id) DO UPDATE SET online = MAX(online, excluded.online),
+            last_seen = MAX(COALESCE(last_seen, 0), excluded.last_seen), topic = excluded.topic",
+        params![device_id, online, timestamps::to_millis(seen_at), topic],
+    )?;
+
+    Ok(match (was_online, online) {
+        (Some(true), _) | (_, false) => None,
+        _ => Some(LiveEvent::DeviceStatus {
+            device_id: device_id.to_string(),
+            online: true,
+        }),
+    })
+}
+
+/// Mark devices that haven't sent anything in `offline_after_minutes` as offline
+pub fn mark_offline_devices(pool: &SqlitePool, offline_after_minutes: u32) -> Vec<LiveEvent> {
+    let conn = match try_get_conn(pool) {
+        Ok(conn) => conn,
+        Err(e) => {
+            println!("Database is not available to mark devices offline: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut stmt = match conn.prepare(
+        "UPDATE devices SET online = 0
+        WHERE online = 1 AND last_seen < ?1
+        RETURNING device_id",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            println!("Failed to prepare offline device query: {:?}", e);
+            return Vec::new();
+        }
+    };
+
+    let offline_before = timestamps::to_millis(offline_before(offline_after_minutes));
+    let events = match stmt.query_map([offline_before], |row| row.get::<_, String>(0)) {
+        Ok(rows) => rows
+            .filter_map(Result::ok)
+            .map(|device_id| LiveEvent::DeviceStatus {
+                device_id,
+                online: false,
+            })
+            .collect(),
+        Err(e) => {
+            println!("Failed to mark devices offline: {:?}", e);
+            Vec::new()
+        }
+    };
+    events
+}
+
+pub fn is_online(pool: &SqlitePool, device_id: &str) -> Option<bool> {
+    let conn = get_conn(pool);
+    conn.query_row(
+        "SELECT online FROM devices WHERE device_id = ?1",
+        [device_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .ok()
+    .flatten()
+}
+
+/// Device ids with the topic they last sent a message on
+pub fn topics(pool: &SqlitePool) -> rusqlite::Result<Vec<(String, String)>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT device_id, topic FROM devices WHERE topic IS NOT NULL ORDER BY device_id",
+    )?;
+    let topics = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect();
+    topics
+}
+
+pub async fn watch_offline_devices(pool: SqlitePool, events: EventSender, offline_after_minutes: u32) {
+    let mut interval = tokio::time::interval(OFFLINE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        for event in mark_offline_devices(&pool, offline_after_minutes) {
+            if let LiveEvent::DeviceStatus { device_id, .. } = &event {
+                println!("Device {} went offline", device_id);
+            }
+            broadcast(&events, event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+
+    /// A message of the device with offline after 120 minutes
+    fn seen(pool: &SqlitePool, device_id: &str, seen_at: DateTime<Utc>) -> Option<LiveEvent> {
+        let topic = format!("zigbee2mqtt/{}", device_id);
+        mark_seen(&get_conn(pool), device_id, &topic, seen_at, 120).unwrap()
+    }
+
+    #[test]
+    fn test_mark_seen() {
+        let pool = get_test_pool();
+
+        assert!(matches!(
+            seen(&pool, "bathroom", Utc::now()),
+            Some(LiveEvent::DeviceStatus { online: true, .. })
+        ));
+        assert!(seen(&pool, "bathroom", Utc::now()).is_none());
+        assert_eq!(is_online(&pool, "bathroom"), Some(true));
+        assert_eq!(is_online(&pool, "kitchen"), None);
+    }
+
+    #[test]
+    fn test_old_messages_do_not_bring_devices_online() {
+        let pool = get_test_pool();
+        let last_seen = |pool: &SqlitePool| -> i64 {
+            get_conn(pool)
+                .query_row(
+                    "SELECT last_seen FROM devices WHERE device_id = 'kitchen'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        let now = Utc::now();
+        let hours_ago = now - chrono::Duration::hours(3);
+
+        assert!(seen(&pool, "kitchen", hours_ago).is_none());
+        assert_eq!(is_online(&pool, "kitchen"), Some(false));
+        assert_eq!(last_seen(&pool), timestamps::to_millis(hours_ago));
+
+        seen(&pool, "kitchen", now);
+        seen(&pool, "kitchen", hours_ago);
+        assert_eq!(is_online(&pool, "kitchen"), Some(true));
+        assert_eq!(last_seen(&pool), timestamps::to_millis(now));
+    }
+
+    #[test]
+    fn test_mark_offline_devices() {
+        let pool = get_test_pool();
+        seen(&pool, "bathroom", Utc::now());
+        seen(&pool, "kitchen", Utc::now() - chrono::Duration::hours(3));
+        get_conn(&pool)
+            .execute("UPDATE devices SET online = 1", [])
+            .unwrap();
+
+        let events = mark_offline_devices(&pool, 120);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0],
+            LiveEvent::DeviceStatus { device_id, online: false } if device_id == "kitchen"
+        ));
+        assert_eq!(is_online(&pool, "kitchen"), Some(false));
+
+        // Coming back online is reported again
+        assert!(seen(&pool, "kitchen", Utc::now()).is_some());
+    }
+}
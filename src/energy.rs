@@ -0,0 +1,201 @@
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+/// Values of a power monitoring plug, whichever of them the plug reports
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct EnergyReport {
+    /// W
+    pub(crate) power: Option<f64>,
+    /// Cumulative counter in kWh, resets when the plug loses power or is reset
+    pub(crate) energy: Option<f64>,
+    /// V
+    pub(crate) voltage: Option<f64>,
+    /// A
+    pub(crate) current: Option<f64>,
+}
+
+pub fn record(
+    conn: &Connection,
+    device_id: &str,
+    report: &EnergyReport,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO energy_data (device_id, power, energy, voltage, current, received_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            device_id,
+            report.power,
+            report.energy,
+            report.voltage,
+            report.current,
+            timestamps::to_millis(received_at)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Latest report of a device and when it was received
+pub fn latest(
+    pool: &SqlitePool,
+    device_id: &str,
+) -> rusqlite::Result<Option<(EnergyReport, DateTime<Utc>)>> {
+    get_conn(pool)
+        .query_row(
+            "SELECT power, energy, voltage, current, received_at FROM energy_data
+            WHERE device_id = ?1 ORDER BY received_at DESC LIMIT 1",
+            [device_id],
+            |row| {
+                Ok((
+                    EnergyReport {
+                        power: row.get(0)?,
+                        energy: row.get(1)?,
+                        voltage: row.get(2)?,
+                        current: row.get(3)?,
+                    },
+                    timestamps::get(row, 4)?,
+                ))
+            },
+        )
+        .optional()
+}
+
+/// Devices that have reported power or energy
+pub fn devices(pool: &SqlitePool) -> rusqlite::Result<Vec<String>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare("SELECT DISTINCT device_id FROM energy_data ORDER BY device_id")?;
+    let devices = stmt.query_map([], |row| row.get(0))?.collect();
+    devices
+}
+
+/// Power of a device since `modifier`, e.g. `-7 days`, oldest first
+pub fn power(
+    pool: &SqlitePool,
+    device_id: &str,
+    modifier: &str,
+) -> rusqlite::Result<Vec<(DateTime<Utc>, f64)>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT received_at, power FROM energy_data
+        WHERE device_id = ?1 AND power IS NOT NULL AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at",
+    )?;
+    let power = stmt
+        .query_map(params![device_id, modifier], |row| {
+            Ok((timestamps::get(row, 0)?, row.get(1)?))
+        })?
+        .collect();
+    power
+}
+
+/// Energy counter of a device since `modifier`, oldest first.
+/// Starts from the last counter before `modifier` so that the first consumption is not lost.
+pub fn counters(
+    pool: &SqlitePool,
+    device_id: &str,
+    modifier: &str,
+) -> rusqlite::Result<Vec<(DateTime<Utc>, f64)>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT received_at, energy FROM energy_data
+        WHERE device_id = ?1 AND energy IS NOT NULL AND received_at >= (
+            SELECT COALESCE(MAX(received_at), 0) FROM energy_data
+            WHERE device_id = ?1 AND energy IS NOT NULL AND received_at < unixepoch('now', ?2) * 1000
+        )
+        ORDER BY received_at",
+    )?;
+    let counters = stmt
+        .query_map(params![device_id, modifier], |row| {
+            Ok((timestamps::get(row, 0)?, row.get(1)?))
+        })?
+        .collect();
+    counters
+}
+
+/// A counter that drops below this fraction of the previous one was reset, smaller
+/// drops are rounding by the plug
+const RESET_FRACTION: f64 = 0.5;
+
+/// kWh used since the previous counter, at the time of the later counter.
+/// A counter that was reset has everything on it used since, a counter that dipped
+/// a little counts as nothing used.
+pub fn consumption(counters: &[(DateTime<Utc>, f64)]) -> Vec<(DateTime<Utc>, f64)> {
+    counters
+        .windows(2)
+        .map(|pair| {
+            let ((_, previous), (time, energy)) = (pair[0], pair[1]);
+            let used = if energy >= previous {
+                energy - previous
+            } else if energy < previous * RESET_FRACTION {
+                energy
+            } else {
+                0.0
+            };
+            (time, used)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+
+    fn hours_ago(hours: i64) -> DateTime<Utc> {
+        Utc::now() - chrono::Duration::hours(hours)
+    }
+
+    #[test]
+    fn test_consumption_with_counter_reset() {
+        let counters = vec![
+            (hours_ago(4), 10.0),
+            (hours_ago(3), 10.5),
+            (hours_ago(2), 0.25),
+            (hours_ago(1), 0.75),
+        ];
+        let used: Vec<f64> = consumption(&counters)
+            .iter()
+            .map(|(_, used)| *used)
+            .collect();
+        assert_eq!(used, vec![0.5, 0.25, 0.5]);
+        assert!(consumption(&counters[..1]).is_empty());
+    }
+
+    #[test]
+    fn test_consumption_with_counter_dip() {
+        let counters = vec![
+            (hours_ago(3), 10.0),
+            (hours_ago(2), 10.5),
+            (hours_ago(1), 10.49),
+        ];
+        let used: Vec<f64> = consumption(&counters)
+            .iter()
+            .map(|(_, used)| *used)
+            .collect();
+        assert_eq!(used, vec![0.5, 0.0]);
+    }
+
+    #[test]
+    fn test_counters_start_before_the_range() {
+        let pool = get_test_pool();
+        let report = |energy| EnergyReport {
+            power: Some(100.0),
+            energy: Some(energy),
+            ..Default::default()
+        };
+        record(&get_conn(&pool), "plug", &report(1.0), hours_ago(48)).unwrap();
+        record(&get_conn(&pool), "plug", &report(2.0), hours_ago(30)).unwrap();
+        record(&get_conn(&pool), "plug", &report(3.0), hours_ago(2)).unwrap();
+        record(&get_conn(&pool), "plug", &EnergyReport::default(), hours_ago(1)).unwrap();
+
+        let counters = counters(&pool, "plug", "-1 day").unwrap();
+        let values: Vec<f64> = counters.iter().map(|(_, energy)| *energy).collect();
+        assert_eq!(values, vec![2.0, 3.0]);
+        assert_eq!(power(&pool, "plug", "-1 day").unwrap().len(), 1);
+        assert_eq!(devices(&pool).unwrap(), vec!["plug"]);
+        assert_eq!(latest(&pool, "plug").unwrap().unwrap().0.power, None);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::actions::ActionEvent;
+use crate::contact::ContactEvent;
+use crate::critical::CriticalAlarm;
+use crate::model::SensorData;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events that slow browsers can fall behind on before they start missing them
+const CHANNEL_CAPACITY: usize = 64;
+
+pub type EventSender = broadcast::Sender<LiveEvent>;
+
+/// Changes pushed from the MQTT client to the connected browsers
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    Reading(SensorData),
+    Alert {
+        rule: String,
+        device_id: String,
+        value: f64,
+        active: bool,
+    },
+    DeviceStatus {
+        device_id: String,
+        online: bool,
+    },
+    Contact(ContactEvent),
+    Action(ActionEvent),
+    CriticalAlarm(CriticalAlarm),
+}
+
+impl LiveEvent {
+    /// Name of the server-sent event
+    pub fn name(&self) -> &'static str {
+        match self {
+            LiveEvent::Reading(_) => "reading",
+            LiveEvent::Alert { .. } => "alert",
+            LiveEvent::DeviceStatus { .. } => "device_status",
+            LiveEvent::Contact(_) => "contact",
+            LiveEvent::Action(_) => "action",
+            LiveEvent::CriticalAlarm(_) => "critical_alarm",
+        }
+    }
+}
+
+pub fn channel() -> EventSender {
+    let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+    sender
+}
+
+/// Nobody listening is not an error, the event is just dropped
+pub fn broadcast(events: &EventSender, event: LiveEvent) {
+    let _ = events.send(event);
+}
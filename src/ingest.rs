@@ -0,0 +1,214 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{self, error::TryRecvError, error::TrySendError};
+
+/// Messages that are written in one transaction at most
+pub const MAX_BATCH: usize = 100;
+
+/// A message as it arrived from the broker, before anything is stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IncomingMessage {
+    pub(crate) topic: String,
+    pub(crate) payload: String,
+    pub(crate) arrived_at: DateTime<Utc>,
+}
+
+/// Counters of the queue between the MQTT client and the database writer
+#[derive(Default)]
+pub struct IngestMetrics {
+    capacity: AtomicU64,
+    received: AtomicU64,
+    written: AtomicU64,
+    batches: AtomicU64,
+    largest_batch: AtomicU64,
+    max_queue_depth: AtomicU64,
+    /// Times the MQTT client had to wait because the queue was full
+    queue_full: AtomicU64,
+    waited_ms: AtomicU64,
+    spooled: AtomicU64,
+}
+
+/// `IngestMetrics` at one point in time
+#[derive(Serialize, Debug, PartialEq)]
+pub struct IngestStats {
+    capacity: u64,
+    /// Messages that are received but not written yet, including the batch being written
+    queue_depth: u64,
+    max_queue_depth: u64,
+    received: u64,
+    written: u64,
+    batches: u64,
+    largest_batch: u64,
+    queue_full: u64,
+    waited_ms: u64,
+    /// Messages waiting in the spool file until the database can be written again
+    spooled: u64,
+}
+
+impl IngestMetrics {
+    pub fn stats(&self) -> IngestStats {
+        let received = self.received.load(Ordering::Relaxed);
+        let written = self.written.load(Ordering::Relaxed);
+        IngestStats {
+            capacity: self.capacity.load(Ordering::Relaxed),
+            queue_depth: received.saturating_sub(written),
+            max_queue_depth: self.max_queue_depth.load(Ordering::Relaxed),
+            received,
+            written,
+            batches: self.batches.load(Ordering::Relaxed),
+            largest_batch: self.largest_batch.load(Ordering::Relaxed),
+            queue_full: self.queue_full.load(Ordering::Relaxed),
+            waited_ms: self.waited_ms.load(Ordering::Relaxed),
+            spooled: self.spooled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Called by the writer after a batch is handled
+    pub fn batch_written(&self, size: usize) {
+        self.written.fetch_add(size as u64, Ordering::Relaxed);
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.largest_batch.fetch_max(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_spooled(&self, spooled: usize) {
+        self.spooled.store(spooled as u64, Ordering::Relaxed);
+    }
+}
+
+/// The MQTT client's end of the queue
+pub struct IngestSender {
+    sender: mpsc::Sender<IncomingMessage>,
+    metrics: Arc<IngestMetrics>,
+}
+
+impl IngestSender {
+    /// Waits while the queue is full, so that the broker holds the messages instead of the memory.
+    /// Returns false if the writer stopped.
+    pub async fn send(&self, message: IncomingMessage) -> bool {
+        let sent = match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(message)) => {
+                self.metrics.queue_full.fetch_add(1, Ordering::Relaxed);
+                let started = Instant::now();
+                let sent = self.sender.send(message).await.is_ok();
+                self.metrics
+                    .waited_ms
+                    .fetch_add(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                sent
+            }
+            Err(TrySendError::Closed(_)) => false,
+        };
+        if sent {
+            let received = self.metrics.received.fetch_add(1, Ordering::Relaxed) + 1;
+            let depth = received.saturating_sub(self.metrics.written.load(Ordering::Relaxed));
+            self.metrics
+                .max_queue_depth
+                .fetch_max(depth, Ordering::Relaxed);
+        }
+        sent
+    }
+}
+
+/// Queue for `capacity` messages. The writer stops after the sender is dropped
+/// and the queued messages are written.
+pub fn channel(
+    capacity: usize,
+) -> (
+    IngestSender,
+    mpsc::Receiver<IncomingMessage>,
+    Arc<IngestMetrics>,
+) {
+    let (sender, receiver) = mpsc::channel(capacity.max(1));
+    let metrics = Arc::new(IngestMetrics::default());
+    metrics
+        .capacity
+        .store(capacity.max(1) as u64, Ordering::Relaxed);
+    let sender = IngestSender {
+        sender,
+        metrics: metrics.clone(),
+    };
+    (sender, receiver, metrics)
+}
+
+/// Waits up to `timeout` for a message and takes the ones that are already queued behind it, up to `MAX_BATCH`.
+/// `TryRecvError::Empty` when no message arrived in time, `Disconnected` when the queue is closed and empty.
+/// Blocks the thread on the runtime, so the writer runs with `spawn_blocking`.
+pub fn next_batch(
+    receiver: &mut mpsc::Receiver<IncomingMessage>,
+    timeout: Duration,
+) -> Result<Vec<IncomingMessage>, TryRecvError> {
+    let first = Handle::current().block_on(tokio::time::timeout(timeout, receiver.recv()));
+    let mut batch = match first {
+        Ok(Some(message)) => vec![message],
+        Ok(None) => return Err(TryRecvError::Disconnected),
+        Err(_) => return Err(TryRecvError::Empty),
+    };
+    while batch.len() < MAX_BATCH {
+        match receiver.try_recv() {
+            Ok(message) => batch.push(message),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+        }
+    }
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(topic: &str) -> IncomingMessage {
+        IncomingMessage {
+            topic: topic.to_string(),
+            payload: "{}".to_string(),
+            arrived_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_and_backpressure() {
+        let (sender, mut receiver, metrics) = channel(2);
+        assert!(sender.send(message("zigbee2mqtt/kitchen")).await);
+        assert!(sender.send(message("zigbee2mqtt/bathroom")).await);
+
+        // The third message waits until the writer takes the first ones
+        let writer = tokio::task::spawn_blocking(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let mut batches = Vec::new();
+            let timeout = Duration::from_secs(5);
+            while let Ok(batch) = next_batch(&mut receiver, timeout) {
+                metrics.batch_written(batch.len());
+                batches.push(batch.len());
+            }
+            (batches, metrics.stats())
+        });
+        assert!(sender.send(message("zigbee2mqtt/attic")).await);
+        drop(sender);
+
+        let (batches, stats) = writer.await.unwrap();
+        assert_eq!(batches.iter().sum::<usize>(), 3);
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.written, 3);
+        assert_eq!(stats.queue_depth, 0);
+        assert!(stats.max_queue_depth >= 2);
+        assert_eq!(stats.queue_full, 1);
+        assert_eq!(stats.capacity, 2);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_times_out() {
+        let (sender, mut receiver, _metrics) = channel(2);
+        let writer = tokio::task::spawn_blocking(move || {
+            let timeout = Duration::from_millis(10);
+            let waited = next_batch(&mut receiver, timeout).map(|batch| batch.len());
+            drop(sender);
+            (waited, next_batch(&mut receiver, timeout).map(|batch| batch.len()))
+        });
+        let (waited, closed) = writer.await.unwrap();
+        assert_eq!(waited, Err(TryRecvError::Empty));
+        assert_eq!(closed, Err(TryRecvError::Disconnected));
+    }
+}
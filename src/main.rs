@@ -3,37 +3,105 @@ mod conn;
 mod mqtt;
 mod config;
 mod model;
+mod events;
+mod actions;
+mod air_quality;
+mod alerts;
+mod battery;
+mod comfort;
+mod contact;
+mod critical;
+mod devices;
+mod energy;
+mod ingest;
+mod mapping;
+mod mould;
+mod network;
+mod occupancy;
+mod plausibility;
+mod spool;
+mod thermostat;
+mod timestamps;
+mod webhook;
 
 use crate::conn::create_pool;
 use config::Config;
 use std::fs;
+use tokio::signal::unix::{signal, SignalKind};
 use crate::web::ru_berry_web;
 
 #[tokio::main]
 async fn main() -> rusqlite::Result<()> {
+    // `ru-berry hash-password <password>` prints a hash to use in the auth configuration
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == "hash-password" {
+        let hash = bcrypt::hash(&args[2], bcrypt::DEFAULT_COST).expect("Failed to hash password");
+        println!("{}", hash);
+        return Ok(());
+    }
+
     let config: Config = serde_json::from_str(&fs::read_to_string("config.json").expect("Unable to read config file"))
         .expect("Unable to parse config file");
 
     let pool = create_pool(&config.sqlite_database).expect("Failed to create SQLite connection pool");
     println!("Connected to SQLite database");
 
+    // Live events from the MQTT client to the web server
+    let events = events::channel();
+
+    // The web server publishes requests with the client of the MQTT task
+    let (mqtt_client, eventloop) = mqtt::create_client(&config);
+
+    // Messages from the MQTT client to the database writer
+    let (ingest, receiver, ingest_metrics) = ingest::channel(config.ingest_queue_size);
+
     // Start the web server in a separate task
     let web_pool = pool.clone();
     let web_config = config.clone();
+    let web_events = events.clone();
+    let web_mqtt_client = mqtt_client.clone();
+    let web_ingest_metrics = ingest_metrics.clone();
     tokio::spawn(async move {
-        ru_berry_web::start_web_server(&web_config, &web_pool).await;
+        ru_berry_web::start_web_server(&web_config, &web_pool, &web_events, &web_mqtt_client, &web_ingest_metrics)
+            .await;
+    });
+
+    // Write the messages on a thread of its own, SQLite blocks while it waits for the disk
+    let writer_pool = pool.clone();
+    let writer_config = config.clone();
+    let writer_events = events.clone();
+    let writer_mqtt_client = mqtt_client.clone();
+    let writer = tokio::task::spawn_blocking(move || {
+        mqtt::run_writer(&writer_config, &writer_pool, &writer_events, &writer_mqtt_client, receiver, &ingest_metrics);
     });
 
     // Start the MQTT client in a separate task
     let mqtt_pool = pool.clone();
     let mqtt_config = config.clone();
-    tokio::spawn(async move {
-        mqtt::start_mqtt_client(&mqtt_config, &mqtt_pool).await;
+    let mqtt_events = events.clone();
+    let mqtt_task = tokio::spawn(async move {
+        mqtt::start_mqtt_client(&mqtt_config, &mqtt_pool, &mqtt_events, mqtt_client, eventloop, ingest).await;
     });
 
     // Keep the main function alive
-    tokio::signal::ctrl_c().await.expect("Failed to listen for ctrl_c");
+    shutdown_signal().await;
+
+    // Stopping the MQTT client closes the queue, the writer writes what is left in it and stops
+    println!("Shutting down, writing the queued messages");
+    mqtt_task.abort();
+    let _ = mqtt_task.await;
+    if let Err(e) = writer.await {
+        println!("Database writer failed: {:?}", e);
+    }
 
     Ok(())
 }
 
+/// Ctrl+C, or SIGTERM when systemd stops the service
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("Failed to listen for ctrl_c"),
+        _ = terminate.recv() => println!("Received SIGTERM"),
+    }
+}
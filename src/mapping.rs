@@ -0,0 +1,406 @@
+use crate::config::{DeviceIdSource, PayloadMapping};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+
+/// Device id of zigbee2mqtt messages, e.g. `kitchen` of `zigbee2mqtt/kitchen`
+pub fn last_level(topic: &str) -> &str {
+    topic.split('/').next_back().unwrap_or(topic)
+}
+
+/// Whether the topic filter has `+` or `#` wildcards
+pub fn is_wildcard(filter: &str) -> bool {
+    filter.split('/').any(|part| part == "+" || part == "#")
+}
+
+/// Wildcards have to be whole levels and `#` has to be the last one
+pub fn is_valid_filter(filter: &str) -> bool {
+    let parts: Vec<&str> = filter.split('/').collect();
+    parts.iter().enumerate().all(|(i, part)| match *part {
+        "#" => i == parts.len() - 1,
+        "+" => true,
+        part => !part.contains(['+', '#']),
+    })
+}
+
+/// Levels of the topic that the wildcards of the filter matched, in order.
+/// `#` captures the rest of the topic. None if the topic doesn't match.
+pub fn captures<'a>(filter: &str, topic: &'a str) -> Option<Vec<&'a str>> {
+    let mut captures = Vec::new();
+    let mut rest = Some(topic);
+    for part in filter.split('/') {
+        if part == "#" {
+            // Also matches the parent level, `a/#` matches `a`
+            captures.push(rest.unwrap_or(""));
+            return Some(captures);
+        }
+        let (level, remaining) = match rest?.split_once('/') {
+            Some((level, remaining)) => (level, Some(remaining)),
+            None => (rest?, None),
+        };
+        match part {
+            "+" => captures.push(level),
+            part if part == level => {}
+            _ => return None,
+        }
+        rest = remaining;
+    }
+    rest.is_none().then_some(captures)
+}
+
+/// Whether the topic matches an MQTT topic filter with `+` and `#` wildcards
+pub fn matches(filter: &str, topic: &str) -> bool {
+    captures(filter, topic).is_some()
+}
+
+/// Whether the topic matches any of the filters
+pub fn matches_any(filters: &[String], topic: &str) -> bool {
+    filters.iter().any(|filter| matches(filter, topic))
+}
+
+/// Device id of a message that is not mapped. With a wildcard subscription like
+/// `home/+/climate` it's the level of the last wildcard, otherwise the last level.
+pub fn device_id<'a>(subscriptions: &[String], topic: &'a str) -> &'a str {
+    subscriptions
+        .iter()
+        .filter_map(|filter| captures(filter, topic))
+        .next()
+        .and_then(|captures| captures.last().copied())
+        .map(last_level)
+        .filter(|device_id| !device_id.is_empty())
+        .unwrap_or_else(|| last_level(topic))
+}
+
+/// The first mapping whose topic matches
+pub fn find<'a>(mappings: &'a [PayloadMapping], topic: &str) -> Option<&'a PayloadMapping> {
+    mappings
+        .iter()
+        .find(|mapping| matches(&mapping.topic, topic))
+}
+
+/// A message in the format of zigbee2mqtt
+#[derive(Debug, PartialEq)]
+pub struct Mapped {
+    pub(crate) device_id: String,
+    pub(crate) payload: Value,
+}
+
+/// Applies the mappings. Plain numbers are combined with the latest plain numbers
+/// of the other topics of the device, so that e.g. the temperature and humidity
+/// that ESPHome publishes to separate topics make a reading.
+/// Values older than `max_age_minutes` of their mapping are not combined anymore.
+#[derive(Default)]
+pub struct Mapper {
+    /// Values of every device with the time they expire
+    plain_values: HashMap<String, HashMap<String, (Value, DateTime<Utc>)>>,
+}
+
+impl Mapper {
+    pub fn map(
+        &mut self,
+        mapping: &PayloadMapping,
+        topic: &str,
+        payload_str: &str,
+        arrived_at: DateTime<Utc>,
+    ) -> Result<Mapped, String> {
+        let (device_id, mut values, plain) = convert(mapping, topic, payload_str)?;
+        if plain {
+            let expires_at = arrived_at + Duration::minutes(mapping.max_age_minutes as i64);
+            let device_values = self.plain_values.entry(device_id.clone()).or_default();
+            device_values.retain(|_, (_, expires)| *expires > arrived_at);
+            for (field, value) in values {
+                device_values.insert(field, (value, expires_at));
+            }
+            values = device_values
+                .iter()
+                .map(|(field, (value, _))| (field.clone(), value.clone()))
+                .collect();
+        }
+        Ok(with_defaults(mapping, device_id, values))
+    }
+
+    /// Like `map`, but the plain values of the message are not kept for the next messages
+    pub fn peek(
+        &self,
+        mapping: &PayloadMapping,
+        topic: &str,
+        payload_str: &str,
+        arrived_at: DateTime<Utc>,
+    ) -> Result<Mapped, String> {
+        let (device_id, mut values, plain) = convert(mapping, topic, payload_str)?;
+        if let (true, Some(device_values)) = (plain, self.plain_values.get(&device_id)) {
+            for (field, (value, expires)) in device_values {
+                if *expires > arrived_at {
+                    values.entry(field.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+        Ok(with_defaults(mapping, device_id, values))
+    }
+}
+
+/// Device id and values of a payload, and whether it is a plain value
+fn convert(
+    mapping: &PayloadMapping,
+    topic: &str,
+    payload_str: &str,
+) -> Result<(String, Map<String, Value>, bool), String> {
+    let (payload, plain) = match serde_json::from_str::<Value>(payload_str) {
+        Ok(value @ (Value::Object(_) | Value::Array(_))) => (value, false),
+        Ok(value) => (value, true),
+        Err(_) => {
+            let number = payload_str
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(Number::from_f64)
+                .ok_or_else(|| format!("Payload of {} is not JSON or a number", topic))?;
+            (Value::Number(number), true)
+        }
+    };
+
+    let device_id = match &mapping.device_id {
+        None => Some(last_level(topic).to_string()),
+        Some(DeviceIdSource::Capture(index)) => captures(&mapping.topic, topic)
+            .and_then(|captures| captures.get(*index).map(|capture| last_level(capture).to_string())),
+        Some(DeviceIdSource::Level(level)) => topic.split('/').nth(*level).map(str::to_string),
+        Some(DeviceIdSource::Pointer(pointer)) => payload
+            .pointer(pointer)
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        Some(DeviceIdSource::Fixed(device_id)) => Some(device_id.clone()),
+    }
+    .filter(|device_id| !device_id.is_empty())
+    .ok_or_else(|| format!("No device id for {}", topic))?;
+
+    let mut values = Map::new();
+    for field in &mapping.fields {
+        let value = match &field.pointer {
+            Some(pointer) => payload.pointer(pointer),
+            None => Some(&payload),
+        };
+        let value = match value {
+            // Integers like `linkquality` stay integers when they are not scaled
+            Some(Value::Number(number)) if field.scale == 1.0 && field.offset == 0.0 => {
+                Some(Value::Number(number.clone()))
+            }
+            Some(Value::Number(number)) => number
+                .as_f64()
+                .and_then(|number| Number::from_f64(number * field.scale + field.offset))
+                .map(Value::Number),
+            // e.g. `contact` or `action`
+            Some(value @ (Value::Bool(_) | Value::String(_))) => Some(value.clone()),
+            _ => None,
+        };
+        if let Some(value) = value {
+            values.insert(field.field.clone(), value);
+        }
+    }
+    if values.is_empty() {
+        return Err(format!("No mapped values in the payload of {}", topic));
+    }
+    Ok((device_id, values, plain))
+}
+
+fn with_defaults(mapping: &PayloadMapping, device_id: String, mut values: Map<String, Value>) -> Mapped {
+    for (field, value) in &mapping.defaults {
+        values.entry(field.clone()).or_insert_with(|| value.clone());
+    }
+    Mapped {
+        device_id,
+        payload: Value::Object(values),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mapping(value: Value) -> PayloadMapping {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_topic_filters() {
+        assert!(matches("tele/+/SENSOR", "tele/livingroom/SENSOR"));
+        assert!(!matches("tele/+/SENSOR", "tele/livingroom/STATE"));
+        assert!(!matches("tele/+", "tele/livingroom/SENSOR"));
+        assert!(matches("esphome/#", "esphome/bedroom/sensor/temperature/state"));
+        assert!(matches("esphome/#", "esphome"));
+        assert!(matches("zigbee2mqtt/kitchen", "zigbee2mqtt/kitchen"));
+        assert!(!matches("zigbee2mqtt/kitchen", "zigbee2mqtt/kitchen/set"));
+    }
+
+    #[test]
+    fn test_wildcard_captures() {
+        assert_eq!(captures("home/+/climate", "home/attic/climate"), Some(vec!["attic"]));
+        assert_eq!(
+            captures("+/+/state", "esphome/bedroom/state"),
+            Some(vec!["esphome", "bedroom"])
+        );
+        assert_eq!(captures("esphome/#", "esphome/bedroom/sensor"), Some(vec!["bedroom/sensor"]));
+        assert_eq!(captures("esphome/#", "esphome"), Some(vec![""]));
+        assert_eq!(captures("home/+/climate", "home/attic"), None);
+
+        assert!(is_valid_filter("zigbee2mqtt/+/set"));
+        assert!(is_valid_filter("#"));
+        assert!(!is_valid_filter("zigbee2mqtt/#/set"));
+        assert!(!is_valid_filter("zigbee2mqtt/kitchen+"));
+    }
+
+    #[test]
+    fn test_device_id_of_subscriptions() {
+        let subscriptions = vec!["home/+/climate".to_string(), "zigbee2mqtt/#".to_string()];
+        assert_eq!(device_id(&subscriptions, "home/attic/climate"), "attic");
+        assert_eq!(device_id(&subscriptions, "zigbee2mqtt/kitchen"), "kitchen");
+        assert_eq!(device_id(&subscriptions, "zigbee2mqtt/garden/shed"), "shed");
+        assert_eq!(device_id(&subscriptions, "zigbee2mqtt"), "zigbee2mqtt");
+        assert_eq!(device_id(&[], "zigbee2mqtt/kitchen"), "kitchen");
+
+        let exclude = vec!["zigbee2mqtt/bridge/#".to_string(), "zigbee2mqtt/+/set".to_string()];
+        assert!(matches_any(&exclude, "zigbee2mqtt/bridge/state"));
+        assert!(matches_any(&exclude, "zigbee2mqtt/radiator/set"));
+        assert!(!matches_any(&exclude, "zigbee2mqtt/radiator"));
+    }
+
+    #[test]
+    fn test_nested_json_with_scaling() {
+        let tasmota = mapping(json!({
+            "topic": "tele/+/SENSOR",
+            "device_id": { "level": 1 },
+            "fields": [
+                { "field": "temperature", "pointer": "/AM2301/Temperature", "scale": 0.5556, "offset": -17.778 },
+                { "field": "humidity", "pointer": "/AM2301/Humidity" },
+                { "field": "power", "pointer": "/ENERGY/Power" }
+            ],
+            "defaults": { "linkquality": 0 }
+        }));
+        let payload = r#"{"Time": "2024-07-01T12:00:00", "AM2301": {"Temperature": 68.0, "Humidity": 45.5}}"#;
+
+        let mapped = Mapper::default()
+            .map(&tasmota, "tele/livingroom/SENSOR", payload, Utc::now())
+            .unwrap();
+        assert_eq!(mapped.device_id, "livingroom");
+        let temperature = mapped.payload["temperature"].as_f64().unwrap();
+        assert!((temperature - 20.0).abs() < 0.01);
+        assert_eq!(mapped.payload["humidity"], json!(45.5));
+        assert_eq!(mapped.payload["linkquality"], json!(0));
+        assert!(mapped.payload.get("power").is_none());
+
+        assert!(Mapper::default()
+            .map(&tasmota, "tele/livingroom/SENSOR", r#"{"Wifi": {}}"#, Utc::now())
+            .is_err());
+    }
+
+    #[test]
+    fn test_plain_numbers_are_combined() {
+        let temperature = mapping(json!({
+            "topic": "esphome/+/sensor/temperature/state",
+            "device_id": { "level": 1 },
+            "fields": [{ "field": "temperature" }]
+        }));
+        let humidity = mapping(json!({
+            "topic": "esphome/+/sensor/humidity/state",
+            "device_id": { "level": 1 },
+            "fields": [{ "field": "humidity" }]
+        }));
+        let mut mapper = Mapper::default();
+
+        let mapped = mapper
+            .map(&temperature, "esphome/bedroom/sensor/temperature/state", "21.5", Utc::now())
+            .unwrap();
+        assert_eq!(mapped.payload, json!({ "temperature": 21.5 }));
+        // Peeking combines the values without keeping them
+        let peeked = mapper
+            .peek(&temperature, "esphome/bedroom/sensor/temperature/state", "22", Utc::now())
+            .unwrap();
+        assert_eq!(peeked.payload, json!({ "temperature": 22 }));
+        let mapped = mapper
+            .map(&humidity, "esphome/bedroom/sensor/humidity/state", " 48 \n", Utc::now())
+            .unwrap();
+        assert_eq!(mapped.payload, json!({ "temperature": 21.5, "humidity": 48 }));
+        assert!(mapper
+            .map(&humidity, "esphome/bedroom/sensor/humidity/state", "unavailable", Utc::now())
+            .is_err());
+    }
+
+    #[test]
+    fn test_plain_values_expire() {
+        let temperature = mapping(json!({
+            "topic": "esphome/+/sensor/temperature/state",
+            "device_id": { "level": 1 },
+            "fields": [{ "field": "temperature" }]
+        }));
+        let humidity = mapping(json!({
+            "topic": "esphome/+/sensor/humidity/state",
+            "device_id": { "level": 1 },
+            "fields": [{ "field": "humidity" }],
+            "max_age_minutes": 30
+        }));
+        let mut mapper = Mapper::default();
+        let start = Utc::now();
+        let at = |minutes| start + Duration::minutes(minutes);
+
+        mapper
+            .map(&humidity, "esphome/bedroom/sensor/humidity/state", "48", at(0))
+            .unwrap();
+        let mapped = mapper
+            .map(&temperature, "esphome/bedroom/sensor/temperature/state", "21.5", at(29))
+            .unwrap();
+        assert_eq!(mapped.payload, json!({ "temperature": 21.5, "humidity": 48 }));
+
+        // The humidity stopped, the temperature is kept for the 60 minutes by default
+        let mapped = mapper
+            .map(&temperature, "esphome/bedroom/sensor/temperature/state", "21.0", at(31))
+            .unwrap();
+        assert_eq!(mapped.payload, json!({ "temperature": 21.0 }));
+        let mapped = mapper
+            .map(&humidity, "esphome/bedroom/sensor/humidity/state", "50", at(90))
+            .unwrap();
+        assert_eq!(mapped.payload, json!({ "temperature": 21.0, "humidity": 50 }));
+        let mapped = mapper
+            .map(&humidity, "esphome/bedroom/sensor/humidity/state", "51", at(92))
+            .unwrap();
+        assert_eq!(mapped.payload, json!({ "humidity": 51 }));
+    }
+
+    #[test]
+    fn test_device_id_sources() {
+        let mut mapper = Mapper::default();
+        let pointer = mapping(json!({
+            "topic": "sensors/#",
+            "device_id": { "pointer": "/name" },
+            "fields": [{ "field": "co2", "pointer": "/co2" }]
+        }));
+        let mapped = mapper
+            .map(&pointer, "sensors/air", r#"{"name": "office", "co2": 700}"#, Utc::now())
+            .unwrap();
+        assert_eq!(mapped.device_id, "office");
+        assert!(mapper.map(&pointer, "sensors/air", r#"{"co2": 700}"#, Utc::now()).is_err());
+
+        let capture = mapping(json!({
+            "topic": "+/sensor/+/state",
+            "device_id": { "capture": 0 },
+            "fields": [{ "field": "temperature" }]
+        }));
+        let mapped = mapper
+            .map(&capture, "attic/sensor/temperature/state", "18.5", Utc::now())
+            .unwrap();
+        assert_eq!(mapped.device_id, "attic");
+
+        let fixed = mapping(json!({
+            "topic": "home/door",
+            "device_id": { "fixed": "front_door" },
+            "fields": [{ "field": "contact", "pointer": "/closed" }]
+        }));
+        let mapped = mapper.map(&fixed, "home/door", r#"{"closed": false}"#, Utc::now()).unwrap();
+        assert_eq!(
+            mapped,
+            Mapped {
+                device_id: "front_door".to_string(),
+                payload: json!({ "contact": false })
+            }
+        );
+    }
+}
@@ -1,29 +1,141 @@
-use chrono::{Local, NaiveDateTime, TimeZone, Utc};
+use crate::alerts::Metric;
+use crate::comfort::DerivedMetrics;
+use crate::timestamps;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SensorData {
     pub(crate) temperature: f32,
     pub(crate) humidity: i32,
     pub(crate) linkquality: i32,
     pub(crate) device_id: String,
-    pub(crate) received_at: String,
+    /// Stored in milliseconds, serialized in ISO 8601
+    pub(crate) received_at: DateTime<Utc>,
+    /// Calculated from the temperature and humidity, not stored
+    #[serde(flatten)]
+    pub(crate) derived: DerivedMetrics,
 }
 
 impl SensorData {
     pub fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
-        let received_at: String = row.get(5)?;
-        let received_at_naive = NaiveDateTime::parse_from_str(&received_at, "%Y-%m-%d %H:%M:%S")
-            .expect("Failed to parse received_at");
-        let received_at_utc = Utc.from_utc_datetime(&received_at_naive);
-        let received_at_with_tz = received_at_utc.with_timezone(&Local);
+        let temperature = row.get::<_, f64>(1)?;
+        let humidity: i32 = row.get(2)?;
 
         Ok(SensorData {
-            temperature: row.get::<_, f64>(1)? as f32,
-            humidity: row.get(2)?,
+            temperature: temperature as f32,
+            humidity,
             linkquality: row.get(3)?,
             device_id: row.get(4)?,
-            received_at: received_at_with_tz.to_string(),
+            received_at: timestamps::get(row, 5)?,
+            derived: DerivedMetrics::new(temperature, humidity as f64),
         })
     }
 }
+
+#[derive(Serialize, Debug)]
+pub struct Alert {
+    pub(crate) rule: String,
+    pub(crate) device_id: String,
+    pub(crate) value: f64,
+    /// Stored in milliseconds, serialized in ISO 8601
+    pub(crate) started_at: DateTime<Utc>,
+}
+
+impl Alert {
+    pub fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Alert {
+            rule: row.get("rule")?,
+            device_id: row.get("device_id")?,
+            value: row.get("value")?,
+            started_at: timestamps::get(row, row.as_ref().column_index("started_at")?)?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusType {
+    /// Displays max/min values for the last 3 days and the latest reading
+    Basic,
+    /// Displays if the limit is being hit and the latest reading
+    Boolean,
+    /// Displays the mould and condensation risk
+    Mould,
+    /// Displays if a door or window is open and how often it was opened
+    Contact,
+    /// Displays the air quality bands and if the room should be aired
+    #[serde(rename = "air_quality")]
+    AirQuality,
+    /// Unknown status type in the database, can't be set through the API
+    #[serde(skip_deserializing)]
+    None,
+}
+
+impl StatusType {
+    pub const ALL: [StatusType; 5] = [
+        StatusType::Basic,
+        StatusType::Boolean,
+        StatusType::Mould,
+        StatusType::Contact,
+        StatusType::AirQuality,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatusType::Basic => "basic",
+            StatusType::Boolean => "boolean",
+            StatusType::Mould => "mould",
+            StatusType::Contact => "contact",
+            StatusType::AirQuality => "air_quality",
+            StatusType::None => "none",
+        }
+    }
+
+    pub fn from_db(value: &str) -> StatusType {
+        StatusType::ALL
+            .into_iter()
+            .find(|t| t.as_str() == value)
+            .unwrap_or(StatusType::None)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TopicConfiguration {
+    pub(crate) id: i64,
+    /// Device id of the topic, or a topic filter like `zigbee2mqtt/+` for every device
+    /// that sends messages on a matching topic
+    pub(crate) topic_name: String,
+    pub(crate) status_type: StatusType,
+    pub(crate) display_name: Option<String>,
+    /// Metric that `min_limit` and `max_limit` apply to
+    pub(crate) limit_metric: Option<Metric>,
+    pub(crate) min_limit: Option<f64>,
+    pub(crate) max_limit: Option<f64>,
+    /// Unit shown after the value of `limit_metric`
+    pub(crate) unit: Option<String>,
+    pub(crate) position: i64,
+}
+
+impl TopicConfiguration {
+    pub fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let limit_metric: Option<String> = row.get("limit_metric")?;
+
+        Ok(TopicConfiguration {
+            id: row.get("id")?,
+            topic_name: row.get("topic_name")?,
+            status_type: StatusType::from_db(&row.get::<_, String>("status_type")?),
+            display_name: row.get("display_name")?,
+            limit_metric: limit_metric.as_deref().and_then(Metric::from_db),
+            min_limit: row.get("min_limit")?,
+            max_limit: row.get("max_limit")?,
+            unit: row.get("unit")?,
+            position: row.get("position")?,
+        })
+    }
+
+    /// Display name if one is configured, otherwise the device id
+    pub fn title(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.topic_name)
+    }
+}
@@ -0,0 +1,279 @@
+use crate::alerts::Metric;
+use crate::comfort::{dew_point, saturation_vapor_pressure, vapor_pressure};
+use crate::config::MouldConfig;
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use serde::Serialize;
+
+/// Mould can grow when the surface humidity stays above this
+const SURFACE_HUMIDITY_LIMIT: f64 = 80.0;
+/// Surface temperatures closer than this to the dew point are a condensation risk
+const CONDENSATION_MARGIN: f64 = 3.0;
+/// Hours above `SURFACE_HUMIDITY_LIMIT` in the last week that make the risk elevated
+const ELEVATED_HOURS_PER_WEEK: f64 = 6.0 * 7.0;
+/// Gaps between readings longer than this are not counted as time above the limit
+const MAX_READING_INTERVAL_HOURS: f64 = 2.0;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MouldRisk {
+    Low,
+    /// Surface humidity above the limit for more than 6 hours a day in the last week
+    Elevated,
+    /// Mean surface humidity of the last 30 days above the limit (IEA Annex 14)
+    High,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Condensation {
+    None,
+    /// Surface temperature is less than 3 °C above the dew point
+    Risk,
+    Condensing,
+}
+
+/// Mould and condensation risk of a room based on the temperature of its coldest surface
+#[derive(Serialize, Debug)]
+pub struct MouldAssessment {
+    pub(crate) surface_temperature: f64,
+    pub(crate) surface_humidity: f64,
+    pub(crate) dew_point: f64,
+    /// Surface temperature minus the dew point
+    pub(crate) condensation_margin: f64,
+    pub(crate) condensation: Condensation,
+    /// Hours with the surface humidity above 80 % in the last 7 days
+    pub(crate) hours_above_limit: f64,
+    /// Mean surface humidity of the last 30 days
+    pub(crate) mean_surface_humidity: f64,
+    pub(crate) risk: MouldRisk,
+}
+
+impl MouldAssessment {
+    /// Values for alert rules
+    pub fn values(&self) -> Vec<(Metric, f64)> {
+        let risk = match self.risk {
+            MouldRisk::Low => 0.0,
+            MouldRisk::Elevated => 1.0,
+            MouldRisk::High => 2.0,
+        };
+        vec![
+            (Metric::SurfaceHumidity, self.surface_humidity),
+            (Metric::CondensationMargin, self.condensation_margin),
+            (Metric::MouldRisk, risk),
+        ]
+    }
+}
+
+/// Temperature of a surface with the temperature factor `factor` (fRsi)
+pub fn surface_temperature(indoor: f64, outdoor: f64, factor: f64) -> f64 {
+    outdoor + factor * (indoor - outdoor)
+}
+
+/// Relative humidity of the room air when it's cooled down to the surface temperature
+pub fn surface_humidity(temperature: f64, humidity: f64, surface_temperature: f64) -> f64 {
+    let humidity = vapor_pressure(temperature, humidity)
+        / saturation_vapor_pressure(surface_temperature)
+        * 100.0;
+    humidity.min(100.0)
+}
+
+/// Readings of a device in the last `days`, oldest first
+fn readings(
+    conn: &Connection,
+    device_id: &str,
+    days: u32,
+) -> rusqlite::Result<Vec<(DateTime<Utc>, f64, f64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT received_at, temperature, humidity FROM sensor_data
+        WHERE device_id = ?1 AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at",
+    )?;
+    let rows = stmt
+        .query_map(
+            rusqlite::params![device_id, format!("-{} days", days)],
+            |row| {
+                Ok((
+                    timestamps::get(row, 0)?,
+                    row.get::<_, f64>(1)?,
+                    row.get::<_, f64>(2)?,
+                ))
+            },
+        )?
+        .collect();
+    rows
+}
+
+/// Returns None if the device has no readings in the last 30 days or no surface temperature
+/// can be worked out for it
+pub fn assess(
+    conn: &Connection,
+    config: &MouldConfig,
+    device_id: &str,
+) -> rusqlite::Result<Option<MouldAssessment>> {
+    if config.outdoor_device_id.as_deref() == Some(device_id) {
+        return Ok(None);
+    }
+
+    let indoor = readings(conn, device_id, 30)?;
+    let outdoor = match &config.outdoor_device_id {
+        Some(outdoor_device_id) => readings(conn, outdoor_device_id, 31)?,
+        None => Vec::new(),
+    };
+
+    // Surface humidity of every indoor reading, using the latest outdoor reading before it
+    let mut surface = Vec::new();
+    let mut outdoor_readings = outdoor.iter().peekable();
+    let mut outdoor_temperature = config.outdoor_temperature;
+    for (time, temperature, humidity) in &indoor {
+        while let Some((_, outdoor, _)) = outdoor_readings.next_if(|(t, _, _)| t <= time) {
+            outdoor_temperature = Some(*outdoor);
+        }
+
+        let surface_temperature = match config.surface_temperatures.get(device_id) {
+            Some(surface_temperature) => *surface_temperature,
+            None => match outdoor_temperature {
+                Some(outdoor) => {
+                    surface_temperature(*temperature, outdoor, config.temperature_factor)
+                }
+                None => continue,
+            },
+        };
+        surface.push((
+            *time,
+            *temperature,
+            *humidity,
+            surface_temperature,
+            surface_humidity(*temperature, *humidity, surface_temperature),
+        ));
+    }
+
+    let (latest_time, temperature, humidity, surface_temperature, surface_humidity) =
+        match surface.last() {
+            Some(latest) => *latest,
+            None => return Ok(None),
+        };
+
+    let week_ago = latest_time - chrono::Duration::days(7);
+    let hours_above_limit: f64 = surface
+        .windows(2)
+        .filter(|pair| pair[0].0 >= week_ago && pair[0].4 >= SURFACE_HUMIDITY_LIMIT)
+        .map(|pair| {
+            ((pair[1].0 - pair[0].0).num_seconds() as f64 / 3600.0).min(MAX_READING_INTERVAL_HOURS)
+        })
+        .sum();
+    let mean_surface_humidity = surface.iter().map(|s| s.4).sum::<f64>() / surface.len() as f64;
+
+    let dew_point = dew_point(temperature, humidity);
+    let condensation_margin = surface_temperature - dew_point;
+    let condensation = if condensation_margin <= 0.0 {
+        Condensation::Condensing
+    } else if condensation_margin < CONDENSATION_MARGIN {
+        Condensation::Risk
+    } else {
+        Condensation::None
+    };
+
+    let risk = if mean_surface_humidity >= SURFACE_HUMIDITY_LIMIT {
+        MouldRisk::High
+    } else if hours_above_limit >= ELEVATED_HOURS_PER_WEEK {
+        MouldRisk::Elevated
+    } else {
+        MouldRisk::Low
+    };
+
+    Ok(Some(MouldAssessment {
+        surface_temperature: round(surface_temperature),
+        surface_humidity: round(surface_humidity),
+        dew_point: round(dew_point),
+        condensation_margin: round(condensation_margin),
+        condensation,
+        hours_above_limit: round(hours_above_limit),
+        mean_surface_humidity: round(mean_surface_humidity),
+        risk,
+    }))
+}
+
+fn round(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::{get_conn, get_test_pool, SqlitePool};
+    use std::collections::HashMap;
+
+    fn config() -> MouldConfig {
+        MouldConfig {
+            outdoor_device_id: Some("garden".to_string()),
+            outdoor_temperature: None,
+            temperature_factor: 0.7,
+            surface_temperatures: HashMap::new(),
+        }
+    }
+
+    fn insert_reading(
+        pool: &SqlitePool,
+        device_id: &str,
+        temperature: f64,
+        humidity: i64,
+        hours_ago: i64,
+    ) {
+        get_conn(pool)
+            .execute(
+                "INSERT INTO sensor_data (temperature, humidity, linkquality, device_id, received_at)
+                VALUES (?1, ?2, 100, ?3, unixepoch('now', ?4) * 1000)",
+                rusqlite::params![temperature, humidity, device_id, format!("-{} hours", hours_ago)],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_surface_humidity() {
+        assert_eq!(surface_temperature(20.0, 0.0, 0.7), 14.0);
+        // 20 °C and 50 % has a dew point of about 9.3 °C
+        assert!((surface_humidity(20.0, 50.0, 20.0) - 50.0).abs() < 0.01);
+        assert!(surface_humidity(20.0, 50.0, 14.0) > 70.0);
+        assert_eq!(surface_humidity(20.0, 50.0, 5.0), 100.0);
+    }
+
+    #[test]
+    fn test_assess_without_surface_temperature() {
+        let pool = get_test_pool();
+        insert_reading(&pool, "bathroom", 20.0, 60, 1);
+
+        // No outdoor readings or fallback
+        assert!(assess(&get_conn(&pool), &config(), "bathroom").unwrap().is_none());
+        // Nothing to assess for the outdoor device itself
+        assert!(assess(&get_conn(&pool), &config(), "garden").unwrap().is_none());
+
+        let mut fixed = config();
+        fixed
+            .surface_temperatures
+            .insert("bathroom".to_string(), 18.0);
+        let assessment = assess(&get_conn(&pool), &fixed, "bathroom").unwrap().unwrap();
+        assert_eq!(assessment.surface_temperature, 18.0);
+        assert_eq!(assessment.risk, MouldRisk::Low);
+        assert_eq!(assessment.condensation, Condensation::None);
+    }
+
+    #[test]
+    fn test_assess_with_outdoor_device() {
+        let pool = get_test_pool();
+        // Cold outside and humid inside for the last 3 days
+        for hours_ago in (0..72).rev() {
+            insert_reading(&pool, "garden", 0.0, 90, hours_ago);
+            insert_reading(&pool, "bathroom", 20.0, 70, hours_ago);
+        }
+
+        let assessment = assess(&get_conn(&pool), &config(), "bathroom").unwrap().unwrap();
+        assert_eq!(assessment.surface_temperature, 14.0);
+        assert_eq!(assessment.surface_humidity, 100.0);
+        assert_eq!(assessment.condensation, Condensation::Condensing);
+        assert_eq!(assessment.hours_above_limit, 71.0);
+        assert_eq!(assessment.risk, MouldRisk::High);
+        assert!(assessment.values().contains(&(Metric::MouldRisk, 2.0)));
+    }
+}
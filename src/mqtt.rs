@@ -1,106 +1,764 @@
-use crate::config::Config;
-use crate::conn::{get_conn, SqlitePool};
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
-use rusqlite::params;
+use crate::actions::{self, ActionEvent};
+use crate::air_quality::{self, AirQualityReport};
+use crate::alerts::{self, Metric};
+use crate::battery;
+use crate::comfort::DerivedMetrics;
+use crate::config::{ActionTrigger, Config};
+use crate::contact;
+use crate::critical::{self, CriticalAlarm, UnstoredAlarm};
+use crate::conn::{try_get_conn, SqlitePool};
+use crate::devices::{mark_seen, watch_offline_devices};
+use crate::energy::{self, EnergyReport};
+use crate::mapping::{self, Mapper};
+use crate::events::{broadcast, EventSender, LiveEvent};
+use crate::ingest::{self, IncomingMessage, IngestMetrics, IngestSender};
+use crate::model::SensorData;
+use crate::mould;
+use crate::network;
+use crate::occupancy;
+use crate::plausibility::{self, PlausibilityFilter};
+use crate::spool::Spool;
+use crate::thermostat::{self, ThermostatReport};
+use crate::timestamps;
+use crate::web::display::Formatter;
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, MqttOptions, QoS};
+use rusqlite::{params, Connection, ErrorCode};
 use serde_json::{Map, Value};
+use std::collections::HashMap;
 use std::error::Error;
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TryRecvError};
 
-pub async fn start_mqtt_client(config: &Config, pool: &SqlitePool) {
-    println!("Starting MQTT client");
+/// How often the spool is written while no messages arrive
+const SPOOL_REPLAY_INTERVAL: Duration = Duration::from_secs(30);
 
+/// Nothing is sent before `start_mqtt_client` polls the event loop
+pub fn create_client(config: &Config) -> (AsyncClient, EventLoop) {
     let mut mqtt_options = MqttOptions::new("", &config.mqtt_ip, config.mqtt_port);
-    let mut last_messages: std::collections::HashMap<String, Instant> =
-        std::collections::HashMap::new();
     mqtt_options.set_credentials(&config.username, &config.password);
     mqtt_options.set_keep_alive(Duration::from_secs(900)); // 15 minutes
 
-    let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+    AsyncClient::new(mqtt_options, 10)
+}
+
+pub async fn start_mqtt_client(
+    config: &Config,
+    pool: &SqlitePool,
+    events: &EventSender,
+    client: AsyncClient,
+    mut eventloop: EventLoop,
+    ingest: IngestSender,
+) {
+    println!("Starting MQTT client");
+
+    tokio::spawn(watch_offline_devices(
+        pool.clone(),
+        events.clone(),
+        config.device_offline_minutes,
+    ));
+
+    if !config.heating_schedule.is_empty() {
+        tokio::spawn(thermostat::run_schedule(
+            config.heating_schedule.clone(),
+            config.zigbee2mqtt_topic.clone(),
+            Formatter::new(&config.display).expect("Invalid display configuration"),
+            client.clone(),
+        ));
+    }
+
+    if !config.critical_alarms.notify.is_empty() {
+        tokio::spawn(critical::run_reminders(
+            pool.clone(),
+            config.critical_alarms.clone(),
+            client.clone(),
+        ));
+    }
 
     // Subscribe to multiple topics
     let topics = &config.mqtt_topics;
     for topic in topics {
         client.subscribe(topic, QoS::AtMostOnce).await.unwrap();
     }
+    let network_map_topic = network::response_topic(&config.zigbee2mqtt_topic);
+    client.subscribe(&network_map_topic, QoS::AtMostOnce).await.unwrap();
 
-    // Iterate to poll the eventloop for connection progress and print messages
+    // Iterate to poll the eventloop for connection progress and queue the messages for the writer
     while let Ok(notification) = eventloop.poll().await {
         match notification {
             Event::Incoming(Incoming::Publish(publish)) => {
-                let payload_str = String::from_utf8(publish.payload.to_vec()).unwrap();
-                let local_timestamp = chrono::Local::now()
-                    .with_timezone(&chrono::Local)
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string();
-
-                // Insert all received messages into messages table
-                audit_message(&pool, &publish.topic, &payload_str);
-
-                let should_update = if let Some(t) = last_messages.get(&publish.topic) {
-                    t.elapsed() > Duration::from_secs(1800)
-                } else {
-                    true
+                let message = IncomingMessage {
+                    topic: publish.topic,
+                    payload: String::from_utf8_lossy(&publish.payload).into_owned(),
+                    arrived_at: Utc::now().trunc_subsecs(3),
                 };
+                if !ingest.send(message).await {
+                    println!("Database writer stopped, stopping the MQTT client");
+                    return;
+                }
+            }
+
+            Event::Incoming(event) => println!("Received = {:?}", event),
+            Event::Outgoing(_) => {}
+        }
+    }
+}
+
+/// Writes the queued messages until the MQTT client stops. Runs on its own thread with
+/// `spawn_blocking`, so that SQLite doesn't block the other tasks.
+pub fn run_writer(
+    config: &Config,
+    pool: &SqlitePool,
+    events: &EventSender,
+    client: &AsyncClient,
+    mut receiver: mpsc::Receiver<IncomingMessage>,
+    metrics: &IngestMetrics,
+) {
+    let mut writer = Writer::new(config, pool, events, client, Spool::open(config.spool_file()));
+    // Messages spooled before a restart don't wait for the first message
+    writer.replay_spool();
+    metrics.set_spooled(writer.spool.len());
+    let mut next_replay = Instant::now() + SPOOL_REPLAY_INTERVAL;
+    loop {
+        match ingest::next_batch(&mut receiver, next_replay.saturating_duration_since(Instant::now())) {
+            Ok(batch) => {
+                writer.write(&batch);
+                metrics.batch_written(batch.len());
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => break,
+        }
+        if Instant::now() >= next_replay {
+            writer.replay_spool();
+            next_replay = Instant::now() + SPOOL_REPLAY_INTERVAL;
+        }
+        metrics.set_spooled(writer.spool.len());
+    }
+    if writer.spool.is_empty() {
+        println!("Database writer stopped, every queued message is written");
+    } else {
+        println!("Database writer stopped, {} messages are left in the spool", writer.spool.len());
+    }
+}
+
+/// Handles the messages in the order they arrived, with the state that is kept between them
+struct Writer<'a> {
+    config: &'a Config,
+    pool: &'a SqlitePool,
+    events: &'a EventSender,
+    client: &'a AsyncClient,
+    network_map_topic: String,
+    exclude_topics: Vec<String>,
+    /// Arrival of the last handled message of each topic
+    last_messages: HashMap<String, DateTime<Utc>>,
+    mapper: Mapper,
+    plausibility_filter: PlausibilityFilter,
+    spool: Spool,
+    /// Last critical state that each device reported, for the alarms of messages that are spooled
+    alarm_states: HashMap<(String, &'static str), bool>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(
+        config: &'a Config,
+        pool: &'a SqlitePool,
+        events: &'a EventSender,
+        client: &'a AsyncClient,
+        spool: Spool,
+    ) -> Self {
+        Writer {
+            config,
+            pool,
+            events,
+            client,
+            network_map_topic: network::response_topic(&config.zigbee2mqtt_topic),
+            exclude_topics: config.exclude_topics(),
+            last_messages: HashMap::new(),
+            mapper: Mapper::default(),
+            plausibility_filter: PlausibilityFilter::default(),
+            spool,
+            alarm_states: HashMap::new(),
+        }
+    }
+
+    /// Responses of the bridge and excluded topics are not device messages
+    fn is_device_message(&self, topic: &str) -> bool {
+        // Wildcard subscriptions also receive the bridge and the commands sent to the devices
+        topic != self.network_map_topic && !mapping::matches_any(&self.exclude_topics, topic)
+    }
+
+    /// Messages are spooled while the database is unavailable, and the spooled ones are written
+    /// before the next messages once it is available again
+    fn write(&mut self, batch: &[IncomingMessage]) {
+        // Newer messages wait behind the spooled ones
+        if !self.replay_spool() {
+            self.spool_live(batch);
+            return;
+        }
+        let written = self.write_or_drop(batch, false);
+        if written < batch.len() {
+            println!("Spooling {} messages", batch.len() - written);
+            self.spool_live(&batch[written..]);
+        }
+    }
+
+    /// Writes the spooled messages, returns whether the spool is empty afterwards
+    fn replay_spool(&mut self) -> bool {
+        if self.spool.is_empty() {
+            return true;
+        }
+        println!("Writing {} spooled messages", self.spool.len());
+        while !self.spool.is_empty() {
+            let spooled = self.spool.next(ingest::MAX_BATCH);
+            if spooled.is_empty() {
+                break;
+            }
+            let written = self.write_or_drop(&spooled.messages, true);
+            if written < spooled.messages.len() {
+                // The next replay starts with the first message that isn't written
+                if written > 0 {
+                    let done = self.spool.next(written);
+                    self.spool.remove(&done);
+                }
+                return false;
+            }
+            self.spool.remove(&spooled);
+        }
+        self.spool.is_empty()
+    }
 
-                if should_update {
-                    last_messages.insert(publish.topic.clone(), Instant::now());
-                } else {
+    /// Writes the messages in one batch. If the batch fails while the database is available,
+    /// the messages are written one by one and the ones that fail are dropped, so that they don't
+    /// hold back the others. Returns how many messages are written or dropped before the database
+    /// was unavailable, the rest are left to spool.
+    fn write_or_drop(&mut self, messages: &[IncomingMessage], replay: bool) -> usize {
+        let e = match self.write_batch(messages, replay) {
+            Ok(()) => return messages.len(),
+            Err(e) => e,
+        };
+        if is_transient(&*e) {
+            println!("Database is not available: {}", e);
+            return 0;
+        }
+        if let [message] = messages {
+            log_dropped(message, &*e);
+            return 1;
+        }
+        println!("Failed to write {} messages, writing them one by one: {}", messages.len(), e);
+        for (written, message) in messages.iter().enumerate() {
+            if let Err(e) = self.write_batch(std::slice::from_ref(message), replay) {
+                if is_transient(&*e) {
+                    println!("Database is not available: {}", e);
+                    return written;
+                }
+                log_dropped(message, &*e);
+            }
+        }
+        messages.len()
+    }
+
+    /// Leaks, smoke and button presses can't wait until the database is available again
+    fn spool_live(&mut self, batch: &[IncomingMessage]) {
+        let effects = self.spooled_effects(batch);
+        self.spool.append(batch);
+        effects.send(self.config, self.client, self.events, false);
+    }
+
+    /// Critical alarms that start and action triggers of live messages, which don't need the database.
+    /// They are only sent if the messages are spooled, `handle` sends them once the messages are written.
+    /// Without the database it can't be told whether an alarm was already sent before ru-berry started.
+    fn spooled_effects(&mut self, batch: &[IncomingMessage]) -> Effects<'a> {
+        let config = self.config;
+        let mut effects = Effects::default();
+        for message in batch {
+            if !self.is_device_message(&message.topic) {
+                continue;
+            }
+            // The mapper keeps its values for `handle`, which maps the message again when it is written
+            let (device_id, value) = match self.peek_device_payload(message) {
+                Ok((device_id, Ok(value))) => (device_id, value),
+                _ => continue,
+            };
+            let received_at = value
+                .as_object()
+                .and_then(|json_object| device_time(json_object, message.arrived_at))
+                .unwrap_or(message.arrived_at);
+
+            for (kind, active) in critical_states(&value) {
+                let was_active = self.alarm_states.insert((device_id.clone(), kind), active);
+                if active && was_active != Some(true) {
+                    effects.unstored_alarms.push(UnstoredAlarm {
+                        device_id: device_id.clone(),
+                        kind: kind.to_string(),
+                        started_at: received_at,
+                    });
+                }
+            }
+            if let Some(event) = action_event(&value, &device_id, message.arrived_at) {
+                for trigger in actions::matching(&config.action_triggers, &event) {
+                    effects.triggers.push((trigger, event.clone()));
+                }
+            }
+        }
+        effects
+    }
+
+    /// Device id and payload in the format of zigbee2mqtt,
+    /// messages of other devices than zigbee2mqtt are converted to it
+    fn device_payload(
+        &mut self,
+        message: &IncomingMessage,
+    ) -> Result<(String, serde_json::Result<Value>), String> {
+        let config = self.config;
+        match mapping::find(&config.payload_mappings, &message.topic) {
+            Some(payload_mapping) => self
+                .mapper
+                .map(payload_mapping, &message.topic, &message.payload, message.arrived_at)
+                .map(|mapped| (mapped.device_id, Ok(mapped.payload))),
+            None => Ok(zigbee2mqtt_payload(config, message)),
+        }
+    }
+
+    /// Like `device_payload`, without keeping the plain values of the message in the mapper
+    fn peek_device_payload(
+        &self,
+        message: &IncomingMessage,
+    ) -> Result<(String, serde_json::Result<Value>), String> {
+        let config = self.config;
+        match mapping::find(&config.payload_mappings, &message.topic) {
+            Some(payload_mapping) => self
+                .mapper
+                .peek(payload_mapping, &message.topic, &message.payload, message.arrived_at)
+                .map(|mapped| (mapped.device_id, Ok(mapped.payload))),
+            None => Ok(zigbee2mqtt_payload(config, message)),
+        }
+    }
+
+    /// The throttle and the critical states of a batch that fails are rolled back with its transaction
+    fn write_batch(&mut self, batch: &[IncomingMessage], replay: bool) -> Result<(), Box<dyn Error>> {
+        let last_messages = self.last_messages.clone();
+        let alarm_states = self.alarm_states.clone();
+        let result = self.write_transaction(batch, replay);
+        if result.is_err() {
+            self.last_messages = last_messages;
+            self.alarm_states = alarm_states;
+        }
+        result
+    }
+
+    /// Writes the messages and everything stored from them in one transaction, so that nothing
+    /// of a batch that fails is stored twice when it is written again.
+    /// `replay` is set for spooled messages, see `Effects`.
+    fn write_transaction(&mut self, batch: &[IncomingMessage], replay: bool) -> Result<(), Box<dyn Error>> {
+        let mut conn = try_get_conn(self.pool)?;
+        let tx = conn.transaction()?;
+
+        let mut effects = Effects::default();
+        for message in batch {
+            self.handle(&tx, message, replay, &mut effects)?;
+        }
+        tx.commit()?;
+        effects.send(self.config, self.client, self.events, replay);
+        Ok(())
+    }
+
+    /// Fails if the database can't be written, values that are missing or invalid are skipped
+    fn handle(
+        &mut self,
+        conn: &Connection,
+        message: &IncomingMessage,
+        replay: bool,
+        effects: &mut Effects<'a>,
+    ) -> rusqlite::Result<()> {
+        let config = self.config;
+        let topic = &message.topic;
+        let payload_str = &message.payload;
+        let arrived_at = message.arrived_at;
+        let log_timestamp = arrived_at.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        if *topic == self.network_map_topic {
+            match network::parse_response(payload_str) {
+                Ok(map) => {
+                    network::store(conn, &map, arrived_at)?;
+                    println!("{} - Stored the network map", log_timestamp);
+                }
+                Err(e) => println!("{} - {}", log_timestamp, e),
+            }
+            return Ok(());
+        }
+        if !self.is_device_message(topic) {
+            return Ok(());
+        }
+
+        let device_payload = self.device_payload(message);
+        // Insert all received messages into messages table, with the device if it is known
+        let audited_device_id = device_payload.as_ref().ok().map(|(device_id, _)| device_id.as_str());
+        audit_message(conn, message, audited_device_id)?;
+
+        let (device_id, json_value) = match device_payload {
+            Ok(device_payload) => device_payload,
+            Err(e) => {
+                println!("{} - {}", log_timestamp, e);
+                return Ok(());
+            }
+        };
+        let device_id = device_id.as_str();
+        let received_at = json_value
+            .as_ref()
+            .ok()
+            .and_then(Value::as_object)
+            .and_then(|json_object| device_time(json_object, arrived_at))
+            .unwrap_or(arrived_at);
+
+        if let Some(event) = mark_seen(conn, device_id, topic, received_at, config.device_offline_minutes)? {
+            println!("{} - {} Device is online", log_timestamp, topic);
+            effects.events.push(event);
+        }
+
+        // Doors open and close, rooms empty and buttons are pressed more often than the readings are stored.
+        // Leaks and smoke can't wait for the next stored reading.
+        if let Ok(value) = &json_value {
+            // The states of spooled messages were kept when they were spooled
+            if !replay {
+                for (kind, active) in critical_states(value) {
+                    self.alarm_states.insert((device_id.to_string(), kind), active);
+                }
+            }
+            critical_report(value, conn, device_id, arrived_at, effects)?;
+            contact_report(value, conn, device_id, arrived_at, effects)?;
+            occupancy_report(value, conn, device_id, arrived_at)?;
+            action_report(value, conn, device_id, arrived_at, config, effects)?;
+        }
+
+        // Spooled readings are all stored, the throttle only applies to live messages
+        let should_update = if let (false, Some(t)) = (replay, self.last_messages.get(topic)) {
+            arrived_at - *t > chrono::Duration::seconds(1800)
+        } else {
+            true
+        };
+
+        if !should_update {
+            println!(
+                "{} - {} Last message was less than 30 minutes ago, skipping",
+                log_timestamp, topic
+            );
+            return Ok(());
+        }
+
+        println!("{} - {} Handled message: {:?}", log_timestamp, topic, payload_str);
+
+        match json_value {
+            Ok(mut value) => {
+                // Implausible values are stored separately and the rest of the message is handled
+                let rejected_readings =
+                    self.plausibility_filter.filter(&config.plausibility_rules, &mut value, device_id, received_at);
+                for rejected in &rejected_readings {
                     println!(
-                        "{} - {} Last message was less than 30 minutes ago, skipping",
-                        local_timestamp, &publish.topic
+                        "{} - {} Rejected {} {}: {}",
+                        log_timestamp, topic, rejected.metric.as_str(), rejected.value, rejected.reason
                     );
-                    continue;
+                    plausibility::record(conn, rejected)?;
+                }
+                let stored = handle_message(&value, conn, device_id, arrived_at, config, effects)?;
+                // A rejected spike doesn't hold back the next reading for 30 minutes
+                if stored || rejected_readings.is_empty() {
+                    self.last_messages.insert(topic.clone(), arrived_at);
                 }
+            }
+            Err(e) => println!("Failed to parse message as JSON: {:?}", e),
+        }
+        Ok(())
+    }
+}
 
-                println!("{} - {} Handled message: {:?}", local_timestamp, &publish.topic, payload_str);
+/// What the messages of a batch cause outside the database. Nothing is sent before the batch is written.
+/// The live events of spooled messages are sent when they are written, their notifications and
+/// triggers are not, they were sent when the messages were spooled, see `Writer::spooled_effects`.
+#[derive(Default)]
+struct Effects<'a> {
+    events: Vec<LiveEvent>,
+    alarms: Vec<CriticalAlarm>,
+    unstored_alarms: Vec<UnstoredAlarm>,
+    triggers: Vec<(&'a ActionTrigger, ActionEvent)>,
+}
 
-                let json_value = serde_json::from_str(&payload_str);
-                match json_value {
-                    Ok(value) => handle_message(&value, &pool, &publish.topic),
-                    Err(e) => println!("Failed to parse message as JSON: {:?}", e),
+impl Effects<'_> {
+    fn send(self, config: &Config, client: &AsyncClient, events: &EventSender, replay: bool) {
+        if !replay {
+            for alarm in &self.alarms {
+                critical::notify(&config.critical_alarms.notify, alarm, false, client);
+            }
+            for alarm in &self.unstored_alarms {
+                println!("{} {} alarm, stored once the database can be written", alarm.device_id, alarm.kind);
+                critical::notify_unstored(&config.critical_alarms.notify, alarm, client);
+            }
+            for (trigger, event) in &self.triggers {
+                actions::run(trigger, event, client);
+            }
+        }
+        for event in self.events {
+            broadcast(events, event);
+        }
+    }
+}
+
+/// Device id and payload of the topics that zigbee2mqtt publishes to
+fn zigbee2mqtt_payload(config: &Config, message: &IncomingMessage) -> (String, serde_json::Result<Value>) {
+    (
+        mapping::device_id(&config.mqtt_topics, &message.topic).to_string(),
+        serde_json::from_str::<Value>(&message.payload),
+    )
+}
+
+/// Messages that can't be stored are logged with their payload
+fn log_dropped(message: &IncomingMessage, e: &dyn Error) {
+    println!(
+        "{} - {} Dropped the message, it can't be stored: {}: {:?}",
+        message.arrived_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        message.topic,
+        e,
+        message.payload
+    );
+}
+
+/// Whether the database is unavailable for a while, e.g. locked by another connection or full,
+/// rather than the messages can't be stored
+fn is_transient(e: &(dyn Error + 'static)) -> bool {
+    if e.is::<r2d2::Error>() {
+        return true;
+    }
+    match e.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::SqliteFailure(error, _)) => matches!(
+            error.code,
+            ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked | ErrorCode::SystemIoFailure | ErrorCode::DiskFull
+        ),
+        _ => false,
+    }
+}
+
+/// Inserts the messages of a batch with the transaction of the batch
+fn audit_message(
+    conn: &Connection,
+    message: &IncomingMessage,
+    device_id: Option<&str>,
+) -> rusqlite::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO messages (topic, payload, received_at, device_id) VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![
+        message.topic,
+        message.payload,
+        timestamps::to_millis(message.arrived_at),
+        device_id
+    ])?;
+    Ok(())
+}
+
+/// Returns whether a temperature and humidity reading was stored
+fn handle_message(
+    payload: &Value,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+    config: &Config,
+    effects: &mut Effects,
+) -> rusqlite::Result<bool> {
+    let key_value_json = match payload.as_object() {
+        Some(key_value_json) => key_value_json,
+        None => {
+            println!("Payload is not a JSON object");
+            return Ok(false);
+        }
+    };
+
+    // Values of every metric in the message for the alert rules
+    let mut values = Vec::new();
+    let mut reading = None;
+
+    if key_value_json.contains_key("temperature") && key_value_json.contains_key("humidity") {
+        match temperature_and_humidity_sensor(key_value_json, device_id, arrived_at) {
+            Ok(sensor_data) => {
+                insert_sensor_data(conn, &sensor_data)?;
+                values.extend(alerts::reading_values(&sensor_data));
+                if let Some(mould_config) = &config.mould {
+                    if let Some(assessment) = mould::assess(conn, mould_config, device_id)? {
+                        values.extend(assessment.values());
+                    }
                 }
+                reading = Some(sensor_data);
             }
+            Err(e) => println!("Invalid temperature and humidity sensor data: {}", e),
+        }
+    }
 
-            Event::Incoming(event) => println!("Received = {:?}", event),
-            Event::Outgoing(_) => {}
+    if let Some(illuminance) = key_value_json.get("illuminance").and_then(Value::as_f64) {
+        let received_at = device_time(key_value_json, arrived_at).unwrap_or(arrived_at);
+        occupancy::record_illuminance(conn, device_id, illuminance, received_at)?;
+    }
+
+    if key_value_json.contains_key("power") || key_value_json.contains_key("energy") {
+        energy_report(key_value_json, conn, device_id, arrived_at)?;
+    }
+
+    if ["local_temperature", "current_heating_setpoint", "pi_heating_demand"]
+        .iter()
+        .any(|key| key_value_json.contains_key(*key))
+    {
+        thermostat_report(key_value_json, conn, device_id, arrived_at)?;
+    }
+
+    if ["co2", "voc", "pm25", "formaldehyd"]
+        .iter()
+        .any(|key| key_value_json.contains_key(*key))
+    {
+        let report = air_quality_report(key_value_json, conn, device_id, arrived_at)?;
+        values.extend(report.values(&config.air_quality));
+    }
+
+    if key_value_json.contains_key("battery") {
+        values.extend(battery_report(key_value_json, conn, device_id, arrived_at)?);
+    }
+
+    let received_at = device_time(key_value_json, arrived_at).unwrap_or(arrived_at);
+    for alert in alerts::evaluate(conn, &config.alert_rules, device_id, &values, received_at)? {
+        if let LiveEvent::Alert { rule, device_id, active, .. } = &alert {
+            println!("Alert {} for {} active: {}", rule, device_id, active);
         }
+        effects.events.push(alert);
+    }
+    let stored = reading.is_some();
+    if let Some(sensor_data) = reading {
+        effects.events.push(LiveEvent::Reading(sensor_data));
     }
+    Ok(stored)
 }
 
-fn audit_message(pool: &SqlitePool, topic: &str, payload_str: &str) {
-    let conn = get_conn(&pool);
-    match conn.execute(
-        "INSERT INTO messages (topic, payload) VALUES (?1, ?2)",
-        &[&topic, &payload_str],
-    ) {
-        Ok(_) => (),
-        Err(e) => println!("Failed to insert message into messages table: {:?}", e),
+/// Contact sensors are stored when they open or close, see `contact::record`
+fn contact_report(
+    payload: &Value,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+    effects: &mut Effects,
+) -> rusqlite::Result<()> {
+    let contact = match payload.get("contact").and_then(Value::as_bool) {
+        Some(contact) => contact,
+        None => return Ok(()),
+    };
+    let received_at = payload
+        .as_object()
+        .and_then(|json_object| device_time(json_object, arrived_at))
+        .unwrap_or(arrived_at);
+
+    if let Some(event) = contact::record(conn, device_id, contact, received_at)? {
+        println!("{} {}", device_id, if event.open { "opened" } else { "closed" });
+        effects.events.push(LiveEvent::Contact(event));
     }
+    Ok(())
 }
 
-fn handle_message(payload: &Value, pool: &&SqlitePool, topic: &str) {
-    if let Some(key_value_json) = payload.as_object() {
-        if key_value_json.contains_key("temperature") && key_value_json.contains_key("humidity") {
-            match temperature_and_humidity_sensor(key_value_json, pool, topic) {
-                Ok(..) => (),
-                Err(e) => println!(
-                    "Failed to insert temperature and humidity sensor data: {:?}",
-                    e
-                ),
-            }
+/// Water leak, smoke and tamper states that a payload reports
+fn critical_states(payload: &Value) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+    critical::KINDS
+        .into_iter()
+        .filter_map(|kind| payload.get(kind).and_then(Value::as_bool).map(|active| (kind, active)))
+}
+
+/// Water leak, smoke and tamper alarms are stored and sent when they start or end,
+/// see `critical::record`
+fn critical_report(
+    payload: &Value,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+    effects: &mut Effects,
+) -> rusqlite::Result<()> {
+    for kind in critical::KINDS {
+        let active = match payload.get(kind).and_then(Value::as_bool) {
+            Some(active) => active,
+            None => continue,
+        };
+        let received_at = payload
+            .as_object()
+            .and_then(|json_object| device_time(json_object, arrived_at))
+            .unwrap_or(arrived_at);
+
+        if let Some(alarm) = critical::record(conn, device_id, kind, active, received_at)? {
+            println!("{} {} {}", device_id, kind, if active { "alarm" } else { "cleared" });
+            effects.alarms.push(alarm.clone());
+            effects.events.push(LiveEvent::CriticalAlarm(alarm));
         }
-    } else {
-        println!("Payload is not a JSON object");
     }
+    Ok(())
+}
+
+/// Button presses are stored and run the triggers configured for them
+fn action_report<'a>(
+    payload: &Value,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+    config: &'a Config,
+    effects: &mut Effects<'a>,
+) -> rusqlite::Result<()> {
+    let event = match action_event(payload, device_id, arrived_at) {
+        Some(event) => event,
+        None => return Ok(()),
+    };
+    println!("{} action {}", event.device_id, event.action);
+
+    actions::record(conn, &event)?;
+    for trigger in actions::matching(&config.action_triggers, &event) {
+        effects.triggers.push((trigger, event.clone()));
+    }
+    effects.events.push(LiveEvent::Action(event));
+    Ok(())
+}
+
+fn action_event(payload: &Value, device_id: &str, arrived_at: DateTime<Utc>) -> Option<ActionEvent> {
+    // zigbee2mqtt can clear the action with an empty string after it
+    let action = payload
+        .get("action")
+        .and_then(Value::as_str)
+        .filter(|action| !action.is_empty())?;
+    Some(ActionEvent {
+        device_id: device_id.to_string(),
+        action: action.to_string(),
+        received_at: payload
+            .as_object()
+            .and_then(|json_object| device_time(json_object, arrived_at))
+            .unwrap_or(arrived_at),
+    })
+}
+
+/// Occupancy sensors start a period when the room is occupied and end it when it is empty,
+/// see `occupancy::record`
+fn occupancy_report(
+    payload: &Value,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    let occupied = match payload.get("occupancy").and_then(Value::as_bool) {
+        Some(occupied) => occupied,
+        None => return Ok(()),
+    };
+    let received_at = payload
+        .as_object()
+        .and_then(|json_object| device_time(json_object, arrived_at))
+        .unwrap_or(arrived_at);
+
+    if occupancy::record(conn, device_id, occupied, received_at)?.is_some() {
+        println!("{} {}", device_id, if occupied { "occupied" } else { "empty" });
+    }
+    Ok(())
 }
 
 fn temperature_and_humidity_sensor(
     json_object: &Map<String, Value>,
-    pool: &SqlitePool,
-    topic: &str,
-) -> Result<(), Box<dyn Error>> {
-    let conn = get_conn(&pool);
-
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+) -> Result<SensorData, &'static str> {
     let temperature = json_object
         .get("temperature")
         .and_then(Value::as_f64)
@@ -117,20 +775,132 @@ fn temperature_and_humidity_sensor(
         .and_then(Value::as_i64)
         .ok_or("Linkquality not found or not a valid i64")?;
 
-    let device_id = topic.split('/').last().ok_or("Invalid topic format")?;
+    Ok(SensorData {
+        temperature,
+        humidity: humidity as i32,
+        linkquality: linkquality as i32,
+        device_id: device_id.to_string(),
+        received_at: device_time(json_object, arrived_at).unwrap_or(arrived_at),
+        derived: DerivedMetrics::new(temperature as f64, humidity as f64),
+    })
+}
 
+fn insert_sensor_data(conn: &Connection, sensor_data: &SensorData) -> rusqlite::Result<()> {
     conn.execute(
-        "INSERT INTO sensor_data (temperature, humidity, linkquality, device_id) VALUES (?1, ?2, ?3, ?4)",
-        params![temperature, humidity, linkquality, device_id],
+        "INSERT INTO sensor_data (temperature, humidity, linkquality, device_id, received_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            sensor_data.temperature,
+            sensor_data.humidity,
+            sensor_data.linkquality,
+            sensor_data.device_id,
+            timestamps::to_millis(sensor_data.received_at)
+        ],
     )?;
-
     Ok(())
 }
 
+/// Power monitoring plug, the values that are missing are stored as NULL
+fn energy_report(
+    json_object: &Map<String, Value>,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    let value = |key| json_object.get(key).and_then(Value::as_f64);
+    let report = EnergyReport {
+        power: value("power"),
+        energy: value("energy"),
+        voltage: value("voltage"),
+        current: value("current"),
+    };
+    let received_at = device_time(json_object, arrived_at).unwrap_or(arrived_at);
+    energy::record(conn, device_id, &report, received_at)
+}
+
+/// Radiator valve, the values that are missing are stored as NULL
+fn thermostat_report(
+    json_object: &Map<String, Value>,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    let value = |key| json_object.get(key).and_then(Value::as_f64);
+    let report = ThermostatReport {
+        local_temperature: value("local_temperature"),
+        setpoint: value("current_heating_setpoint"),
+        heating_demand: value("pi_heating_demand"),
+    };
+    let received_at = device_time(json_object, arrived_at).unwrap_or(arrived_at);
+    thermostat::record(conn, device_id, &report, received_at)
+}
+
+fn air_quality_report(
+    json_object: &Map<String, Value>,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+) -> rusqlite::Result<AirQualityReport> {
+    let value = |key| json_object.get(key).and_then(Value::as_f64);
+    let report = AirQualityReport {
+        co2: value("co2"),
+        voc: value("voc"),
+        pm25: value("pm25"),
+        formaldehyd: value("formaldehyd"),
+    };
+    let received_at = device_time(json_object, arrived_at).unwrap_or(arrived_at);
+    air_quality::record(conn, device_id, &report, received_at)?;
+    Ok(report)
+}
+
+/// Battery level of a battery powered device, with the voltage if it's reported.
+/// Nothing is stored if the level is not a number.
+fn battery_report(
+    json_object: &Map<String, Value>,
+    conn: &Connection,
+    device_id: &str,
+    arrived_at: DateTime<Utc>,
+) -> rusqlite::Result<Vec<(Metric, f64)>> {
+    let battery = match json_object.get("battery").and_then(Value::as_f64) {
+        Some(battery) => battery,
+        None => {
+            println!("Battery of {} is not a valid f64", device_id);
+            return Ok(Vec::new());
+        }
+    };
+
+    let voltage = json_object
+        .get("voltage")
+        .and_then(|v| v.as_i64().or_else(|| v.as_f64().map(|f| f.round() as i64)));
+
+    let received_at = device_time(json_object, arrived_at).unwrap_or(arrived_at);
+    battery::record(conn, device_id, battery, voltage, received_at)?;
+
+    let mut values = vec![(Metric::Battery, battery)];
+    if let Some(voltage) = voltage {
+        values.push((Metric::Voltage, voltage as f64));
+    }
+    if let Some(days) = battery::days_remaining(conn, device_id)? {
+        values.push((Metric::BatteryDaysRemaining, days));
+    }
+    Ok(values)
+}
+
+/// The `last_seen` that zigbee2mqtt adds when `advanced.last_seen` is enabled,
+/// either ISO 8601 or milliseconds since the epoch.
+/// Times after the arrival of the message are ignored, the clock of the bridge is wrong.
+fn device_time(json_object: &Map<String, Value>, arrived_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let last_seen = match json_object.get("last_seen")? {
+        Value::Number(millis) => DateTime::from_timestamp_millis(millis.as_i64()?)?,
+        Value::String(time) => timestamps::parse(time)?,
+        _ => return None,
+    };
+    Some(last_seen.trunc_subsecs(3)).filter(|time| *time <= arrived_at)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::conn::get_test_pool;
+    use crate::conn::{get_conn, get_test_pool};
     use serde_json::json;
 
     #[test]
@@ -144,15 +914,14 @@ mod tests {
         .as_object()
         .unwrap()
         .clone();
-        let topic = "sensor/device123";
+        let device_id = "device123";
 
-        let result = temperature_and_humidity_sensor(&json_object, &pool, topic);
-        assert!(result.is_ok());
+        let sensor_data = temperature_and_humidity_sensor(&json_object, device_id, Utc::now()).unwrap();
+        insert_sensor_data(&get_conn(&pool), &sensor_data).unwrap();
     }
 
     #[test]
     fn test_temperature_and_humidity_sensor_missing_temperature() {
-        let pool = get_test_pool();
         let json_object = json!({
             "humidity": 60,
             "linkquality": 100
@@ -160,15 +929,14 @@ mod tests {
         .as_object()
         .unwrap()
         .clone();
-        let topic = "sensor/device123";
+        let device_id = "device123";
 
-        let result = temperature_and_humidity_sensor(&json_object, &pool, topic);
+        let result = temperature_and_humidity_sensor(&json_object, device_id, Utc::now());
         assert!(result.is_err());
     }
 
     #[test]
     fn test_temperature_and_humidity_sensor_invalid_humidity() {
-        let pool = get_test_pool();
         let json_object = json!({
             "temperature": 22.5,
             "humidity": "invalid",
@@ -177,15 +945,14 @@ mod tests {
         .as_object()
         .unwrap()
         .clone();
-        let topic = "sensor/device123";
+        let device_id = "device123";
 
-        let result = temperature_and_humidity_sensor(&json_object, &pool, topic);
+        let result = temperature_and_humidity_sensor(&json_object, device_id, Utc::now());
         assert!(result.is_err());
     }
 
     #[test]
     fn test_temperature_and_humidity_sensor_missing_linkquality() {
-        let pool = get_test_pool();
         let json_object = json!({
             "temperature": 22.5,
             "humidity": 60
@@ -193,33 +960,385 @@ mod tests {
         .as_object()
         .unwrap()
         .clone();
-        let topic = "sensor/device123";
+        let device_id = "device123";
 
-        let result = temperature_and_humidity_sensor(&json_object, &pool, topic);
+        let result = temperature_and_humidity_sensor(&json_object, device_id, Utc::now());
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_received_at_from_device_last_seen() {
+        let pool = get_test_pool();
+        let arrived_at = timestamps::parse("2024-07-01T12:00:00Z").unwrap();
+        let reading = |last_seen: Value| {
+            let json_object = json!({
+                "temperature": 22.5,
+                "humidity": 60,
+                "linkquality": 100,
+                "last_seen": last_seen
+            })
+            .as_object()
+            .unwrap()
+            .clone();
+            let sensor_data =
+                temperature_and_humidity_sensor(&json_object, "device123", arrived_at).unwrap();
+            insert_sensor_data(&get_conn(&pool), &sensor_data).unwrap();
+            sensor_data.received_at
+        };
+
+        let last_seen = timestamps::parse("2024-07-01T11:59:30.250Z").unwrap();
+        assert_eq!(reading(json!("2024-07-01T14:59:30.250+03:00")), last_seen);
+        assert_eq!(reading(json!(last_seen.timestamp_millis())), last_seen);
+        assert_eq!(reading(json!("2024-07-01T12:05:00Z")), arrived_at);
+        assert_eq!(reading(json!("yesterday")), arrived_at);
+
+        let stored: Vec<DateTime<Utc>> = get_conn(&pool)
+            .prepare("SELECT * FROM sensor_data ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok(SensorData::from_row(row)?.received_at))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(stored, vec![last_seen, last_seen, arrived_at, arrived_at]);
+    }
+
+    #[test]
+    fn test_battery_report() {
+        let pool = get_test_pool();
+        let json_object = json!({
+            "battery": 87,
+            "voltage": 2995,
+            "temperature": 22.5
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+
+        let values = battery_report(&json_object, &get_conn(&pool), "device123", Utc::now()).unwrap();
+        assert_eq!(values, vec![(Metric::Battery, 87.0), (Metric::Voltage, 2995.0)]);
+        let status = battery::latest(&pool, "device123").unwrap().unwrap();
+        assert_eq!(status.battery, 87.0);
+        assert_eq!(status.voltage, Some(2995));
+
+        let json_object = json!({ "battery": "low" }).as_object().unwrap().clone();
+        let values = battery_report(&json_object, &get_conn(&pool), "device123", Utc::now()).unwrap();
+        assert!(values.is_empty());
+    }
+
     #[test]
     fn test_audit_message() {
         let pool = get_test_pool();
         let topic = "sensor/device123";
         let payload_str = "{\"temperature\": 22.5, \"humidity\": 60, \"linkquality\": 100}";
 
-        audit_message(&pool, topic, payload_str);
+        let message = IncomingMessage {
+            topic: topic.to_string(),
+            payload: payload_str.to_string(),
+            arrived_at: Utc::now(),
+        };
+        audit_message(&get_conn(&pool), &message, Some("device123")).unwrap();
 
         let conn = get_conn(&pool);
         let mut stmt = conn
-            .prepare("SELECT topic, payload FROM messages WHERE topic = ?1")
+            .prepare("SELECT topic, payload, device_id FROM messages WHERE topic = ?1")
             .unwrap();
         let mut rows = stmt.query(params![topic]).unwrap();
 
         if let Some(row) = rows.next().unwrap() {
             let db_topic: String = row.get(0).unwrap();
             let db_payload: String = row.get(1).unwrap();
+            let db_device_id: String = row.get(2).unwrap();
             assert_eq!(db_topic, topic);
             assert_eq!(db_payload, payload_str);
+            assert_eq!(db_device_id, "device123");
         } else {
             panic!("No message found in the database");
         }
     }
+
+    #[test]
+    fn test_contact_report_broadcasts_changes() {
+        let pool = get_test_pool();
+        let mut effects = Effects::default();
+        let device_id = "front_door";
+
+        contact_report(&json!({"contact": false}), &get_conn(&pool), device_id, Utc::now(), &mut effects).unwrap();
+        contact_report(&json!({"contact": false}), &get_conn(&pool), device_id, Utc::now(), &mut effects).unwrap();
+        contact_report(&json!({"temperature": 22.5}), &get_conn(&pool), device_id, Utc::now(), &mut effects).unwrap();
+
+        match effects.events.as_slice() {
+            [LiveEvent::Contact(event)] => {
+                assert_eq!(event.device_id, "front_door");
+                assert!(event.open);
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+    }
+
+    fn test_config() -> Config {
+        serde_json::from_value(json!({
+            "username": "u",
+            "password": "p",
+            "mqtt_ip": "127.0.0.1",
+            "mqtt_port": 1883,
+            "mqtt_topics": [],
+            "sqlite_database": ":memory:",
+            "web_server_ip": "127.0.0.1",
+            "web_server_port": 8080
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_messages_are_spooled_until_the_database_is_available() {
+        let pool = get_test_pool();
+        let config = test_config();
+        let events = crate::events::channel();
+        let (client, _eventloop) = create_client(&config);
+        let path = std::env::temp_dir().join(format!("ru-berry-writer-{}.spool", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = Writer::new(&config, &pool, &events, &client, Spool::open(&path));
+
+        let start = Utc::now().trunc_subsecs(3) - chrono::Duration::hours(2);
+        let reading = |device_id: &str, minutes| IncomingMessage {
+            topic: format!("zigbee2mqtt/{}", device_id),
+            payload: r#"{"temperature": 21.5, "humidity": 50, "linkquality": 90}"#.to_string(),
+            arrived_at: start + chrono::Duration::minutes(minutes),
+        };
+        let count = |conn: &Connection, table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+                .unwrap()
+        };
+
+        // The writer times out waiting for the connection
+        let held = get_conn(&pool);
+        writer.write(&[reading("kitchen", 0), reading("kitchen", 10)]);
+        writer.write(&[reading("kitchen", 40)]);
+        assert_eq!(writer.spool.len(), 3);
+        assert!(!writer.replay_spool());
+        assert_eq!(count(&held, "sensor_data"), 0);
+        drop(held);
+
+        // Written without waiting for the next message
+        assert!(writer.replay_spool());
+        assert!(!path.exists());
+        writer.write(&[reading("bathroom", 60)]);
+        let conn = get_conn(&pool);
+        assert_eq!(count(&conn, "messages"), 4);
+        // The 30 minutes between readings only apply to live messages
+        assert_eq!(count(&conn, "sensor_data"), 4);
+        std::fs::remove_file(path.with_extension("spool.offset")).unwrap();
+    }
+
+    #[test]
+    fn test_messages_that_can_not_be_stored_are_dropped() {
+        let pool = get_test_pool();
+        let config = test_config();
+        let events = crate::events::channel();
+        let (client, _eventloop) = create_client(&config);
+        let path = std::env::temp_dir().join(format!("ru-berry-dropped-{}.spool", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = Writer::new(&config, &pool, &events, &client, Spool::open(&path));
+
+        let start = Utc::now().trunc_subsecs(3) - chrono::Duration::hours(2);
+        let message = |device_id: &str, payload: Value, minutes| IncomingMessage {
+            topic: format!("zigbee2mqtt/{}", device_id),
+            payload: payload.to_string(),
+            arrived_at: start + chrono::Duration::minutes(minutes),
+        };
+        let reading = |minutes| {
+            let payload = json!({ "contact": false, "temperature": 21.5, "humidity": 50, "linkquality": 90 });
+            message("window", payload, minutes)
+        };
+        let count = |table: &str| -> i64 {
+            get_conn(&pool)
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+                .unwrap()
+        };
+
+        get_conn(&pool)
+            .execute_batch("ALTER TABLE sensor_data RENAME TO sensor_data_broken")
+            .unwrap();
+        writer.write(&[reading(0), message("door", json!({ "contact": true }), 1)]);
+        // The reading can't be stored, the message and the contact event are rolled back with it,
+        // the door in the same batch is stored and nothing is spooled
+        assert!(writer.spool.is_empty());
+        assert_eq!(count("messages"), 1);
+        assert_eq!(count("contact_events"), 1);
+
+        get_conn(&pool)
+            .execute_batch("ALTER TABLE sensor_data_broken RENAME TO sensor_data")
+            .unwrap();
+        writer.write(&[reading(10)]);
+        assert_eq!(count("messages"), 2);
+        assert_eq!(count("contact_events"), 2);
+        assert_eq!(count("sensor_data"), 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_alarms_and_triggers_of_spooled_messages_are_sent_once() {
+        let pool = get_test_pool();
+        let mut config = test_config();
+        config.action_triggers = serde_json::from_value(json!([{
+            "device_id": "hall_remote",
+            "action": "single",
+            "mqtt": { "topic": "zigbee2mqtt/hall_light/set", "payload": { "state": "TOGGLE" } }
+        }]))
+        .unwrap();
+        config.critical_alarms =
+            serde_json::from_value(json!({ "notify": [{ "mqtt": { "topic": "alarms/critical" } }] })).unwrap();
+        let events = crate::events::channel();
+        let (client, mut eventloop) = create_client(&config);
+        let path = std::env::temp_dir().join(format!("ru-berry-actions-{}.spool", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = Writer::new(&config, &pool, &events, &client, Spool::open(&path));
+
+        let message = |device_id: &str, payload: Value, minutes| IncomingMessage {
+            topic: format!("zigbee2mqtt/{}", device_id),
+            payload: payload.to_string(),
+            arrived_at: Utc::now().trunc_subsecs(3) - chrono::Duration::minutes(minutes),
+        };
+        let mut published = || {
+            eventloop.clean();
+            eventloop.pending.drain(..).count()
+        };
+        let held = get_conn(&pool);
+        writer.write(&[message("hall_remote", json!({"action": "single"}), 30)]);
+        writer.write(&[message("kitchen_leak", json!({"water_leak": true}), 20)]);
+        writer.write(&[message("kitchen_leak", json!({"water_leak": true}), 10)]);
+        // Sent while the database is unavailable, the leak only once
+        assert_eq!(published(), 2);
+
+        drop(held);
+        writer.write(&[message("hall_remote", json!({"action": "single"}), 0)]);
+        assert!(writer.spool.is_empty());
+        assert_eq!(actions::events(&pool, Some("hall_remote"), "-1 day").unwrap().len(), 2);
+        assert_eq!(critical::pending(&pool).unwrap().len(), 1);
+        // Only the live press toggles the light, the spooled ones are not sent again
+        assert_eq!(published(), 1);
+        std::fs::remove_file(path.with_extension("spool.offset")).unwrap();
+    }
+
+    #[test]
+    fn test_rejected_spike_does_not_skip_the_next_reading() {
+        let pool = get_test_pool();
+        let mut config = test_config();
+        config.plausibility_rules =
+            vec![serde_json::from_value(json!({ "metric": "temperature", "min": -30 })).unwrap()];
+        let events = crate::events::channel();
+        let (client, _eventloop) = create_client(&config);
+        let path = std::env::temp_dir().join(format!("ru-berry-spike-{}.spool", std::process::id()));
+        let mut writer = Writer::new(&config, &pool, &events, &client, Spool::open(&path));
+
+        let start = Utc::now().trunc_subsecs(3) - chrono::Duration::hours(1);
+        let reading = |temperature: f64, minutes| IncomingMessage {
+            topic: "zigbee2mqtt/garden".to_string(),
+            payload: json!({ "temperature": temperature, "humidity": 50, "linkquality": 90 }).to_string(),
+            arrived_at: start + chrono::Duration::minutes(minutes),
+        };
+        writer.write(&[reading(-40.0, 0), reading(18.5, 5), reading(18.0, 10)]);
+
+        let temperatures: Vec<f64> = get_conn(&pool)
+            .prepare("SELECT temperature FROM sensor_data WHERE device_id = 'garden'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(temperatures, vec![18.5]);
+        assert_eq!(plausibility::rejected(&pool, Some("garden"), "-1 day").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_occupancy_illuminance_power_and_heating() {
+        let pool = get_test_pool();
+        let config = test_config();
+        let device_id = "hall";
+        let payload = json!({
+            "occupancy": true,
+            "illuminance": 120,
+            "battery": 90,
+            "power": 5.5,
+            "current_heating_setpoint": 21
+        });
+
+        occupancy_report(&payload, &get_conn(&pool), device_id, Utc::now()).unwrap();
+        handle_message(&payload, &get_conn(&pool), device_id, Utc::now(), &config, &mut Effects::default()).unwrap();
+
+        let periods = occupancy::periods(&pool, "hall", "-1 day").unwrap();
+        assert_eq!(periods.len(), 1);
+        let illuminance = occupancy::illuminance(&pool, "hall", "-1 day").unwrap();
+        assert_eq!(illuminance[0].1, 120.0);
+        let (report, _) = energy::latest(&pool, "hall").unwrap().unwrap();
+        assert_eq!(report.power, Some(5.5));
+        assert_eq!(report.energy, None);
+        let (_, report) = thermostat::latest(&pool, "hall").unwrap().unwrap();
+        assert_eq!(report.setpoint, Some(21.0));
+    }
+
+    #[test]
+    fn test_air_quality_alert() {
+        let pool = get_test_pool();
+        let mut config = test_config();
+        config.alert_rules = serde_json::from_value(json!([
+            { "name": "Ventilate", "metric": "ventilation_needed", "max": 0 }
+        ]))
+        .unwrap();
+        let device_id = "bedroom";
+
+        handle_message(&json!({"co2": 1600, "pm25": 3}), &get_conn(&pool), device_id, Utc::now(), &config, &mut Effects::default()).unwrap();
+
+        let (_, report) = air_quality::latest(&pool, "bedroom").unwrap().unwrap();
+        assert_eq!(report.co2, Some(1600.0));
+        assert_eq!(report.voc, None);
+        let alerts = alerts::active_alerts(&pool).unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule, "Ventilate");
+    }
+
+    #[test]
+    fn test_critical_report() {
+        let pool = get_test_pool();
+        let config = test_config();
+        let (client, _eventloop) = create_client(&config);
+        let events = crate::events::channel();
+        let mut receiver = events.subscribe();
+        let device_id = "kitchen_leak";
+
+        let mut effects = Effects::default();
+        critical_report(&json!({"water_leak": true, "tamper": false}), &get_conn(&pool), device_id, Utc::now(), &mut effects).unwrap();
+        critical_report(&json!({"water_leak": true, "battery": 90}), &get_conn(&pool), device_id, Utc::now(), &mut effects).unwrap();
+        assert_eq!(effects.alarms.len(), 1);
+        effects.send(&config, &client, &events, false);
+
+        match receiver.try_recv().unwrap() {
+            LiveEvent::CriticalAlarm(alarm) => assert_eq!(alarm.kind, "water_leak"),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(critical::pending(&pool).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_action_report() {
+        let pool = get_test_pool();
+        let config = test_config();
+        let (client, _eventloop) = create_client(&config);
+        let events = crate::events::channel();
+        let mut receiver = events.subscribe();
+        let device_id = "hall_remote";
+
+        let mut effects = Effects::default();
+        action_report(&json!({"action": "double"}), &get_conn(&pool), device_id, Utc::now(), &config, &mut effects).unwrap();
+        action_report(&json!({"action": ""}), &get_conn(&pool), device_id, Utc::now(), &config, &mut effects).unwrap();
+        effects.send(&config, &client, &events, false);
+
+        match receiver.try_recv().unwrap() {
+            LiveEvent::Action(event) => assert_eq!(event.action, "double"),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(actions::events(&pool, Some("hall_remote"), "-1 day").unwrap().len(), 1);
+    }
 }
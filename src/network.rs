@@ -0,0 +1,259 @@
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use chrono::{DateTime, Duration, Utc};
+use rumqttc::{AsyncClient, ClientError, QoS};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Days in each of the two periods whose average link quality is compared
+const TREND_DAYS: i64 = 7;
+/// Drop in the average link quality that counts as deteriorating
+const DETERIORATION: f64 = 0.2;
+
+/// Raw network map of zigbee2mqtt
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkMap {
+    pub(crate) nodes: Vec<Node>,
+    pub(crate) links: Vec<Link>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Node {
+    pub(crate) ieee_addr: String,
+    pub(crate) friendly_name: String,
+    /// `Coordinator`, `Router` or `EndDevice`
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+}
+
+/// `target` has `source` in its neighbour table
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Link {
+    pub(crate) source_ieee_addr: String,
+    pub(crate) target_ieee_addr: String,
+    #[serde(default)]
+    pub(crate) linkquality: i64,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    status: String,
+    #[serde(default)]
+    data: serde_json::Value,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseData {
+    value: NetworkMap,
+}
+
+/// Link quality of a device over the last two weeks
+#[derive(Serialize, Debug)]
+pub struct LinkqualityTrend {
+    pub(crate) device_id: String,
+    pub(crate) latest: i64,
+    /// Average of the last 7 days
+    pub(crate) recent_average: Option<f64>,
+    /// Average of the 7 days before that
+    pub(crate) previous_average: Option<f64>,
+    /// Recent average at least 20 % below the previous one
+    pub(crate) deteriorating: bool,
+}
+
+pub fn request_topic(base_topic: &str) -> String {
+    format!("{}/bridge/request/networkmap", base_topic)
+}
+
+pub fn response_topic(base_topic: &str) -> String {
+    format!("{}/bridge/response/networkmap", base_topic)
+}
+
+/// Asks zigbee2mqtt to scan the network, the map arrives on `response_topic` after a while.
+/// Fails instead of waiting when the MQTT client is not keeping up.
+pub fn request_map(client: &AsyncClient, base_topic: &str) -> Result<(), ClientError> {
+    client.try_publish(
+        request_topic(base_topic),
+        QoS::AtMostOnce,
+        false,
+        r#"{"type": "raw", "routes": false}"#,
+    )
+}
+
+pub fn parse_response(payload: &str) -> Result<NetworkMap, String> {
+    let response: Response =
+        serde_json::from_str(payload).map_err(|e| format!("Invalid network map: {}", e))?;
+    if response.status != "ok" {
+        return Err(format!(
+            "Network map request failed: {}",
+            response.error.unwrap_or(response.status)
+        ));
+    }
+    serde_json::from_value::<ResponseData>(response.data)
+        .map(|data| data.value)
+        .map_err(|e| format!("Invalid network map: {}", e))
+}
+
+/// Only the latest map is kept. The caller runs this in a transaction with the message.
+pub fn store(
+    conn: &Connection,
+    map: &NetworkMap,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    let map = serde_json::to_string(map).expect("Failed to serialize network map");
+    conn.execute("DELETE FROM network_map", [])?;
+    conn.execute(
+        "INSERT INTO network_map (map, received_at) VALUES (?1, ?2)",
+        params![map, timestamps::to_millis(received_at)],
+    )?;
+    Ok(())
+}
+
+pub fn latest(pool: &SqlitePool) -> rusqlite::Result<Option<(NetworkMap, DateTime<Utc>)>> {
+    let stored = get_conn(pool)
+        .query_row(
+            "SELECT map, received_at FROM network_map ORDER BY id DESC LIMIT 1",
+            [],
+            |row| Ok((row.get::<_, String>(0)?, timestamps::get(row, 1)?)),
+        )
+        .optional()?;
+
+    Ok(
+        stored.and_then(|(map, received_at)| match serde_json::from_str(&map) {
+            Ok(map) => Some((map, received_at)),
+            Err(e) => {
+                println!("Failed to read the stored network map: {:?}", e);
+                None
+            }
+        }),
+    )
+}
+
+/// Link quality by device, oldest first
+pub type LinkqualityHistory = BTreeMap<String, Vec<(DateTime<Utc>, i64)>>;
+
+/// Link quality of every device in the last two weeks
+pub fn linkquality_history(pool: &SqlitePool) -> rusqlite::Result<LinkqualityHistory> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT device_id, received_at, linkquality FROM sensor_data
+        WHERE received_at >= unixepoch('now', ?1) * 1000
+        ORDER BY received_at",
+    )?;
+    let mut rows = stmt.query([format!("-{} days", TREND_DAYS * 2)])?;
+
+    let mut history = LinkqualityHistory::new();
+    while let Some(row) = rows.next()? {
+        history
+            .entry(row.get(0)?)
+            .or_default()
+            .push((timestamps::get(row, 1)?, row.get(2)?));
+    }
+    Ok(history)
+}
+
+impl LinkqualityTrend {
+    /// None without any history
+    pub fn new(
+        device_id: &str,
+        history: &[(DateTime<Utc>, i64)],
+        now: DateTime<Utc>,
+    ) -> Option<LinkqualityTrend> {
+        let latest = history.last()?.1;
+        let split = now - Duration::days(TREND_DAYS);
+        let average = |values: Vec<i64>| {
+            (!values.is_empty()).then(|| values.iter().sum::<i64>() as f64 / values.len() as f64)
+        };
+        let recent_average = average(
+            history
+                .iter()
+                .filter(|(time, _)| *time >= split)
+                .map(|(_, lq)| *lq)
+                .collect(),
+        );
+        let previous_average = average(
+            history
+                .iter()
+                .filter(|(time, _)| *time < split)
+                .map(|(_, lq)| *lq)
+                .collect(),
+        );
+
+        Some(LinkqualityTrend {
+            device_id: device_id.to_string(),
+            latest,
+            recent_average,
+            previous_average,
+            deteriorating: match (recent_average, previous_average) {
+                (Some(recent), Some(previous)) => recent < previous * (1.0 - DETERIORATION),
+                _ => false,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+
+    #[test]
+    fn test_deteriorating_link_quality() {
+        let now = Utc::now();
+        let days_ago = |days| now - Duration::days(days);
+
+        let trend =
+            LinkqualityTrend::new("kitchen", &[(days_ago(10), 120), (days_ago(2), 80)], now)
+                .unwrap();
+        assert_eq!(trend.latest, 80);
+        assert_eq!(trend.previous_average, Some(120.0));
+        assert!(trend.deteriorating);
+
+        let trend =
+            LinkqualityTrend::new("kitchen", &[(days_ago(10), 120), (days_ago(2), 110)], now)
+                .unwrap();
+        assert!(!trend.deteriorating);
+
+        let trend = LinkqualityTrend::new("kitchen", &[(days_ago(2), 10)], now).unwrap();
+        assert_eq!(trend.previous_average, None);
+        assert!(!trend.deteriorating);
+        assert!(LinkqualityTrend::new("kitchen", &[], now).is_none());
+    }
+
+    #[test]
+    fn test_network_map_response() {
+        let payload = r#"{
+            "data": {
+                "routes": false,
+                "type": "raw",
+                "value": {
+                    "nodes": [
+                        {"ieeeAddr": "0x00", "friendlyName": "Coordinator", "type": "Coordinator", "networkAddress": 0},
+                        {"ieeeAddr": "0x01", "friendlyName": "kitchen", "type": "EndDevice", "networkAddress": 1}
+                    ],
+                    "links": [
+                        {"sourceIeeeAddr": "0x01", "targetIeeeAddr": "0x00", "linkquality": 120, "lqi": 120, "depth": 1}
+                    ]
+                }
+            },
+            "status": "ok"
+        }"#;
+        let map = parse_response(payload).unwrap();
+        assert_eq!(map.nodes[1].friendly_name, "kitchen");
+        assert_eq!(map.links[0].linkquality, 120);
+
+        let pool = get_test_pool();
+        assert!(latest(&pool).unwrap().is_none());
+        store(&get_conn(&pool), &map, Utc::now()).unwrap();
+        store(&get_conn(&pool), &map, Utc::now()).unwrap();
+        let (stored, _) = latest(&pool).unwrap().unwrap();
+        assert_eq!(stored.nodes.len(), 2);
+
+        let error = parse_response(r#"{"data": {}, "status": "error", "error": "Timeout"}"#);
+        assert_eq!(error.unwrap_err(), "Network map request failed: Timeout");
+    }
+}
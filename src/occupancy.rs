@@ -0,0 +1,150 @@
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+/// A room was occupied from `started_at` until `ended_at`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct OccupancyPeriod {
+    pub(crate) device_id: String,
+    pub(crate) started_at: DateTime<Utc>,
+    /// None while the room is still occupied
+    pub(crate) ended_at: Option<DateTime<Utc>>,
+}
+
+impl OccupancyPeriod {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let ended_at: Option<i64> = row.get(2)?;
+        Ok(OccupancyPeriod {
+            device_id: row.get(0)?,
+            started_at: timestamps::get(row, 1)?,
+            ended_at: ended_at.and_then(DateTime::from_timestamp_millis),
+        })
+    }
+}
+
+fn current(conn: &Connection, device_id: &str) -> rusqlite::Result<Option<i64>> {
+    conn.query_row(
+        "SELECT id FROM occupancy_periods WHERE device_id = ?1 AND ended_at IS NULL
+        ORDER BY started_at DESC LIMIT 1",
+        [device_id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Starts a period when the room becomes occupied and ends it when the room is empty again.
+/// Returns the period if it started or ended.
+pub fn record(
+    conn: &Connection,
+    device_id: &str,
+    occupied: bool,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<Option<OccupancyPeriod>> {
+    let millis = timestamps::to_millis(received_at);
+    match (current(conn, device_id)?, occupied) {
+        (None, true) => {
+            conn.execute(
+                "INSERT INTO occupancy_periods (device_id, started_at) VALUES (?1, ?2)",
+                params![device_id, millis],
+            )?;
+        }
+        (Some(id), false) => {
+            conn.execute(
+                "UPDATE occupancy_periods SET ended_at = MAX(started_at, ?1) WHERE id = ?2",
+                params![millis, id],
+            )?;
+        }
+        _ => return Ok(None),
+    }
+
+    conn.query_row(
+        "SELECT device_id, started_at, ended_at FROM occupancy_periods
+        WHERE device_id = ?1 ORDER BY started_at DESC, id DESC LIMIT 1",
+        [device_id],
+        OccupancyPeriod::from_row,
+    )
+    .optional()
+}
+
+/// Periods of a device that were ongoing after `modifier`, e.g. `-7 days`, oldest first
+pub fn periods(
+    pool: &SqlitePool,
+    device_id: &str,
+    modifier: &str,
+) -> rusqlite::Result<Vec<OccupancyPeriod>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT device_id, started_at, ended_at FROM occupancy_periods
+        WHERE device_id = ?1 AND (ended_at IS NULL OR ended_at >= unixepoch('now', ?2) * 1000)
+        ORDER BY started_at",
+    )?;
+    let periods = stmt
+        .query_map(params![device_id, modifier], OccupancyPeriod::from_row)?
+        .collect();
+    periods
+}
+
+pub fn record_illuminance(
+    conn: &Connection,
+    device_id: &str,
+    illuminance: f64,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO illuminance_data (device_id, illuminance, received_at) VALUES (?1, ?2, ?3)",
+        params![device_id, illuminance, timestamps::to_millis(received_at)],
+    )?;
+    Ok(())
+}
+
+/// Illuminance of a device since `modifier`, e.g. `-7 days`, oldest first
+pub fn illuminance(
+    pool: &SqlitePool,
+    device_id: &str,
+    modifier: &str,
+) -> rusqlite::Result<Vec<(DateTime<Utc>, f64)>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT received_at, illuminance FROM illuminance_data
+        WHERE device_id = ?1 AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at",
+    )?;
+    let illuminance = stmt
+        .query_map(params![device_id, modifier], |row| {
+            Ok((timestamps::get(row, 0)?, row.get(1)?))
+        })?
+        .collect();
+    illuminance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+    use chrono::SubsecRound;
+
+    #[test]
+    fn test_periods_start_and_end() {
+        let pool = get_test_pool();
+        let now = Utc::now().trunc_subsecs(3);
+        let minutes_ago = |minutes| now - chrono::Duration::minutes(minutes);
+
+        // Empty rooms don't end anything
+        assert!(record(&get_conn(&pool), "hall", false, minutes_ago(60)).unwrap().is_none());
+        let started = record(&get_conn(&pool), "hall", true, minutes_ago(50)).unwrap().unwrap();
+        assert_eq!(started.ended_at, None);
+        assert!(record(&get_conn(&pool), "hall", true, minutes_ago(45)).unwrap().is_none());
+        let ended = record(&get_conn(&pool), "hall", false, minutes_ago(40)).unwrap().unwrap();
+        assert_eq!(ended.started_at, minutes_ago(50));
+        assert_eq!(ended.ended_at, Some(minutes_ago(40)));
+        record(&get_conn(&pool), "hall", true, minutes_ago(10)).unwrap();
+
+        let day = periods(&pool, "hall", "-1 day").unwrap();
+        assert_eq!(day.len(), 2);
+        assert_eq!(day[1].ended_at, None);
+        // Only the ongoing period
+        assert_eq!(periods(&pool, "hall", "-5 minutes").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::alerts::Metric;
+use crate::config::PlausibilityRule;
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+
+/// A value that a plausibility rule rejected, it is stored here instead of with the readings
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RejectedReading {
+    pub(crate) device_id: String,
+    pub(crate) metric: Metric,
+    pub(crate) value: f64,
+    /// e.g. `above 60`
+    pub(crate) reason: String,
+    pub(crate) received_at: DateTime<Utc>,
+}
+
+impl RejectedReading {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let metric: String = row.get(1)?;
+        Ok(RejectedReading {
+            device_id: row.get(0)?,
+            metric: Metric::from_db(&metric).ok_or_else(|| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    1,
+                    rusqlite::types::Type::Text,
+                    format!("Unknown metric {}", metric).into(),
+                )
+            })?,
+            value: row.get(2)?,
+            reason: row.get(3)?,
+            received_at: timestamps::get(row, 4)?,
+        })
+    }
+}
+
+#[derive(Default)]
+struct History {
+    /// Value and time of the last value that was accepted, for the rate of change
+    last_accepted: Option<(DateTime<Utc>, f64)>,
+    /// Latest values including the rejected ones, so that the median follows a lasting change
+    recent: VecDeque<f64>,
+}
+
+fn median(values: &VecDeque<f64>) -> Option<f64> {
+    let mut sorted: Vec<f64> = values.iter().copied().collect();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((sorted[middle - 1] + sorted[middle]) / 2.0),
+        _ => Some(sorted[middle]),
+    }
+}
+
+/// Checks the values of the messages against the plausibility rules.
+/// The history for the rate of change and the spikes starts empty after a restart.
+#[derive(Default)]
+pub struct PlausibilityFilter {
+    history: HashMap<(String, &'static str), History>,
+}
+
+impl PlausibilityFilter {
+    /// Why the value is implausible, None if every rule accepts it
+    pub fn check(
+        &mut self,
+        rules: &[PlausibilityRule],
+        device_id: &str,
+        metric: Metric,
+        value: f64,
+        received_at: DateTime<Utc>,
+    ) -> Option<String> {
+        let rules: Vec<&PlausibilityRule> = rules
+            .iter()
+            .filter(|rule| rule.metric == metric)
+            .filter(|rule| rule.device_id.as_deref().is_none_or(|id| id == device_id))
+            .collect();
+        if rules.is_empty() {
+            return None;
+        }
+
+        let history = self
+            .history
+            .entry((device_id.to_string(), metric.as_str()))
+            .or_default();
+        let mut reason = None;
+        for rule in &rules {
+            if let Some(min) = rule.min.filter(|min| value < *min) {
+                reason = Some(format!("below {}", min));
+            } else if let Some(max) = rule.max.filter(|max| value > *max) {
+                reason = Some(format!("above {}", max));
+            } else if let (Some(max_change), Some((at, last))) =
+                (rule.max_change_per_minute, history.last_accepted)
+            {
+                // At least a minute, so that messages right after each other can change by `max_change`
+                let minutes = ((received_at - at).num_seconds() as f64 / 60.0).max(1.0);
+                let change = (value - last).abs() / minutes;
+                if change > max_change {
+                    reason = Some(format!("changed by {:.2} per minute", change));
+                }
+            }
+            if reason.is_none() {
+                if let Some(max_spike) = rule.max_spike {
+                    let window = rule.spike_window.max(1);
+                    let recent: VecDeque<f64> =
+                        history.recent.iter().rev().take(window).copied().collect();
+                    if recent.len() >= window {
+                        let median = median(&recent).unwrap_or(value);
+                        if (value - median).abs() > max_spike {
+                            reason = Some(format!("spike from median {}", median));
+                        }
+                    }
+                }
+            }
+            if reason.is_some() {
+                break;
+            }
+        }
+
+        let window = rules
+            .iter()
+            .map(|rule| rule.spike_window.max(1))
+            .max()
+            .unwrap_or(1);
+        history.recent.push_back(value);
+        while history.recent.len() > window {
+            history.recent.pop_front();
+        }
+        if reason.is_none() {
+            history.last_accepted = Some((received_at, value));
+        }
+        reason
+    }
+
+    /// Removes the implausible values from the payload so that they aren't stored as readings.
+    /// Returns the rejected values.
+    pub fn filter(
+        &mut self,
+        rules: &[PlausibilityRule],
+        payload: &mut Value,
+        device_id: &str,
+        received_at: DateTime<Utc>,
+    ) -> Vec<RejectedReading> {
+        let Some(json_object) = payload.as_object_mut() else {
+            return Vec::new();
+        };
+        let mut rejected = Vec::new();
+        for metric in Metric::ALL {
+            let Some(value) = json_object.get(metric.as_str()).and_then(Value::as_f64) else {
+                continue;
+            };
+            if let Some(reason) = self.check(rules, device_id, metric, value, received_at) {
+                json_object.remove(metric.as_str());
+                rejected.push(RejectedReading {
+                    device_id: device_id.to_string(),
+                    metric,
+                    value,
+                    reason,
+                    received_at,
+                });
+            }
+        }
+        rejected
+    }
+}
+
+pub fn record(conn: &Connection, rejected: &RejectedReading) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO rejected_readings (device_id, metric, value, reason, received_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            rejected.device_id,
+            rejected.metric.as_str(),
+            rejected.value,
+            rejected.reason,
+            timestamps::to_millis(rejected.received_at)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Rejected values since the SQLite datetime `modifier`, latest first
+pub fn rejected(
+    pool: &SqlitePool,
+    device_id: Option<&str>,
+    modifier: &str,
+) -> rusqlite::Result<Vec<RejectedReading>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT device_id, metric, value, reason, received_at FROM rejected_readings
+        WHERE (?1 IS NULL OR device_id = ?1) AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at DESC, id DESC",
+    )?;
+    let rejected = stmt
+        .query_map(params![device_id, modifier], RejectedReading::from_row)?
+        .collect();
+    rejected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+    use chrono::{Duration, SubsecRound};
+    use serde_json::json;
+
+    fn rule(value: Value) -> PlausibilityRule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_range_and_rate_of_change() {
+        let rules = vec![
+            rule(
+                json!({ "metric": "temperature", "min": -30, "max": 60, "max_change_per_minute": 0.5 }),
+            ),
+            rule(json!({ "metric": "humidity", "device_id": "bathroom", "min": 1 })),
+        ];
+        let mut filter = PlausibilityFilter::default();
+        let start = Utc::now();
+        let at = |minutes| start + Duration::minutes(minutes);
+
+        let mut check = |device_id, metric, value, minutes| {
+            filter.check(&rules, device_id, metric, value, at(minutes))
+        };
+        assert_eq!(check("bathroom", Metric::Temperature, 21.0, 0), None);
+        assert_eq!(
+            check("bathroom", Metric::Temperature, -40.0, 1),
+            Some("below -30".to_string())
+        );
+        // 30 minutes after the last accepted value
+        assert_eq!(check("bathroom", Metric::Temperature, 25.0, 30), None);
+        assert_eq!(
+            check("bathroom", Metric::Temperature, 35.0, 40),
+            Some("changed by 1.00 per minute".to_string())
+        );
+        assert!(check("bathroom", Metric::Humidity, 0.0, 40).is_some());
+        assert_eq!(check("kitchen", Metric::Humidity, 0.0, 40), None);
+        assert_eq!(check("kitchen", Metric::Linkquality, 0.0, 40), None);
+    }
+
+    #[test]
+    fn test_spikes_are_rejected_until_the_change_lasts() {
+        let rules = vec![rule(
+            json!({ "metric": "humidity", "max_spike": 15, "spike_window": 3 }),
+        )];
+        let mut filter = PlausibilityFilter::default();
+        let now = Utc::now();
+        let mut check = |value| filter.check(&rules, "bedroom", Metric::Humidity, value, now);
+
+        for value in [50.0, 52.0, 51.0] {
+            assert_eq!(check(value), None);
+        }
+        assert_eq!(check(95.0), Some("spike from median 51".to_string()));
+        assert_eq!(check(52.0), None);
+        assert!(check(80.0).is_some());
+        // Median of 95, 52 and 80
+        assert_eq!(check(80.0), None);
+    }
+
+    #[test]
+    fn test_rejected_values_are_removed_and_stored() {
+        let pool = get_test_pool();
+        let rules = vec![rule(json!({ "metric": "temperature", "min": -30 }))];
+        let mut filter = PlausibilityFilter::default();
+        let received_at = Utc::now().trunc_subsecs(3);
+
+        let mut payload = json!({ "temperature": -40, "humidity": 0, "linkquality": 90 });
+        let values = filter.filter(&rules, &mut payload, "garden", received_at);
+        assert_eq!(payload, json!({ "humidity": 0, "linkquality": 90 }));
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].reason, "below -30");
+
+        record(&get_conn(&pool), &values[0]).unwrap();
+        assert_eq!(rejected(&pool, Some("garden"), "-1 day").unwrap(), values);
+        assert!(rejected(&pool, Some("kitchen"), "-1 day")
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+use crate::ingest::IncomingMessage;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// Messages kept in memory when the file can't be written either, newer ones are dropped
+const MAX_MEMORY_MESSAGES: usize = 10_000;
+
+/// Messages that couldn't be written to the database, one JSON object per line.
+/// They stay in the file until they are written, also across restarts.
+/// Written messages are not removed from the file, the file is removed once every message is written.
+pub struct Spool {
+    path: PathBuf,
+    /// Where the messages that are not written yet start in the file, kept in `<path>.offset`
+    offset: u64,
+    /// Messages that couldn't be written to the file either, e.g. when the disk is full.
+    /// At most `MAX_MEMORY_MESSAGES`, so that a full disk doesn't use up the memory as well.
+    memory: Vec<IncomingMessage>,
+    len: usize,
+}
+
+/// The oldest spooled messages, see `Spool::next`
+pub struct SpooledBatch {
+    pub messages: Vec<IncomingMessage>,
+    /// Where the lines that were read end in the file
+    end: u64,
+    lines: usize,
+    from_memory: usize,
+}
+
+impl SpooledBatch {
+    pub fn is_empty(&self) -> bool {
+        self.lines == 0 && self.from_memory == 0
+    }
+}
+
+impl Spool {
+    pub fn open(path: impl Into<PathBuf>) -> Spool {
+        let mut spool = Spool {
+            path: path.into(),
+            offset: 0,
+            memory: Vec::new(),
+            len: 0,
+        };
+        let offset = fs::read_to_string(spool.offset_path())
+            .ok()
+            .and_then(|offset| offset.trim().parse().ok())
+            .unwrap_or(0);
+        match fs::metadata(&spool.path) {
+            Ok(metadata) if metadata.len() >= offset => spool.offset = offset,
+            // The spool was removed before its offset was reset
+            _ if offset > 0 => spool.reset_offset(),
+            _ => {}
+        }
+        spool.len = spool.next(usize::MAX).lines;
+        if spool.len > 0 {
+            println!(
+                "{} messages are spooled in {}",
+                spool.len,
+                spool.path.display()
+            );
+        }
+        spool
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn offset_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".offset");
+        path.into()
+    }
+
+    /// Up to `max` of the oldest spooled messages, read from the offset on.
+    /// Lines that can't be parsed are skipped.
+    pub fn next(&self, max: usize) -> SpooledBatch {
+        let mut batch = SpooledBatch {
+            messages: Vec::new(),
+            end: self.offset,
+            lines: 0,
+            from_memory: 0,
+        };
+        match self.read_lines(max, &mut batch) {
+            Ok(()) => {
+                // The messages in memory are newer than the ones in the file
+                batch.from_memory = (max - batch.messages.len()).min(self.memory.len());
+                batch
+                    .messages
+                    .extend(self.memory[..batch.from_memory].iter().cloned());
+            }
+            Err(e) => println!("Failed to read {}: {:?}", self.path.display(), e),
+        }
+        batch
+    }
+
+    fn read_lines(&self, max: usize, batch: &mut SpooledBatch) -> std::io::Result<()> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        while batch.messages.len() < max {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            batch.end += read as u64;
+            batch.lines += 1;
+            match serde_json::from_str::<IncomingMessage>(line.trim_end()) {
+                Ok(message) => batch.messages.push(message),
+                Err(e) => println!("Skipping spooled message: {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn append(&mut self, messages: &[IncomingMessage]) {
+        if messages.is_empty() {
+            return;
+        }
+        // Messages must stay in order, so nothing goes to the file while some wait in memory
+        if self.memory.is_empty() {
+            match self.write_lines(messages) {
+                Ok(()) => {
+                    self.len += messages.len();
+                    return;
+                }
+                Err(e) => println!("Failed to write {}: {:?}", self.path.display(), e),
+            }
+        }
+
+        let kept = (MAX_MEMORY_MESSAGES - self.memory.len()).min(messages.len());
+        if let (Some(first), Some(last)) = (messages[kept..].first(), messages.last()) {
+            println!(
+                "Dropping {} messages that arrived from {} to {}, {} are already spooled in memory",
+                messages.len() - kept,
+                first.arrived_at.to_rfc3339(),
+                last.arrived_at.to_rfc3339(),
+                self.memory.len()
+            );
+        }
+        self.memory.extend(messages[..kept].iter().cloned());
+        self.len += kept;
+    }
+
+    /// Appends whole lines. On an error, e.g. when the disk is full, the file is cut back
+    /// to where it ended, so that none of the messages are in it.
+    fn write_lines(&self, messages: &[IncomingMessage]) -> std::io::Result<()> {
+        let mut lines = String::new();
+        for message in messages {
+            lines.push_str(&serde_json::to_string(message).expect("Failed to serialize message"));
+            lines.push('\n');
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let end = file.metadata()?.len();
+        let written = file
+            .write_all(lines.as_bytes())
+            .and_then(|()| file.sync_data());
+        if written.is_err() {
+            if let Err(e) = file.set_len(end).and_then(|()| file.sync_data()) {
+                println!("Failed to cut back {}: {:?}", self.path.display(), e);
+            }
+        }
+        written
+    }
+
+    /// Removes the messages of a batch from `next` once they are written
+    pub fn remove(&mut self, batch: &SpooledBatch) {
+        self.len = self.len.saturating_sub(batch.lines + batch.from_memory);
+        self.memory.drain(..batch.from_memory);
+        self.offset = batch.end;
+        if self.len == 0 {
+            match fs::remove_file(&self.path) {
+                Ok(()) => return self.reset_offset(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return self.reset_offset(),
+                // The offset still skips the written messages
+                Err(e) => println!("Failed to remove {}: {:?}", self.path.display(), e),
+            }
+        }
+        if let Err(e) = self.write_offset() {
+            println!("Failed to write {}: {:?}", self.offset_path().display(), e);
+        }
+    }
+
+    fn reset_offset(&mut self) {
+        self.offset = 0;
+        if let Err(e) = self.write_offset() {
+            println!("Failed to write {}: {:?}", self.offset_path().display(), e);
+        }
+    }
+
+    /// The offset is written to a temporary file that replaces the old one,
+    /// so a crash leaves either the old or the new offset
+    fn write_offset(&self) -> std::io::Result<()> {
+        let offset_path = self.offset_path();
+        let mut temp_path = offset_path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(self.offset.to_string().as_bytes())?;
+        file.sync_data()?;
+        fs::rename(&temp_path, &offset_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{SubsecRound, Utc};
+
+    fn message(topic: &str) -> IncomingMessage {
+        IncomingMessage {
+            topic: topic.to_string(),
+            payload: r#"{"temperature": 21.5}"#.to_string(),
+            arrived_at: Utc::now().trunc_subsecs(3),
+        }
+    }
+
+    #[test]
+    fn test_messages_are_kept_across_restarts() {
+        let path = std::env::temp_dir().join(format!("ru-berry-{}.spool", std::process::id()));
+        let offset_path = std::env::temp_dir().join(format!("ru-berry-{}.spool.offset", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&offset_path);
+
+        let mut spool = Spool::open(&path);
+        assert!(spool.is_empty());
+        let messages = vec![
+            message("zigbee2mqtt/kitchen"),
+            message("zigbee2mqtt/bathroom"),
+        ];
+        spool.append(&messages);
+        spool.append(&[message("zigbee2mqtt/attic")]);
+
+        let mut spool = Spool::open(&path);
+        assert_eq!(spool.len(), 3);
+        let batch = spool.next(2);
+        assert_eq!(batch.messages, messages);
+        // Read again after a crash before the batch is removed
+        assert_eq!(Spool::open(&path).next(2).messages, messages);
+
+        spool.remove(&batch);
+        assert_eq!(spool.len(), 1);
+        let mut spool = Spool::open(&path);
+        assert_eq!(spool.len(), 1);
+        let batch = spool.next(100);
+        assert_eq!(batch.messages[0].topic, "zigbee2mqtt/attic");
+
+        spool.remove(&batch);
+        assert!(spool.is_empty());
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(&offset_path).unwrap(), "0");
+        assert!(Spool::open(&path).is_empty());
+        fs::remove_file(&offset_path).unwrap();
+    }
+
+    #[test]
+    fn test_messages_in_memory_come_after_the_file() {
+        let dir = std::env::temp_dir().join(format!("ru-berry-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let mut spool = Spool::open(dir.join("messages.spool"));
+
+        spool.append(&[message("zigbee2mqtt/kitchen")]);
+        // A directory can't be opened as a file
+        fs::rename(dir.join("messages.spool"), dir.join("written.spool")).unwrap();
+        fs::create_dir(dir.join("messages.spool")).unwrap();
+        spool.append(&[message("zigbee2mqtt/bathroom")]);
+        assert_eq!(spool.len(), 2);
+
+        fs::remove_dir(dir.join("messages.spool")).unwrap();
+        fs::rename(dir.join("written.spool"), dir.join("messages.spool")).unwrap();
+        let batch = spool.next(100);
+        let topics: Vec<&str> = batch.messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(topics, vec!["zigbee2mqtt/kitchen", "zigbee2mqtt/bathroom"]);
+        spool.remove(&batch);
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_messages_in_memory_are_limited() {
+        let dir = std::env::temp_dir().join(format!("ru-berry-spool-limit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // A directory can't be opened as a file
+        fs::create_dir_all(dir.join("messages.spool")).unwrap();
+        let mut spool = Spool::open(dir.join("messages.spool"));
+
+        let kitchen = vec![message("zigbee2mqtt/kitchen"); MAX_MEMORY_MESSAGES - 1];
+        spool.append(&kitchen);
+        spool.append(&[
+            message("zigbee2mqtt/attic"),
+            message("zigbee2mqtt/bathroom"),
+        ]);
+        assert_eq!(spool.len(), MAX_MEMORY_MESSAGES);
+
+        fs::remove_dir(dir.join("messages.spool")).unwrap();
+        let batch = spool.next(usize::MAX);
+        assert_eq!(batch.messages.len(), MAX_MEMORY_MESSAGES);
+        assert_eq!(batch.messages.last().unwrap().topic, "zigbee2mqtt/attic");
+
+        spool.remove(&batch);
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+use crate::config::HeatingSchedule;
+use crate::conn::{get_conn, SqlitePool};
+use crate::timestamps;
+use crate::web::display::Formatter;
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use rumqttc::{AsyncClient, QoS};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::time::Duration;
+
+/// How often the heating schedule is checked
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Values of a radiator valve, whichever of them the valve reports
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ThermostatReport {
+    /// °C measured by the valve
+    pub(crate) local_temperature: Option<f64>,
+    /// °C
+    pub(crate) setpoint: Option<f64>,
+    /// How far the valve is open, %
+    pub(crate) heating_demand: Option<f64>,
+}
+
+pub fn record(
+    conn: &Connection,
+    device_id: &str,
+    report: &ThermostatReport,
+    received_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO thermostat_data (device_id, local_temperature, setpoint, heating_demand, received_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            device_id,
+            report.local_temperature,
+            report.setpoint,
+            report.heating_demand,
+            timestamps::to_millis(received_at)
+        ],
+    )?;
+    Ok(())
+}
+
+fn report_from_row(row: &rusqlite::Row) -> rusqlite::Result<(DateTime<Utc>, ThermostatReport)> {
+    Ok((
+        timestamps::get(row, 0)?,
+        ThermostatReport {
+            local_temperature: row.get(1)?,
+            setpoint: row.get(2)?,
+            heating_demand: row.get(3)?,
+        },
+    ))
+}
+
+/// Reports of a valve since `modifier`, e.g. `-7 days`, oldest first
+pub fn history(
+    pool: &SqlitePool,
+    device_id: &str,
+    modifier: &str,
+) -> rusqlite::Result<Vec<(DateTime<Utc>, ThermostatReport)>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT received_at, local_temperature, setpoint, heating_demand FROM thermostat_data
+        WHERE device_id = ?1 AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at, id",
+    )?;
+    let history = stmt
+        .query_map(params![device_id, modifier], report_from_row)?
+        .collect();
+    history
+}
+
+pub fn latest(
+    pool: &SqlitePool,
+    device_id: &str,
+) -> rusqlite::Result<Option<(DateTime<Utc>, ThermostatReport)>> {
+    get_conn(pool)
+        .query_row(
+            "SELECT received_at, local_temperature, setpoint, heating_demand FROM thermostat_data
+            WHERE device_id = ?1 ORDER BY received_at DESC, id DESC LIMIT 1",
+            [device_id],
+            report_from_row,
+        )
+        .optional()
+}
+
+/// Entries whose time was passed after `last` until `now`, both in local time
+fn due(
+    schedule: &[HeatingSchedule],
+    last: NaiveDateTime,
+    now: NaiveDateTime,
+) -> Vec<&HeatingSchedule> {
+    schedule
+        .iter()
+        .filter(|entry| {
+            // The check can span midnight
+            [last.date(), now.date()].iter().any(|day| {
+                let at = day.and_time(entry.time);
+                last < at
+                    && at <= now
+                    && (entry.days.is_empty() || entry.days.contains(&day.weekday()))
+            })
+        })
+        .collect()
+}
+
+/// Sends the setpoints of the heating schedule through zigbee2mqtt when their time comes.
+/// Times that passed while ru-berry wasn't running are not caught up on.
+pub async fn run_schedule(
+    schedule: Vec<HeatingSchedule>,
+    base_topic: String,
+    formatter: Formatter,
+    client: AsyncClient,
+) {
+    let mut last = formatter.local(Utc::now());
+    loop {
+        tokio::time::sleep(SCHEDULE_INTERVAL).await;
+        let now = formatter.local(Utc::now());
+        for entry in due(&schedule, last, now) {
+            let topic = format!("{}/{}/set", base_topic, entry.device_id);
+            let payload = serde_json::json!({ "current_heating_setpoint": entry.setpoint });
+            match client
+                .publish(&topic, QoS::AtLeastOnce, false, payload.to_string())
+                .await
+            {
+                Ok(()) => println!("Set {} to {} °C", entry.device_id, entry.setpoint),
+                Err(e) => println!("Failed to set {}: {:?}", entry.device_id, e),
+            }
+        }
+        last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+    use serde_json::json;
+
+    fn time(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_due_entries() {
+        let schedule: Vec<HeatingSchedule> = serde_json::from_value(json!([
+            { "device_id": "living_room", "time": "06:30", "setpoint": 21, "days": ["Mon", "Tue"] },
+            { "device_id": "living_room", "time": "22:00", "setpoint": 17 },
+            { "device_id": "bedroom", "time": "00:00", "setpoint": 16 }
+        ]))
+        .unwrap();
+        let setpoints = |last, now| -> Vec<f64> {
+            due(&schedule, time(last), time(now))
+                .iter()
+                .map(|entry| entry.setpoint)
+                .collect()
+        };
+
+        // 2024-07-01 is a Monday
+        assert_eq!(
+            setpoints("2024-07-01 06:29:50", "2024-07-01 06:30:20"),
+            vec![21.0]
+        );
+        assert_eq!(
+            setpoints("2024-07-01 06:30:20", "2024-07-01 06:30:50"),
+            Vec::<f64>::new()
+        );
+        assert_eq!(
+            setpoints("2024-07-03 06:29:50", "2024-07-03 06:30:20"),
+            Vec::<f64>::new()
+        );
+        assert_eq!(
+            setpoints("2024-07-01 23:59:50", "2024-07-02 00:00:20"),
+            vec![16.0]
+        );
+        assert_eq!(
+            setpoints("2024-07-01 21:59:59", "2024-07-01 22:00:00"),
+            vec![17.0]
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let pool = get_test_pool();
+        let report = ThermostatReport {
+            local_temperature: Some(19.5),
+            setpoint: Some(21.0),
+            heating_demand: Some(40.0),
+        };
+        record(&get_conn(&pool), "living_room", &report, Utc::now()).unwrap();
+        record(
+            &get_conn(&pool),
+            "living_room",
+            &ThermostatReport {
+                heating_demand: Some(0.0),
+                ..Default::default()
+            },
+            Utc::now(),
+        )
+        .unwrap();
+
+        let history = history(&pool, "living_room", "-1 day").unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].1, report);
+        let (_, latest) = latest(&pool, "living_room").unwrap().unwrap();
+        assert_eq!(latest.setpoint, None);
+    }
+}
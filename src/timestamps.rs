@@ -0,0 +1,71 @@
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use rusqlite::types::{FromSqlError, ValueRef};
+use rusqlite::Row;
+
+/// Readings and messages store `received_at` as milliseconds since the Unix epoch (UTC)
+pub fn to_millis(time: DateTime<Utc>) -> i64 {
+    time.timestamp_millis()
+}
+
+/// Timestamps in ISO 8601 or in the UTC format that SQLite uses, e.g. `2024-01-01 12:00:00`
+pub fn parse(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+                .ok()
+                .map(|time| Utc.from_utc_datetime(&time))
+        })
+}
+
+/// Reads a timestamp column in milliseconds, or text from before the timestamps were converted.
+/// Missing or malformed values are an error instead of a panic.
+pub fn get(row: &Row, index: usize) -> rusqlite::Result<DateTime<Utc>> {
+    let value = row.get_ref(index)?;
+    let time = match value {
+        ValueRef::Integer(millis) => DateTime::from_timestamp_millis(millis),
+        ValueRef::Text(text) => std::str::from_utf8(text).ok().and_then(parse),
+        _ => None,
+    };
+    time.ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            index,
+            value.data_type(),
+            Box::new(FromSqlError::Other(
+                format!("Invalid timestamp {:?}", value).into(),
+            )),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::{get_conn, get_test_pool};
+
+    #[test]
+    fn test_parse() {
+        let time = parse("2024-07-01 12:00:00").unwrap();
+        assert_eq!(parse("2024-07-01T12:00:00Z"), Some(time));
+        assert_eq!(parse("2024-07-01T15:00:00+03:00"), Some(time));
+        assert_eq!(parse("yesterday"), None);
+    }
+
+    #[test]
+    fn test_get_does_not_panic_on_malformed_values() {
+        let pool = get_test_pool();
+        let conn = get_conn(&pool);
+        let time = parse("2024-07-01T12:00:00.250Z").unwrap();
+        let read =
+            |value: rusqlite::types::Value| conn.query_row("SELECT ?1", [value], |row| get(row, 0));
+
+        assert_eq!(read(to_millis(time).into()).unwrap(), time);
+        assert_eq!(
+            read("2024-07-01 12:00:00".to_string().into()).unwrap(),
+            parse("2024-07-01 12:00:00").unwrap()
+        );
+        assert!(read("not a time".to_string().into()).is_err());
+        assert!(read(rusqlite::types::Value::Null).is_err());
+    }
+}
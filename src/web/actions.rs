@@ -0,0 +1,24 @@
+use crate::actions;
+use crate::conn::SqlitePool;
+use crate::web::device::Range;
+use crate::web::error::ApiError;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct ActionEventsQuery {
+    /// Every remote when missing
+    device_id: Option<String>,
+    #[serde(default)]
+    range: Range,
+}
+
+/// Button presses of the remotes, latest first
+pub async fn get_action_events(
+    query: ActionEventsQuery,
+    pool: SqlitePool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Getting action events");
+    let events = actions::events(&pool, query.device_id.as_deref(), query.range.modifier())
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&events))
+}
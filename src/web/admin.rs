@@ -0,0 +1,190 @@
+use crate::alerts::Metric;
+use crate::model::StatusType;
+
+/// Page for managing the topic configuration, all changes go through the REST API
+pub async fn get_topic_admin() -> Result<impl warp::Reply, warp::Rejection> {
+    let status_types = StatusType::ALL
+        .iter()
+        .map(|t| format!("<option value=\"{}\">{}</option>", t.as_str(), t.as_str()))
+        .collect::<String>();
+
+    let metrics = Metric::ALL
+        .iter()
+        .map(|m| format!("<option value=\"{}\">{}</option>", m.as_str(), m.as_str()))
+        .collect::<String>();
+
+    Ok(warp::reply::html(
+        TOPIC_ADMIN_PAGE
+            .replace("{status_types}", &status_types)
+            .replace("{metrics}", &metrics),
+    ))
+}
+
+const TOPIC_ADMIN_PAGE: &str = r#"<html>
+    <head>
+        <title>Topic Configuration</title>
+        <style>
+            body {
+                font-family: Arial, sans-serif;
+            }
+            table {
+                border-collapse: collapse;
+            }
+            th, td {
+                padding: 4px;
+            }
+            input.number {
+                width: 5em;
+            }
+            #error {
+                color: red;
+            }
+        </style>
+    </head>
+    <body>
+        <h1>Topic Configuration</h1>
+        <p>Changes are shown on the <a href="/sensor_data_status">status page</a> immediately.</p>
+        <p id="error"></p>
+        <table border="1">
+            <thead>
+                <tr>
+                    <th>Order</th>
+                    <th>Device ID</th>
+                    <th>Status Type</th>
+                    <th>Display Name</th>
+                    <th>Limit Metric</th>
+                    <th>Min Limit</th>
+                    <th>Max Limit</th>
+                    <th>Unit</th>
+                    <th></th>
+                </tr>
+            </thead>
+            <tbody id="topics"></tbody>
+            <tfoot>
+                <tr id="new-topic">
+                    <td></td>
+                    <td><input name="topic_name"></td>
+                    <td><select name="status_type">{status_types}</select></td>
+                    <td><input name="display_name"></td>
+                    <td><select name="limit_metric"><option value=""></option>{metrics}</select></td>
+                    <td><input name="min_limit" class="number" type="number" step="any"></td>
+                    <td><input name="max_limit" class="number" type="number" step="any"></td>
+                    <td><input name="unit" class="number"></td>
+                    <td><button onclick="addTopic()">Add</button></td>
+                </tr>
+            </tfoot>
+        </table>
+
+        <template id="topic-row">
+            <tr>
+                <td>
+                    <button class="up">&uarr;</button>
+                    <button class="down">&darr;</button>
+                </td>
+                <td><input name="topic_name"></td>
+                <td><select name="status_type">{status_types}</select></td>
+                <td><input name="display_name"></td>
+                <td><select name="limit_metric"><option value=""></option>{metrics}</select></td>
+                <td><input name="min_limit" class="number" type="number" step="any"></td>
+                <td><input name="max_limit" class="number" type="number" step="any"></td>
+                <td><input name="unit" class="number"></td>
+                <td>
+                    <button class="save">Save</button>
+                    <button class="delete">Delete</button>
+                </td>
+            </tr>
+        </template>
+
+        <script>
+            const fields = ['topic_name', 'status_type', 'display_name', 'limit_metric', 'min_limit', 'max_limit', 'unit'];
+            let topics = [];
+
+            function readRow(row) {
+                const topic = {};
+                for (const field of fields) {
+                    const value = row.querySelector('[name="' + field + '"]').value;
+                    if (field === 'min_limit' || field === 'max_limit') {
+                        topic[field] = value === '' ? null : parseFloat(value);
+                    } else if (field === 'limit_metric') {
+                        topic[field] = value === '' ? null : value;
+                    } else {
+                        topic[field] = value;
+                    }
+                }
+                return topic;
+            }
+
+            async function request(method, url, body) {
+                document.getElementById('error').textContent = '';
+                const response = await fetch(url, {
+                    method: method,
+                    headers: { 'Content-Type': 'application/json' },
+                    body: body === undefined ? undefined : JSON.stringify(body),
+                });
+                if (!response.ok) {
+                    const text = await response.text();
+                    let message = text;
+                    try {
+                        message = JSON.parse(text).message || text;
+                    } catch (e) {}
+                    document.getElementById('error').textContent = response.status + ': ' + message;
+                    return false;
+                }
+                return true;
+            }
+
+            async function load() {
+                const response = await fetch('/topic_configuration');
+                topics = await response.json();
+                const body = document.getElementById('topics');
+                body.innerHTML = '';
+                const template = document.getElementById('topic-row');
+
+                topics.forEach((topic, index) => {
+                    const row = template.content.firstElementChild.cloneNode(true);
+                    for (const field of fields) {
+                        const value = topic[field];
+                        row.querySelector('[name="' + field + '"]').value = value === null ? '' : value;
+                    }
+                    row.querySelector('.save').onclick = async () => {
+                        if (await request('PUT', '/topic_configuration/' + topic.id, readRow(row))) {
+                            await load();
+                        }
+                    };
+                    row.querySelector('.delete').onclick = async () => {
+                        if (confirm('Delete ' + topic.topic_name + '?')
+                            && await request('DELETE', '/topic_configuration/' + topic.id)) {
+                            await load();
+                        }
+                    };
+                    row.querySelector('.up').onclick = () => move(index, -1);
+                    row.querySelector('.down').onclick = () => move(index, 1);
+                    body.appendChild(row);
+                });
+            }
+
+            async function move(index, direction) {
+                const target = index + direction;
+                if (target < 0 || target >= topics.length) {
+                    return;
+                }
+                const ids = topics.map(t => t.id);
+                [ids[index], ids[target]] = [ids[target], ids[index]];
+                if (await request('PUT', '/topic_configuration/order', ids)) {
+                    await load();
+                }
+            }
+
+            async function addTopic() {
+                const row = document.getElementById('new-topic');
+                if (await request('POST', '/topic_configuration', readRow(row))) {
+                    row.querySelectorAll('input').forEach(input => input.value = '');
+                    await load();
+                }
+            }
+
+            load();
+        </script>
+    </body>
+</html>
+"#;
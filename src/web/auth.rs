@@ -0,0 +1,533 @@
+use crate::config::AuthConfig;
+use crate::conn::{get_conn, try_get_conn, SqlitePool};
+use crate::web::error::ApiError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+const TOKEN_LENGTH: usize = 40;
+
+/// Basic credentials that were verified are trusted for this long without verifying them again
+const VERIFIED_CREDENTIALS_TTL: Duration = Duration::from_secs(300);
+
+/// `last_used_at` of a token is only updated when it is older, so that not every request writes
+const LAST_USED_UPDATE_AFTER: &str = "-5 minutes";
+
+/// Verified for unknown usernames, so that they take as long as a wrong password
+const DUMMY_PASSWORD_HASH: &str = "$2b$12$hwhE.EAxyp3pIpxxY38KhOTBnu2Mvs5cX9vrT3FxFEPBFtVeebAR2";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Can view sensor data and the status page
+    #[default]
+    Read,
+    /// Can do everything, including managing API tokens
+    Admin,
+}
+
+impl Scope {
+    fn allows(self, required: Scope) -> bool {
+        self == Scope::Admin || self == required
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn from_db(value: &str) -> Option<Scope> {
+        match value {
+            "read" => Some(Scope::Read),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingCredentials,
+    InvalidCredentials,
+    InsufficientScope,
+    /// API tokens couldn't be checked
+    Database(ApiError),
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::MissingCredentials | AuthError::InvalidCredentials => ApiError::Unauthorized,
+            AuthError::InsufficientScope => ApiError::Forbidden,
+            AuthError::Database(e) => e,
+        }
+    }
+}
+
+/// The users of the configuration. Verifying a password with bcrypt takes about a second on a
+/// Raspberry Pi and browsers send the credentials with every request, so the credentials that
+/// were verified are remembered for `VERIFIED_CREDENTIALS_TTL` by a hash of them.
+pub struct Authenticator {
+    config: AuthConfig,
+    verified: Mutex<HashMap<String, (Scope, Instant)>>,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Self {
+        Authenticator {
+            config,
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn verified_scope(&self, credentials_hash: &str) -> Option<Scope> {
+        let verified = self.verified.lock().unwrap();
+        verified
+            .get(credentials_hash)
+            .filter(|(_, verified_at)| verified_at.elapsed() < VERIFIED_CREDENTIALS_TTL)
+            .map(|(scope, _)| *scope)
+    }
+
+    fn remember(&self, credentials_hash: String, scope: Scope) {
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, (_, verified_at)| verified_at.elapsed() < VERIFIED_CREDENTIALS_TTL);
+        verified.insert(credentials_hash, (scope, Instant::now()));
+    }
+}
+
+#[derive(Serialize)]
+struct ApiToken {
+    id: i64,
+    name: String,
+    scope: Scope,
+    created_at: String,
+    last_used_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NewApiToken {
+    name: String,
+    #[serde(default)]
+    scope: Scope,
+}
+
+#[derive(Serialize)]
+struct CreatedApiToken {
+    id: i64,
+    name: String,
+    scope: Scope,
+    /// Only returned once, the database stores a hash of it
+    token: String,
+}
+
+/// Reject requests that don't carry credentials for at least the `required` scope.
+/// Every request is let through if authentication is not configured.
+pub fn with_auth(
+    auth: Option<Arc<Authenticator>>,
+    pool: SqlitePool,
+    required: Scope,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let auth = auth.clone();
+            let pool = pool.clone();
+            async move {
+                let auth = match auth {
+                    None => return Ok(()),
+                    Some(auth) => auth,
+                };
+
+                // bcrypt and the database would block the other requests of the worker thread
+                let scope = tokio::task::spawn_blocking(move || {
+                    authenticate(header.as_deref(), &auth, &pool)
+                })
+                .await
+                .expect("Authentication failed")
+                .map_err(ApiError::from)?;
+
+                if scope.allows(required) {
+                    Ok(())
+                } else {
+                    Err(Rejection::from(ApiError::from(AuthError::InsufficientScope)))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn authenticate(
+    header: Option<&str>,
+    auth: &Authenticator,
+    pool: &SqlitePool,
+) -> Result<Scope, AuthError> {
+    let header = header.ok_or(AuthError::MissingCredentials)?;
+
+    if let Some(encoded) = header.strip_prefix("Basic ") {
+        basic_auth_scope(encoded.trim(), auth)
+    } else if let Some(token) = header.strip_prefix("Bearer ") {
+        token_scope(token.trim(), pool)
+    } else {
+        Err(AuthError::InvalidCredentials)
+    }
+}
+
+fn basic_auth_scope(encoded: &str, auth: &Authenticator) -> Result<Scope, AuthError> {
+    let credentials_hash = hash_token(encoded);
+    if let Some(scope) = auth.verified_scope(&credentials_hash) {
+        return Ok(scope);
+    }
+
+    let decoded = STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or(AuthError::InvalidCredentials)?;
+
+    let (username, password) = decoded
+        .split_once(':')
+        .ok_or(AuthError::InvalidCredentials)?;
+
+    let user = match auth.config.users.iter().find(|u| u.username == username) {
+        Some(user) => user,
+        None => {
+            let _ = bcrypt::verify(password, DUMMY_PASSWORD_HASH);
+            return Err(AuthError::InvalidCredentials);
+        }
+    };
+
+    match bcrypt::verify(password, &user.password_hash) {
+        Ok(true) => {
+            auth.remember(credentials_hash, user.scope);
+            Ok(user.scope)
+        }
+        Ok(false) => Err(AuthError::InvalidCredentials),
+        Err(e) => {
+            println!("Invalid password hash for user {}: {:?}", user.username, e);
+            Err(AuthError::InvalidCredentials)
+        }
+    }
+}
+
+fn token_scope(token: &str, pool: &SqlitePool) -> Result<Scope, AuthError> {
+    let conn = try_get_conn(pool).map_err(|e| AuthError::Database(e.into()))?;
+    let token_hash = hash_token(token);
+
+    let token: Option<(String, bool)> = conn
+        .query_row(
+            &format!(
+                "SELECT scope, last_used_at IS NULL OR last_used_at < datetime('now', '{}')
+                FROM api_tokens WHERE token_hash = ?1",
+                LAST_USED_UPDATE_AFTER
+            ),
+            [&token_hash],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(|e| AuthError::Database(e.into()))?;
+
+    let (scope, update_last_used) = token.ok_or(AuthError::InvalidCredentials)?;
+    let scope = Scope::from_db(&scope).ok_or(AuthError::InvalidCredentials)?;
+
+    if update_last_used {
+        if let Err(e) = conn.execute(
+            "UPDATE api_tokens SET last_used_at = CURRENT_TIMESTAMP WHERE token_hash = ?1",
+            [&token_hash],
+        ) {
+            println!("Failed to update token last_used_at: {:?}", e);
+        }
+    }
+
+    Ok(scope)
+}
+
+/// Tokens are random, so a plain SHA-256 is enough to avoid storing them as is.
+/// Basic credentials are only kept in memory by this hash after they were verified with bcrypt.
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn create_token(pool: &SqlitePool, name: &str, scope: Scope) -> rusqlite::Result<(i64, String)> {
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+
+    let conn = get_conn(pool);
+    conn.execute(
+        "INSERT INTO api_tokens (name, token_hash, scope) VALUES (?1, ?2, ?3)",
+        params![name, hash_token(&token), scope.as_str()],
+    )?;
+
+    Ok((conn.last_insert_rowid(), token))
+}
+
+pub async fn list_api_tokens(pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    let conn = get_conn(&pool);
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, name, scope, created_at, last_used_at FROM api_tokens ORDER BY id",
+        )
+        .map_err(ApiError::from)?;
+
+    let tokens = stmt
+        .query_map([], |row| {
+            Ok(ApiToken {
+                id: row.get("id")?,
+                name: row.get("name")?,
+                scope: Scope::from_db(&row.get::<_, String>("scope")?).unwrap_or_default(),
+                created_at: row.get("created_at")?,
+                last_used_at: row.get("last_used_at")?,
+            })
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&tokens))
+}
+
+pub async fn create_api_token(
+    new_token: NewApiToken,
+    pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let (id, token) =
+        create_token(&pool, &new_token.name, new_token.scope).map_err(ApiError::from)?;
+    println!("Created API token {} with {} scope", new_token.name, new_token.scope.as_str());
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&CreatedApiToken {
+            id,
+            name: new_token.name,
+            scope: new_token.scope,
+            token,
+        }),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn delete_api_token(id: i64, pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    let conn = get_conn(&pool);
+    let deleted = conn
+        .execute("DELETE FROM api_tokens WHERE id = ?1", [id])
+        .map_err(ApiError::from)?;
+
+    if deleted == 0 {
+        return Err(ApiError::NotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserConfig;
+    use crate::conn::get_test_pool;
+    use crate::web::error::handle_rejection;
+
+    fn test_auth() -> AuthConfig {
+        AuthConfig {
+            users: vec![
+                UserConfig {
+                    username: "viewer".to_string(),
+                    password_hash: bcrypt::hash("viewer-password", 4).unwrap(),
+                    scope: Scope::Read,
+                },
+                UserConfig {
+                    username: "admin".to_string(),
+                    password_hash: bcrypt::hash("admin-password", 4).unwrap(),
+                    scope: Scope::Admin,
+                },
+            ],
+        }
+    }
+
+    fn basic_header(username: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password)))
+    }
+
+    #[test]
+    fn test_authenticate_basic() {
+        let pool = get_test_pool();
+        let auth = Authenticator::new(test_auth());
+
+        let header = basic_header("viewer", "viewer-password");
+        assert_eq!(authenticate(Some(&header), &auth, &pool).unwrap(), Scope::Read);
+
+        let header = basic_header("admin", "admin-password");
+        assert_eq!(authenticate(Some(&header), &auth, &pool).unwrap(), Scope::Admin);
+    }
+
+    #[test]
+    fn test_authenticate_basic_wrong_password() {
+        let pool = get_test_pool();
+        let auth = Authenticator::new(test_auth());
+
+        let header = basic_header("viewer", "admin-password");
+        assert!(matches!(
+            authenticate(Some(&header), &auth, &pool),
+            Err(AuthError::InvalidCredentials)
+        ));
+
+        let header = basic_header("nobody", "viewer-password");
+        assert!(matches!(
+            authenticate(Some(&header), &auth, &pool),
+            Err(AuthError::InvalidCredentials)
+        ));
+        // Unknown usernames are verified against a valid hash, not rejected by an invalid one
+        assert!(!bcrypt::verify("viewer-password", DUMMY_PASSWORD_HASH).unwrap());
+    }
+
+    #[test]
+    fn test_authenticate_missing_or_malformed_header() {
+        let pool = get_test_pool();
+        let auth = Authenticator::new(test_auth());
+
+        assert!(matches!(
+            authenticate(None, &auth, &pool),
+            Err(AuthError::MissingCredentials)
+        ));
+        assert!(matches!(
+            authenticate(Some("Basic not-base64!"), &auth, &pool),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            authenticate(Some("Digest something"), &auth, &pool),
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn test_authenticate_bearer_token() {
+        let pool = get_test_pool();
+        let auth = Authenticator::new(test_auth());
+        let (_, token) = create_token(&pool, "grafana", Scope::Read).unwrap();
+
+        let header = format!("Bearer {}", token);
+        assert_eq!(authenticate(Some(&header), &auth, &pool).unwrap(), Scope::Read);
+
+        assert!(matches!(
+            authenticate(Some("Bearer wrong-token"), &auth, &pool),
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn test_verified_basic_credentials_are_remembered() {
+        let pool = get_test_pool();
+        let auth = Authenticator::new(test_auth());
+
+        let header = basic_header("viewer", "admin-password");
+        assert!(authenticate(Some(&header), &auth, &pool).is_err());
+        assert!(auth.verified.lock().unwrap().is_empty());
+
+        let header = basic_header("viewer", "viewer-password");
+        assert_eq!(authenticate(Some(&header), &auth, &pool).unwrap(), Scope::Read);
+        let credentials_hash = hash_token(header.strip_prefix("Basic ").unwrap());
+        assert_eq!(auth.verified_scope(&credentials_hash), Some(Scope::Read));
+
+        if let Some(expired) = Instant::now().checked_sub(VERIFIED_CREDENTIALS_TTL) {
+            let mut verified = auth.verified.lock().unwrap();
+            verified.insert(credentials_hash.clone(), (Scope::Read, expired));
+            drop(verified);
+            assert_eq!(auth.verified_scope(&credentials_hash), None);
+        }
+    }
+
+    #[test]
+    fn test_token_last_used_is_updated_at_most_every_few_minutes() {
+        let pool = get_test_pool();
+        let auth = Authenticator::new(test_auth());
+        let (id, token) = create_token(&pool, "grafana", Scope::Read).unwrap();
+        let header = format!("Bearer {}", token);
+        let set_last_used = |modifier: &str| {
+            get_conn(&pool)
+                .execute(
+                    "UPDATE api_tokens SET last_used_at = datetime('now', ?2) WHERE id = ?1",
+                    params![id, modifier],
+                )
+                .unwrap();
+        };
+        let last_used = || -> String {
+            get_conn(&pool)
+                .query_row("SELECT last_used_at FROM api_tokens WHERE id = ?1", [id], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+
+        set_last_used("-1 minute");
+        let recently = last_used();
+        authenticate(Some(&header), &auth, &pool).unwrap();
+        assert_eq!(last_used(), recently);
+
+        set_last_used("-10 minutes");
+        let long_ago = last_used();
+        authenticate(Some(&header), &auth, &pool).unwrap();
+        assert_ne!(last_used(), long_ago);
+    }
+
+    #[test]
+    fn test_token_is_stored_hashed() {
+        let pool = get_test_pool();
+        let (id, token) = create_token(&pool, "script", Scope::Admin).unwrap();
+
+        let conn = get_conn(&pool);
+        let stored: String = conn
+            .query_row("SELECT token_hash FROM api_tokens WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_ne!(stored, token);
+        assert_eq!(stored, hash_token(&token));
+    }
+
+    #[tokio::test]
+    async fn test_with_auth_filter() {
+        let pool = get_test_pool();
+        let route = warp::path("admin")
+            .and(with_auth(Some(Arc::new(Authenticator::new(test_auth()))), pool.clone(), Scope::Admin))
+            .map(|| "ok")
+            .recover(handle_rejection);
+
+        let response = warp::test::request().path("/admin").reply(&route).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key("WWW-Authenticate"));
+
+        let response = warp::test::request()
+            .path("/admin")
+            .header("authorization", basic_header("viewer", "viewer-password"))
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = warp::test::request()
+            .path("/admin")
+            .header("authorization", basic_header("admin", "admin-password"))
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_with_auth_disabled() {
+        let pool = get_test_pool();
+        let route = warp::path("admin")
+            .and(with_auth(None, pool, Scope::Admin))
+            .map(|| "ok");
+
+        let response = warp::test::request().path("/admin").reply(&route).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
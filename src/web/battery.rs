@@ -0,0 +1,37 @@
+use crate::battery::{self, BatteryStatus};
+use crate::conn::SqlitePool;
+use crate::web::error::ApiError;
+use crate::web::templates::Templates;
+use crate::web::topics::device_topic_configurations;
+use minijinja::context;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct BatteryView {
+    title: String,
+    #[serde(flatten)]
+    status: BatteryStatus,
+}
+
+/// Every battery powered device, lowest battery first
+pub async fn get_battery_overview(
+    pool: SqlitePool,
+    templates: Templates,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Getting battery overview");
+    let topics = device_topic_configurations(&pool).map_err(ApiError::from)?;
+    let devices: Vec<BatteryView> = battery::overview(&pool)
+        .map_err(ApiError::from)?
+        .into_iter()
+        .map(|status| BatteryView {
+            title: topics
+                .iter()
+                .find(|t| t.topic_name == status.device_id)
+                .map_or(status.device_id.clone(), |t| t.title().to_string()),
+            status,
+        })
+        .collect();
+
+    let html = templates.render("battery.html", context! { devices })?;
+    Ok(warp::reply::html(html))
+}
@@ -0,0 +1,242 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+const WIDTH: f64 = 800.0;
+const HEIGHT: f64 = 200.0;
+
+/// Values of one line in a chart, oldest first.
+/// Values that aren't finite are left out.
+pub struct Series {
+    pub name: &'static str,
+    pub color: &'static str,
+    pub points: Vec<(NaiveDateTime, f64)>,
+}
+
+/// Line chart drawn as SVG by the `line_chart` macro, every series shares the time axis
+#[derive(Serialize)]
+pub struct LineChart {
+    title: String,
+    width: f64,
+    height: f64,
+    /// Nothing to draw
+    empty: bool,
+    min: f64,
+    max: f64,
+    /// Value axis on the right for the secondary series
+    secondary: Option<Axis>,
+    start: String,
+    end: String,
+    series: Vec<SeriesView>,
+}
+
+#[derive(Serialize)]
+struct Axis {
+    min: f64,
+    max: f64,
+}
+
+#[derive(Serialize)]
+struct SeriesView {
+    name: &'static str,
+    color: &'static str,
+    /// `points` attribute of the SVG polyline
+    points: String,
+}
+
+fn finite(series: Vec<Series>) -> Vec<Series> {
+    series
+        .into_iter()
+        .map(|s| Series {
+            points: s
+                .points
+                .into_iter()
+                .filter(|(_, value)| value.is_finite())
+                .collect(),
+            ..s
+        })
+        .collect()
+}
+
+/// Range of the values, a flat line is drawn in the middle instead of dividing by zero
+fn axis(series: &[Series]) -> Axis {
+    let values = || {
+        series
+            .iter()
+            .flat_map(|s| s.points.iter().map(|(_, value)| *value))
+    };
+    let mut min = values().fold(f64::INFINITY, f64::min);
+    let mut max = values().fold(f64::NEG_INFINITY, f64::max);
+    if !min.is_finite() {
+        return Axis { min: 0.0, max: 1.0 };
+    }
+    if max <= min {
+        min -= 1.0;
+        max += 1.0;
+    }
+    Axis { min, max }
+}
+
+impl LineChart {
+    pub fn new(title: &str, series: Vec<Series>) -> LineChart {
+        LineChart::with_secondary_axis(title, series, Vec::new())
+    }
+
+    /// `secondary` series are scaled to their own axis on the right, for values in another unit
+    pub fn with_secondary_axis(
+        title: &str,
+        series: Vec<Series>,
+        secondary: Vec<Series>,
+    ) -> LineChart {
+        let series = finite(series);
+        let secondary = finite(secondary);
+        let all_points = || {
+            series
+                .iter()
+                .chain(secondary.iter())
+                .flat_map(|s| s.points.iter())
+        };
+
+        let start = all_points().map(|(time, _)| *time).min();
+        let end = all_points().map(|(time, _)| *time).max();
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => {
+                return LineChart {
+                    title: title.to_string(),
+                    width: WIDTH,
+                    height: HEIGHT,
+                    empty: true,
+                    min: 0.0,
+                    max: 0.0,
+                    secondary: None,
+                    start: String::new(),
+                    end: String::new(),
+                    series: Vec::new(),
+                }
+            }
+        };
+
+        let duration = (end - start).num_seconds().max(1) as f64;
+        let view = |s: &Series, axis: &Axis| SeriesView {
+            name: s.name,
+            color: s.color,
+            points: s
+                .points
+                .iter()
+                .map(|(time, value)| {
+                    let x = (*time - start).num_seconds() as f64 / duration * WIDTH;
+                    let y = HEIGHT - (value - axis.min) / (axis.max - axis.min) * HEIGHT;
+                    format!("{:.1},{:.1}", x, y)
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+
+        let primary = axis(&series);
+        let secondary_axis = (!secondary.is_empty()).then(|| axis(&secondary));
+        let mut views: Vec<SeriesView> = series.iter().map(|s| view(s, &primary)).collect();
+        if let Some(secondary_axis) = &secondary_axis {
+            views.extend(secondary.iter().map(|s| view(s, secondary_axis)));
+        }
+
+        LineChart {
+            title: title.to_string(),
+            width: WIDTH,
+            height: HEIGHT,
+            empty: false,
+            min: primary.min,
+            max: primary.max,
+            secondary: secondary_axis,
+            start: start.format("%Y-%m-%d %H:%M").to_string(),
+            end: end.format("%Y-%m-%d %H:%M").to_string(),
+            series: views,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_points_are_scaled_to_the_chart() {
+        let chart = LineChart::new(
+            "Temperature",
+            vec![Series {
+                name: "temperature",
+                color: "red",
+                points: vec![
+                    (time("2024-01-01 00:00:00"), 10.0),
+                    (time("2024-01-01 12:00:00"), 20.0),
+                    (time("2024-01-02 00:00:00"), 15.0),
+                ],
+            }],
+        );
+
+        assert!(!chart.empty);
+        assert_eq!(chart.min, 10.0);
+        assert_eq!(chart.max, 20.0);
+        assert_eq!(chart.start, "2024-01-01 00:00");
+        assert_eq!(chart.series[0].points, "0.0,200.0 400.0,0.0 800.0,100.0");
+    }
+
+    #[test]
+    fn test_empty_and_flat_charts() {
+        let chart = LineChart::new("Battery", vec![]);
+        assert!(chart.empty);
+
+        let chart = LineChart::new(
+            "Battery",
+            vec![Series {
+                name: "battery",
+                color: "green",
+                points: vec![(time("2024-01-01 00:00:00"), 100.0)],
+            }],
+        );
+        assert!(!chart.empty);
+        assert_eq!(chart.series[0].points, "0.0,100.0");
+
+        let chart = LineChart::new(
+            "Dew Point",
+            vec![Series {
+                name: "dew_point",
+                color: "teal",
+                points: vec![(time("2024-01-01 00:00:00"), f64::NEG_INFINITY)],
+            }],
+        );
+        assert!(chart.empty);
+    }
+
+    #[test]
+    fn test_secondary_axis() {
+        let chart = LineChart::with_secondary_axis(
+            "Heating",
+            vec![Series {
+                name: "setpoint",
+                color: "blue",
+                points: vec![
+                    (time("2024-01-01 00:00:00"), 18.0),
+                    (time("2024-01-02 00:00:00"), 22.0),
+                ],
+            }],
+            vec![Series {
+                name: "heating_demand",
+                color: "orange",
+                points: vec![
+                    (time("2024-01-01 00:00:00"), 0.0),
+                    (time("2024-01-02 00:00:00"), 100.0),
+                ],
+            }],
+        );
+
+        assert_eq!((chart.min, chart.max), (18.0, 22.0));
+        let secondary = chart.secondary.as_ref().unwrap();
+        assert_eq!((secondary.min, secondary.max), (0.0, 100.0));
+        assert_eq!(chart.series[1].points, "0.0,200.0 800.0,0.0");
+        assert!(LineChart::new("Battery", vec![]).secondary.is_none());
+    }
+}
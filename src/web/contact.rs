@@ -0,0 +1,24 @@
+use crate::conn::SqlitePool;
+use crate::contact;
+use crate::web::device::Range;
+use crate::web::error::ApiError;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct ContactEventsQuery {
+    /// Every contact sensor when missing
+    device_id: Option<String>,
+    #[serde(default)]
+    range: Range,
+}
+
+/// Opening and closing history of the contact sensors, latest first
+pub async fn get_contact_events(
+    query: ContactEventsQuery,
+    pool: SqlitePool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Getting contact events");
+    let events = contact::events(&pool, query.device_id.as_deref(), query.range.modifier())
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&events))
+}
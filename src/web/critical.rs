@@ -0,0 +1,26 @@
+use crate::conn::SqlitePool;
+use crate::critical;
+use crate::events::{broadcast, EventSender, LiveEvent};
+use crate::web::error::ApiError;
+use chrono::Utc;
+
+/// Alarms that are still reported or haven't been acknowledged, oldest first
+pub async fn get_critical_alarms(pool: SqlitePool) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Getting critical alarms");
+    let alarms = critical::pending(&pool).map_err(ApiError::from)?;
+    Ok(warp::reply::json(&alarms))
+}
+
+/// Stops the reminders of the alarm and returns it
+pub async fn acknowledge_critical_alarm(
+    id: i64,
+    pool: SqlitePool,
+    events: EventSender,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Acknowledging critical alarm {}", id);
+    let alarm = critical::acknowledge(&pool, id, Utc::now())
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::NotFound)?;
+    broadcast(&events, LiveEvent::CriticalAlarm(alarm.clone()));
+    Ok(warp::reply::json(&alarm))
+}
@@ -0,0 +1,555 @@
+use crate::air_quality::{self, AirQualityReport};
+use crate::alerts::Metric;
+use crate::battery;
+use crate::conn::{get_conn, SqlitePool};
+use crate::model::SensorData;
+use crate::occupancy::{self, OccupancyPeriod};
+use crate::plausibility;
+use crate::thermostat;
+use crate::timestamps;
+use crate::web::chart::{LineChart, Series};
+use crate::web::display::Formatter;
+use crate::web::error::ApiError;
+use crate::web::templates::Templates;
+use crate::web::topics::find_topic_configuration_for_device;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use minijinja::context;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Raw messages shown at the bottom of the page
+const RECENT_MESSAGES: i64 = 50;
+
+/// Time range of the charts and the daily statistics
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Range {
+    Day,
+    #[default]
+    Week,
+    Month,
+    Year,
+}
+
+impl Range {
+    pub const ALL: [Range; 4] = [Range::Day, Range::Week, Range::Month, Range::Year];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Range::Day => "day",
+            Range::Week => "week",
+            Range::Month => "month",
+            Range::Year => "year",
+        }
+    }
+
+    /// SQLite datetime modifier for the start of the range
+    pub fn modifier(&self) -> &'static str {
+        match self {
+            Range::Day => "-1 day",
+            Range::Week => "-7 days",
+            Range::Month => "-30 days",
+            Range::Year => "-365 days",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeviceQuery {
+    #[serde(default)]
+    range: Range,
+}
+
+#[derive(Serialize, Debug)]
+struct DeviceInfo {
+    device_id: String,
+    online: Option<bool>,
+    /// None for a timestamp that couldn't be converted to milliseconds
+    last_seen: Option<DateTime<Utc>>,
+    first_reading: Option<DateTime<Utc>>,
+    readings: i64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct DailyStatistics {
+    day: String,
+    min_temperature: f64,
+    max_temperature: f64,
+    avg_temperature: f64,
+    min_humidity: i64,
+    max_humidity: i64,
+    avg_humidity: f64,
+    readings: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct Message {
+    topic: String,
+    payload: String,
+    /// None for a timestamp that couldn't be converted to milliseconds
+    received_at: Option<DateTime<Utc>>,
+}
+
+/// Returns None if nothing is known about the device
+fn device_info(pool: &SqlitePool, device_id: &str) -> rusqlite::Result<Option<DeviceInfo>> {
+    let conn = get_conn(pool);
+
+    let (online, last_seen) = conn
+        .query_row(
+            "SELECT online, last_seen FROM devices WHERE device_id = ?1",
+            [device_id],
+            |row| Ok((Some(row.get::<_, bool>(0)?), timestamps::get(row, 1).ok())),
+        )
+        .optional()?
+        .unwrap_or((None, None));
+
+    let (readings, first_reading) = conn.query_row(
+        "SELECT COUNT(*), MIN(received_at) FROM sensor_data WHERE device_id = ?1",
+        [device_id],
+        |row| {
+            let first_reading: Option<i64> = row.get(1)?;
+            Ok((
+                row.get::<_, i64>(0)?,
+                first_reading.and_then(DateTime::from_timestamp_millis),
+            ))
+        },
+    )?;
+
+    if online.is_none() && readings == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(DeviceInfo {
+        device_id: device_id.to_string(),
+        online,
+        last_seen,
+        first_reading,
+        readings,
+    }))
+}
+
+fn latest_reading(pool: &SqlitePool, device_id: &str) -> rusqlite::Result<Option<SensorData>> {
+    let conn = get_conn(pool);
+    conn.query_row(
+        "SELECT * FROM sensor_data WHERE device_id = ?1 ORDER BY received_at DESC LIMIT 1",
+        [device_id],
+        SensorData::from_row,
+    )
+    .optional()
+}
+
+/// Readings in the range, oldest first
+fn readings(pool: &SqlitePool, device_id: &str, range: Range) -> rusqlite::Result<Vec<SensorData>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT * FROM sensor_data
+        WHERE device_id = ?1 AND received_at >= unixepoch('now', ?2) * 1000
+        ORDER BY received_at",
+    )?;
+    let readings = stmt
+        .query_map(params![device_id, range.modifier()], SensorData::from_row)?
+        .collect();
+    readings
+}
+
+/// Statistics of the readings by day in the configured time zone, latest day first
+fn daily_statistics(readings: &[SensorData], formatter: &Formatter) -> Vec<DailyStatistics> {
+    let mut days: BTreeMap<NaiveDate, Vec<&SensorData>> = BTreeMap::new();
+    for data in readings {
+        days.entry(formatter.local(data.received_at).date())
+            .or_default()
+            .push(data);
+    }
+
+    days.into_iter()
+        .rev()
+        .map(|(day, readings)| {
+            let temperatures = readings.iter().map(|data| data.temperature as f64);
+            let humidities = readings.iter().map(|data| data.humidity as i64);
+            let count = readings.len() as f64;
+            DailyStatistics {
+                day: day.to_string(),
+                min_temperature: temperatures.clone().fold(f64::INFINITY, f64::min),
+                max_temperature: temperatures.clone().fold(f64::NEG_INFINITY, f64::max),
+                avg_temperature: temperatures.sum::<f64>() / count,
+                min_humidity: humidities.clone().min().unwrap_or_default(),
+                max_humidity: humidities.clone().max().unwrap_or_default(),
+                avg_humidity: humidities.sum::<i64>() as f64 / count,
+                readings: readings.len() as i64,
+            }
+        })
+        .collect()
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct DailyOccupancy {
+    day: String,
+    hours: f64,
+}
+
+/// Occupied hours of each local day, newest first. Periods over midnight count for both days.
+fn daily_occupancy(
+    periods: &[OccupancyPeriod],
+    formatter: &Formatter,
+    now: DateTime<Utc>,
+) -> Vec<DailyOccupancy> {
+    let mut days: BTreeMap<NaiveDate, f64> = BTreeMap::new();
+    for period in periods {
+        let mut start = formatter.local(period.started_at);
+        let end = formatter.local(period.ended_at.unwrap_or(now));
+        while start < end {
+            let midnight = (start.date() + Duration::days(1)).and_time(NaiveTime::MIN);
+            let until = end.min(midnight);
+            *days.entry(start.date()).or_default() += (until - start).num_seconds() as f64 / 3600.0;
+            start = until;
+        }
+    }
+
+    days.into_iter()
+        .rev()
+        .map(|(day, hours)| DailyOccupancy {
+            day: day.to_string(),
+            hours: (hours * 10.0).round() / 10.0,
+        })
+        .collect()
+}
+
+/// Steps between 0 (empty) and 1 (occupied)
+fn occupancy_points(
+    periods: &[OccupancyPeriod],
+    formatter: &Formatter,
+    now: DateTime<Utc>,
+) -> Vec<(chrono::NaiveDateTime, f64)> {
+    periods
+        .iter()
+        .flat_map(|period| {
+            let start = formatter.local(period.started_at);
+            let end = formatter.local(period.ended_at.unwrap_or(now));
+            [(start, 0.0), (start, 1.0), (end, 1.0), (end, 0.0)]
+        })
+        .collect()
+}
+
+fn recent_messages(pool: &SqlitePool, device_id: &str) -> rusqlite::Result<Vec<Message>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(
+        "SELECT topic, payload, received_at FROM messages
+        WHERE device_id = ?1
+        ORDER BY id DESC
+        LIMIT ?2",
+    )?;
+    let messages = stmt
+        .query_map(params![device_id, RECENT_MESSAGES], |row| {
+            Ok(Message {
+                topic: row.get(0)?,
+                payload: row.get(1)?,
+                received_at: timestamps::get(row, 2).ok(),
+            })
+        })?
+        .collect();
+    messages
+}
+
+pub async fn get_device(
+    device_id: String,
+    query: DeviceQuery,
+    pool: SqlitePool,
+    templates: Templates,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let device_id = percent_encoding::percent_decode_str(&device_id)
+        .decode_utf8_lossy()
+        .to_string();
+    let range = query.range;
+    println!("Getting device details for: {}", device_id);
+
+    let device = device_info(&pool, &device_id)
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::NotFound)?;
+    let topic = find_topic_configuration_for_device(&pool, &device_id).map_err(ApiError::from)?;
+    let latest = latest_reading(&pool, &device_id).map_err(ApiError::from)?;
+    let readings = readings(&pool, &device_id, range).map_err(ApiError::from)?;
+    let battery_levels =
+        battery::levels(&get_conn(&pool), &device_id, range.modifier()).map_err(ApiError::from)?;
+    let battery = battery::latest(&pool, &device_id).map_err(ApiError::from)?;
+    let periods =
+        occupancy::periods(&pool, &device_id, range.modifier()).map_err(ApiError::from)?;
+    let illuminance =
+        occupancy::illuminance(&pool, &device_id, range.modifier()).map_err(ApiError::from)?;
+    let heating = thermostat::history(&pool, &device_id, range.modifier()).map_err(ApiError::from)?;
+    let air = air_quality::history(&pool, &device_id, range.modifier()).map_err(ApiError::from)?;
+    let thermostat = thermostat::latest(&pool, &device_id)
+        .map_err(ApiError::from)?
+        .map(|(_, report)| report);
+    let formatter = templates.formatter();
+    let now = Utc::now();
+    let daily = daily_statistics(&readings, formatter);
+    let daily_occupancy = daily_occupancy(&periods, formatter, now);
+    let messages = recent_messages(&pool, &device_id).map_err(ApiError::from)?;
+    let rejected = plausibility::rejected(&pool, Some(&device_id), range.modifier())
+        .map_err(ApiError::from)?;
+
+    let series = |metric: Metric, color| Series {
+        name: metric.as_str(),
+        color,
+        points: readings
+            .iter()
+            .filter_map(|data| {
+                let value = metric.value(data)?;
+                let value = if metric.is_temperature() {
+                    formatter.convert_temperature(value)
+                } else {
+                    value
+                };
+                Some((formatter.local(data.received_at), value))
+            })
+            .collect(),
+    };
+    let temperature_chart = LineChart::new(
+        &format!("Temperature ({})", formatter.temperature_unit()),
+        vec![
+            series(Metric::Temperature, "red"),
+            series(Metric::DewPoint, "teal"),
+            series(Metric::HeatIndex, "orange"),
+        ],
+    );
+    let humidity_chart = LineChart::new("Humidity", vec![series(Metric::Humidity, "blue")]);
+    let absolute_humidity_chart = LineChart::new(
+        "Absolute Humidity",
+        vec![series(Metric::AbsoluteHumidity, "navy")],
+    );
+    let vapor_pressure_deficit_chart = LineChart::new(
+        "Vapor Pressure Deficit",
+        vec![series(Metric::VaporPressureDeficit, "purple")],
+    );
+    let linkquality_chart =
+        LineChart::new("Link Quality", vec![series(Metric::Linkquality, "gray")]);
+    let battery_chart = LineChart::new(
+        "Battery",
+        vec![Series {
+            name: "battery",
+            color: "green",
+            points: battery_levels
+                .into_iter()
+                .map(|(time, value)| (formatter.local(time), value))
+                .collect(),
+        }],
+    );
+    let occupancy_chart = LineChart::new(
+        "Occupancy",
+        vec![Series {
+            name: "occupancy",
+            color: "brown",
+            points: occupancy_points(&periods, formatter, now),
+        }],
+    );
+    let illuminance_chart = LineChart::new(
+        "Illuminance (lx)",
+        vec![Series {
+            name: "illuminance",
+            color: "goldenrod",
+            points: illuminance
+                .into_iter()
+                .map(|(time, value)| (formatter.local(time), value))
+                .collect(),
+        }],
+    );
+    let heating_series =
+        |name, color, value: fn(&thermostat::ThermostatReport) -> Option<f64>, temperature| {
+            Series {
+                name,
+                color,
+                points: heating
+                    .iter()
+                    .filter_map(|(time, report)| {
+                        let value = value(report)?;
+                        let value = if temperature {
+                            formatter.convert_temperature(value)
+                        } else {
+                            value
+                        };
+                        Some((formatter.local(*time), value))
+                    })
+                    .collect(),
+            }
+        };
+    // Valve demand in % on the right axis
+    let heating_chart = LineChart::with_secondary_axis(
+        &format!("Heating ({}, %)", formatter.temperature_unit()),
+        vec![
+            heating_series("local_temperature", "red", |r| r.local_temperature, true),
+            heating_series("setpoint", "blue", |r| r.setpoint, true),
+        ],
+        vec![heating_series("heating_demand", "orange", |r| r.heating_demand, false)],
+    );
+    let air_series = |name, color, value: fn(&AirQualityReport) -> Option<f64>| Series {
+        name,
+        color,
+        points: air
+            .iter()
+            .filter_map(|(time, report)| Some((formatter.local(*time), value(report)?)))
+            .collect(),
+    };
+    let co2_chart = LineChart::new("CO₂ (ppm)", vec![air_series("co2", "black", |r| r.co2)]);
+    let pollutants_chart = LineChart::new(
+        "VOC (ppb), PM2.5 and Formaldehyde (µg/m³)",
+        vec![
+            air_series("voc", "olive", |r| r.voc),
+            air_series("pm25", "maroon", |r| r.pm25),
+            air_series("formaldehyd", "darkcyan", |r| r.formaldehyd),
+        ],
+    );
+
+    let html = templates.render(
+        "device.html",
+        context! {
+            title => topic.as_ref().map(|t| t.title().to_string()).unwrap_or(device_id.clone()),
+            device,
+            topic,
+            latest,
+            battery,
+            thermostat,
+            range => range.as_str(),
+            ranges => Range::ALL.iter().map(Range::as_str).collect::<Vec<_>>(),
+            charts => vec![
+                temperature_chart,
+                humidity_chart,
+                absolute_humidity_chart,
+                vapor_pressure_deficit_chart,
+                linkquality_chart,
+                battery_chart,
+                occupancy_chart,
+                illuminance_chart,
+                heating_chart,
+                co2_chart,
+                pollutants_chart,
+            ],
+            daily,
+            daily_occupancy,
+            messages,
+            rejected,
+        },
+    )?;
+    Ok(warp::reply::html(html))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DisplayConfig;
+    use crate::conn::get_test_pool;
+
+    fn insert_reading(
+        pool: &SqlitePool,
+        device_id: &str,
+        temperature: f64,
+        humidity: i64,
+        received_at: &str,
+    ) {
+        get_conn(pool)
+            .execute(
+                "INSERT INTO sensor_data (temperature, humidity, linkquality, device_id, received_at)
+                VALUES (?1, ?2, 100, ?3, unixepoch('now', ?4) * 1000)",
+                params![temperature, humidity, device_id, received_at],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unknown_device() {
+        let pool = get_test_pool();
+        assert!(device_info(&pool, "kitchen").unwrap().is_none());
+
+        insert_reading(&pool, "kitchen", 20.0, 50, "-1 hour");
+        let info = device_info(&pool, "kitchen").unwrap().unwrap();
+        assert_eq!(info.readings, 1);
+        assert_eq!(info.online, None);
+    }
+
+    /// Days start at midnight UTC, whatever the time zone of the host is
+    fn utc_formatter() -> Formatter {
+        Formatter::new(&DisplayConfig {
+            timezone: Some("UTC".to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_daily_statistics_in_range() {
+        let pool = get_test_pool();
+        insert_reading(&pool, "kitchen", 20.0, 40, "-3 days");
+        insert_reading(&pool, "kitchen", 24.0, 60, "-3 days");
+        insert_reading(&pool, "kitchen", 10.0, 90, "-10 days");
+        insert_reading(&pool, "bathroom", 30.0, 90, "-3 days");
+
+        let formatter = utc_formatter();
+        let week = readings(&pool, "kitchen", Range::Week).unwrap();
+        let week = daily_statistics(&week, &formatter);
+        assert_eq!(week.len(), 1);
+        assert_eq!(week[0].min_temperature, 20.0);
+        assert_eq!(week[0].max_temperature, 24.0);
+        assert_eq!(week[0].avg_temperature, 22.0);
+        assert_eq!(week[0].avg_humidity, 50.0);
+        assert_eq!(week[0].readings, 2);
+
+        let month = readings(&pool, "kitchen", Range::Month).unwrap();
+        assert_eq!(daily_statistics(&month, &formatter).len(), 2);
+        assert_eq!(readings(&pool, "kitchen", Range::Day).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_daily_occupancy_over_midnight() {
+        let formatter = utc_formatter();
+        let time = |value: &str| timestamps::parse(value).unwrap();
+        let period = |start, end: Option<&str>| OccupancyPeriod {
+            device_id: "hall".to_string(),
+            started_at: time(start),
+            ended_at: end.map(&time),
+        };
+        let periods = vec![
+            period("2024-07-01 22:00:00", Some("2024-07-02 01:30:00")),
+            period("2024-07-02 08:00:00", Some("2024-07-02 08:15:00")),
+            period("2024-07-02 23:00:00", None),
+        ];
+
+        let days = daily_occupancy(&periods, &formatter, time("2024-07-03 00:30:00"));
+        let hours: Vec<(&str, f64)> = days.iter().map(|d| (d.day.as_str(), d.hours)).collect();
+        assert_eq!(
+            hours,
+            vec![("2024-07-03", 0.5), ("2024-07-02", 2.8), ("2024-07-01", 2.0)]
+        );
+    }
+
+    #[test]
+    fn test_messages_of_device() {
+        let pool = get_test_pool();
+        let conn = get_conn(&pool);
+        for (topic, payload, device_id) in [
+            (
+                "zigbee2mqtt/kitchen",
+                r#"{"battery": 90, "temperature": 20}"#,
+                Some("kitchen"),
+            ),
+            ("zigbee2mqtt/kitchen", r#"{"temperature": 21}"#, Some("kitchen")),
+            ("esphome/kitchen/sensor/humidity/state", "55", Some("kitchen")),
+            ("zigbee2mqtt/kitchen", "not json", Some("kitchen")),
+            ("zigbee2mqtt/kitchen/set", r#"{"battery": 10}"#, Some("set")),
+            ("zigbee2mqtt/other_kitchen", r#"{"battery": 10}"#, Some("other_kitchen")),
+            ("home/kitchen", "no device id", None),
+        ] {
+            conn.execute(
+                "INSERT INTO messages (topic, payload, received_at, device_id)
+                VALUES (?1, ?2, unixepoch('now') * 1000, ?3)",
+                params![topic, payload, device_id],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        let messages = recent_messages(&pool, "kitchen").unwrap();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].payload, "not json");
+        assert_eq!(messages[1].topic, "esphome/kitchen/sensor/humidity/state");
+    }
+}
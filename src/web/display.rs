@@ -0,0 +1,189 @@
+use crate::config::{DisplayConfig, TemperatureUnit};
+use crate::model::SensorData;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+/// Formats values with the units, precision and time zone of the display configuration.
+/// Values are stored in °C and UTC.
+#[derive(Clone, Debug)]
+pub struct Formatter {
+    unit: TemperatureUnit,
+    precision: usize,
+    datetime_format: String,
+    /// Time zone of the system when missing
+    timezone: Option<Tz>,
+}
+
+/// `?display=true` formats the JSON of readings like the web pages
+#[derive(Deserialize)]
+pub struct DisplayQuery {
+    #[serde(default)]
+    display: bool,
+}
+
+impl DisplayQuery {
+    pub fn enabled(&self) -> bool {
+        self.display
+    }
+}
+
+/// Reading with converted and rounded values, `received_at` stays in ISO 8601
+#[derive(Serialize)]
+pub struct DisplayReading {
+    #[serde(flatten)]
+    data: SensorData,
+    temperature_unit: &'static str,
+    /// `received_at` in the configured time zone and format
+    received_at_local: String,
+}
+
+impl Formatter {
+    pub fn new(config: &DisplayConfig) -> Result<Formatter, String> {
+        let timezone = match &config.timezone {
+            Some(name) => Some(
+                name.parse::<Tz>()
+                    .map_err(|e| format!("Unknown timezone {}: {}", name, e))?,
+            ),
+            None => None,
+        };
+
+        if StrftimeItems::new(&config.datetime_format).any(|item| item == Item::Error) {
+            return Err(format!(
+                "Invalid datetime_format {}",
+                config.datetime_format
+            ));
+        }
+
+        Ok(Formatter {
+            unit: config.temperature_unit,
+            precision: config.precision,
+            datetime_format: config.datetime_format.clone(),
+            timezone,
+        })
+    }
+
+    pub fn precision(&self) -> usize {
+        self.precision
+    }
+
+    pub fn temperature_unit(&self) -> &'static str {
+        match self.unit {
+            TemperatureUnit::Celsius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+        }
+    }
+
+    pub fn convert_temperature(&self, celsius: f64) -> f64 {
+        match self.unit {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    pub fn round(&self, value: f64) -> f64 {
+        let factor = 10f64.powi(self.precision as i32);
+        (value * factor).round() / factor
+    }
+
+    pub fn number(&self, value: f64) -> String {
+        format!("{:.*}", self.precision, value)
+    }
+
+    /// Temperature in °C converted to the configured unit, with the unit
+    pub fn temperature(&self, celsius: f64) -> String {
+        format!(
+            "{} {}",
+            self.number(self.convert_temperature(celsius)),
+            self.temperature_unit()
+        )
+    }
+
+    /// Time in the configured time zone, for the charts
+    pub fn local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        match self.timezone {
+            Some(timezone) => time.with_timezone(&timezone).naive_local(),
+            None => time.with_timezone(&Local).naive_local(),
+        }
+    }
+
+    pub fn datetime(&self, time: DateTime<Utc>) -> String {
+        match self.timezone {
+            Some(timezone) => time
+                .with_timezone(&timezone)
+                .format(&self.datetime_format)
+                .to_string(),
+            None => time
+                .with_timezone(&Local)
+                .format(&self.datetime_format)
+                .to_string(),
+        }
+    }
+
+    pub fn reading(&self, data: &SensorData) -> DisplayReading {
+        let mut converted = data.clone();
+        converted.temperature =
+            self.round(self.convert_temperature(data.temperature as f64)) as f32;
+        converted.derived.dew_point = self.round(self.convert_temperature(data.derived.dew_point));
+        converted.derived.heat_index =
+            self.round(self.convert_temperature(data.derived.heat_index));
+        converted.derived.absolute_humidity = self.round(data.derived.absolute_humidity);
+        converted.derived.vapor_pressure_deficit = self.round(data.derived.vapor_pressure_deficit);
+
+        DisplayReading {
+            data: converted,
+            temperature_unit: self.temperature_unit(),
+            received_at_local: self.datetime(data.received_at),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamps;
+
+    fn formatter(unit: TemperatureUnit, timezone: Option<&str>) -> Formatter {
+        Formatter::new(&DisplayConfig {
+            temperature_unit: unit,
+            precision: 1,
+            datetime_format: "%d.%m.%Y %H:%M".to_string(),
+            timezone: timezone.map(str::to_string),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_temperature() {
+        let celsius = formatter(TemperatureUnit::Celsius, None);
+        assert_eq!(celsius.temperature(21.456), "21.5 °C");
+        assert_eq!(celsius.temperature(21.0), "21.0 °C");
+
+        let fahrenheit = formatter(TemperatureUnit::Fahrenheit, None);
+        assert_eq!(fahrenheit.temperature(100.0), "212.0 °F");
+        assert_eq!(fahrenheit.round(fahrenheit.convert_temperature(21.5)), 70.7);
+    }
+
+    #[test]
+    fn test_datetime_in_timezone() {
+        let helsinki = formatter(TemperatureUnit::Celsius, Some("Europe/Helsinki"));
+        let time = timestamps::parse("2024-07-01 12:00:00").unwrap();
+        assert_eq!(helsinki.datetime(time), "01.07.2024 15:00");
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = DisplayConfig {
+            timezone: Some("Mars/Olympus_Mons".to_string()),
+            ..Default::default()
+        };
+        assert!(Formatter::new(&config).is_err());
+
+        let config = DisplayConfig {
+            datetime_format: "%Y-%".to_string(),
+            ..Default::default()
+        };
+        assert!(Formatter::new(&config).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::config::TariffConfig;
+use crate::conn::SqlitePool;
+use crate::energy;
+use crate::web::chart::{LineChart, Series};
+use crate::web::display::Formatter;
+use crate::web::error::ApiError;
+use crate::web::templates::Templates;
+use crate::web::topics::{device_topic_configurations, find_topic_configuration_for_device};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Length of the periods that the consumption is summed over
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hour,
+    #[default]
+    Day,
+    Month,
+}
+
+impl Period {
+    pub const ALL: [Period; 3] = [Period::Hour, Period::Day, Period::Month];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Hour => "hour",
+            Period::Day => "day",
+            Period::Month => "month",
+        }
+    }
+
+    /// SQLite datetime modifier for the start of the report
+    fn modifier(&self) -> &'static str {
+        match self {
+            Period::Hour => "-2 days",
+            Period::Day => "-31 days",
+            Period::Month => "-365 days",
+        }
+    }
+
+    /// Local period that the time belongs to
+    fn label(&self, time: NaiveDateTime) -> String {
+        let format = match self {
+            Period::Hour => "%Y-%m-%d %H:00",
+            Period::Day => "%Y-%m-%d",
+            Period::Month => "%Y-%m",
+        };
+        time.format(format).to_string()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EnergyQuery {
+    #[serde(default)]
+    period: Period,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Consumption {
+    period: String,
+    /// kWh
+    energy: f64,
+    /// None without a tariff
+    cost: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct DeviceEnergy {
+    device_id: String,
+    title: String,
+    /// W
+    power: Option<f64>,
+    received_at: Option<DateTime<Utc>>,
+    today: Option<Consumption>,
+    this_month: Option<Consumption>,
+}
+
+/// Consumption of each local period, newest first.
+/// Every kWh costs the price at the local time it was counted.
+fn consumption_by_period(
+    consumption: &[(DateTime<Utc>, f64)],
+    period: Period,
+    formatter: &Formatter,
+    tariff: Option<&TariffConfig>,
+) -> Vec<Consumption> {
+    let mut periods: BTreeMap<String, (f64, f64)> = BTreeMap::new();
+    for (time, used) in consumption {
+        let local = formatter.local(*time);
+        let (energy, cost) = periods.entry(period.label(local)).or_default();
+        *energy += used;
+        *cost += used * tariff.map_or(0.0, |tariff| tariff.price_at(local.time()));
+    }
+
+    periods
+        .into_iter()
+        .rev()
+        .map(|(period, (energy, cost))| Consumption {
+            period,
+            energy: (energy * 1000.0).round() / 1000.0,
+            cost: tariff.map(|_| (cost * 100.0).round() / 100.0),
+        })
+        .collect()
+}
+
+/// Every power monitoring plug with its consumption today and this month
+pub async fn get_energy_overview(
+    pool: SqlitePool,
+    templates: Templates,
+    tariff: Option<TariffConfig>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Getting energy overview");
+    let topics = device_topic_configurations(&pool).map_err(ApiError::from)?;
+    let formatter = templates.formatter();
+    let now = formatter.local(Utc::now());
+
+    let mut devices = Vec::new();
+    for device_id in energy::devices(&pool).map_err(ApiError::from)? {
+        let latest = energy::latest(&pool, &device_id).map_err(ApiError::from)?;
+        let counters =
+            energy::counters(&pool, &device_id, Period::Day.modifier()).map_err(ApiError::from)?;
+        let consumption = energy::consumption(&counters);
+        let current = |period: Period| {
+            consumption_by_period(&consumption, period, formatter, tariff.as_ref())
+                .into_iter()
+                .find(|c| c.period == period.label(now))
+        };
+
+        devices.push(DeviceEnergy {
+            title: topics
+                .iter()
+                .find(|t| t.topic_name == device_id)
+                .map_or(device_id.clone(), |t| t.title().to_string()),
+            power: latest.as_ref().and_then(|(report, _)| report.power),
+            received_at: latest.map(|(_, received_at)| received_at),
+            today: current(Period::Day),
+            this_month: current(Period::Month),
+            device_id,
+        });
+    }
+
+    let currency = tariff.map(|tariff| tariff.currency);
+    let html = templates.render("energy.html", context! { devices, currency })?;
+    Ok(warp::reply::html(html))
+}
+
+/// Consumption report of one plug
+pub async fn get_device_energy(
+    device_id: String,
+    query: EnergyQuery,
+    pool: SqlitePool,
+    templates: Templates,
+    tariff: Option<TariffConfig>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let device_id = percent_encoding::percent_decode_str(&device_id)
+        .decode_utf8_lossy()
+        .to_string();
+    let period = query.period;
+    println!("Getting energy report for: {}", device_id);
+
+    let latest = energy::latest(&pool, &device_id)
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::NotFound)?;
+    let topic = find_topic_configuration_for_device(&pool, &device_id).map_err(ApiError::from)?;
+    let counters =
+        energy::counters(&pool, &device_id, period.modifier()).map_err(ApiError::from)?;
+    let formatter = templates.formatter();
+    let consumption = consumption_by_period(
+        &energy::consumption(&counters),
+        period,
+        formatter,
+        tariff.as_ref(),
+    );
+    let power = energy::power(&pool, &device_id, period.modifier()).map_err(ApiError::from)?;
+    let power_chart = LineChart::new(
+        "Power (W)",
+        vec![Series {
+            name: "power",
+            color: "darkorange",
+            points: power
+                .into_iter()
+                .map(|(time, value)| (formatter.local(time), value))
+                .collect(),
+        }],
+    );
+
+    let html = templates.render(
+        "energy_device.html",
+        context! {
+            title => topic.as_ref().map(|t| t.title().to_string()).unwrap_or(device_id.clone()),
+            device_id,
+            latest => latest.0,
+            received_at => latest.1,
+            period => period.as_str(),
+            periods => Period::ALL.iter().map(Period::as_str).collect::<Vec<_>>(),
+            consumption,
+            total => consumption.iter().map(|c| c.energy).sum::<f64>(),
+            currency => tariff.map(|tariff| tariff.currency),
+            power_chart,
+        },
+    )?;
+    Ok(warp::reply::html(html))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DisplayConfig, TariffPeriod};
+
+    #[test]
+    fn test_time_of_use_cost() {
+        let formatter = Formatter::new(&DisplayConfig {
+            timezone: Some("UTC".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let tariff = TariffConfig {
+            currency: "€".to_string(),
+            price: 0.2,
+            periods: vec![TariffPeriod {
+                start: "22:00".parse().unwrap(),
+                end: "07:00".parse().unwrap(),
+                price: 0.1,
+            }],
+        };
+        let time = |value| crate::timestamps::parse(value).unwrap();
+        let consumption = vec![
+            (time("2024-07-01 06:30:00"), 1.0),
+            (time("2024-07-01 12:00:00"), 2.0),
+            (time("2024-07-01 23:00:00"), 1.5),
+            (time("2024-07-02 08:00:00"), 0.5),
+        ];
+
+        let days = consumption_by_period(&consumption, Period::Day, &formatter, Some(&tariff));
+        assert_eq!(
+            days,
+            vec![
+                Consumption {
+                    period: "2024-07-02".to_string(),
+                    energy: 0.5,
+                    cost: Some(0.1),
+                },
+                Consumption {
+                    period: "2024-07-01".to_string(),
+                    energy: 4.5,
+                    cost: Some(0.65),
+                },
+            ]
+        );
+
+        let months = consumption_by_period(&consumption, Period::Month, &formatter, None);
+        assert_eq!(months.len(), 1);
+        assert_eq!(months[0].energy, 5.0);
+        assert_eq!(months[0].cost, None);
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::convert::Infallible;
+use warp::http::StatusCode;
+use warp::reject::{MethodNotAllowed, PayloadTooLarge, UnsupportedMediaType};
+use warp::{Rejection, Reply};
+
+/// Errors of the web module, turned into JSON responses by `handle_rejection`
+#[derive(Debug)]
+pub enum ApiError {
+    /// Reading or writing the database failed, details are only logged
+    Database(rusqlite::Error),
+    /// No database connection was free in time, details are only logged
+    DatabaseUnavailable(r2d2::Error),
+    /// Rendering a page failed, details are only logged
+    Template(minijinja::Error),
+    /// The MQTT client could not send a request, details are only logged
+    Mqtt(rumqttc::ClientError),
+    BadRequest(String),
+    NotFound,
+    Unauthorized,
+    Forbidden,
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        ApiError::Database(e)
+    }
+}
+
+impl From<r2d2::Error> for ApiError {
+    fn from(e: r2d2::Error) -> Self {
+        ApiError::DatabaseUnavailable(e)
+    }
+}
+
+impl From<minijinja::Error> for ApiError {
+    fn from(e: minijinja::Error) -> Self {
+        ApiError::Template(e)
+    }
+}
+
+impl From<rumqttc::ClientError> for ApiError {
+    fn from(e: rumqttc::ClientError) -> Self {
+        ApiError::Mqtt(e)
+    }
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::DatabaseUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Template(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Mqtt(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::Database(_) => "database_error",
+            ApiError::DatabaseUnavailable(_) => "database_unavailable",
+            ApiError::Template(_) => "template_error",
+            ApiError::Mqtt(_) => "mqtt_error",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound => "not_found",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden => "forbidden",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::Database(_) => "Failed to read or write the database".to_string(),
+            ApiError::DatabaseUnavailable(_) => "The database is busy, try again".to_string(),
+            ApiError::Template(_) => "Failed to render the page".to_string(),
+            ApiError::Mqtt(_) => "Failed to send the MQTT request".to_string(),
+            ApiError::BadRequest(message) => message.clone(),
+            ApiError::NotFound => "Not found".to_string(),
+            ApiError::Unauthorized => "Missing or invalid credentials".to_string(),
+            ApiError::Forbidden => "Not allowed with the scope of the credentials".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: &'static str,
+    message: String,
+    /// Matches the log line of the error
+    request_id: String,
+}
+
+/// Turn every rejection into a JSON error with a request id that is also logged
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let request_id = format!("{:016x}", rand::random::<u64>());
+
+    let (status, code, message) = if let Some(e) = err.find::<ApiError>() {
+        match e {
+            ApiError::Database(source) => println!("{} - Database error: {:?}", request_id, source),
+            ApiError::DatabaseUnavailable(source) => {
+                println!("{} - Database unavailable: {}", request_id, source)
+            }
+            ApiError::Template(source) => println!("{} - Template error: {:#}", request_id, source),
+            ApiError::Mqtt(source) => println!("{} - MQTT error: {:?}", request_id, source),
+            _ => {}
+        }
+        (e.status(), e.code(), e.message())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "Not found".to_string())
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "invalid_query", e.to_string())
+    } else if err.find::<UnsupportedMediaType>().is_some() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "Expected a JSON body".to_string(),
+        )
+    } else if err.find::<PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "Request body is too large".to_string())
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed".to_string())
+    } else {
+        println!("{} - Unhandled rejection: {:?}", request_id, err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error".to_string())
+    };
+
+    let body = warp::reply::json(&ErrorResponse {
+        code,
+        message,
+        request_id: request_id.clone(),
+    });
+    let mut response = warp::reply::with_status(body, status).into_response();
+    let headers = response.headers_mut();
+    headers.insert("x-request-id", request_id.parse().unwrap());
+    if status == StatusCode::UNAUTHORIZED {
+        headers.insert("www-authenticate", "Basic realm=\"ru-berry\"".parse().unwrap());
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use warp::Filter;
+
+    fn body_json(body: &[u8]) -> Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_database_error() {
+        let route = warp::path("broken")
+            .and_then(|| async {
+                Err::<String, Rejection>(
+                    ApiError::Database(rusqlite::Error::InvalidQuery).into(),
+                )
+            })
+            .recover(handle_rejection);
+
+        let response = warp::test::request().path("/broken").reply(&route).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = body_json(response.body());
+        assert_eq!(body["code"], "database_error");
+        // The rusqlite error is only logged
+        assert!(!body["message"].as_str().unwrap().contains("InvalidQuery"));
+        assert_eq!(
+            body["request_id"].as_str().unwrap(),
+            response.headers()["x-request-id"].to_str().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warp_rejections() {
+        let route = warp::path("things")
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(|_: Value| "ok")
+            .recover(handle_rejection);
+
+        let response = warp::test::request().path("/missing").reply(&route).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_json(response.body())["code"], "not_found");
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/things")
+            .header("content-type", "application/json")
+            .body("{not json")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_json(response.body())["code"], "invalid_body");
+
+        let response = warp::test::request().path("/things").reply(&route).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
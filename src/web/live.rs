@@ -0,0 +1,42 @@
+use crate::events::{EventSender, LiveEvent};
+use crate::web::display::{DisplayQuery, DisplayReading, Formatter};
+use serde::Serialize;
+use std::convert::Infallible;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use warp::sse::Event;
+
+/// Reading event with the values formatted like the web pages
+#[derive(Serialize)]
+struct DisplayReadingEvent {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(flatten)]
+    reading: DisplayReading,
+}
+
+/// Stream live events to the browser as server-sent events
+pub async fn get_live_events(
+    query: DisplayQuery,
+    events: EventSender,
+    formatter: Formatter,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let display = query.enabled();
+    let stream = BroadcastStream::new(events.subscribe()).filter_map(move |event| match event {
+        Ok(event) => {
+            let sse = Event::default().event(event.name());
+            let sse = match &event {
+                LiveEvent::Reading(data) if display => sse.json_data(DisplayReadingEvent {
+                    kind: event.name(),
+                    reading: formatter.reading(data),
+                }),
+                _ => sse.json_data(&event),
+            };
+            sse.ok().map(Ok::<_, Infallible>)
+        }
+        // The browser fell behind, it will catch up on the next events
+        Err(_) => None,
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
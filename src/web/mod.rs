@@ -1,2 +1,19 @@
+mod actions;
+mod admin;
+pub(crate) mod auth;
+mod battery;
+mod chart;
+mod contact;
+mod critical;
+mod device;
+pub(crate) mod display;
+mod energy;
+mod error;
+mod live;
+mod network;
+mod plausibility;
 pub(crate) mod ru_berry_web;
-mod status;
\ No newline at end of file
+mod status;
+mod templates;
+mod tls;
+mod topics;
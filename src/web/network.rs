@@ -0,0 +1,258 @@
+use crate::conn::SqlitePool;
+use crate::network::{self, LinkqualityTrend, NetworkMap};
+use crate::web::chart::{LineChart, Series};
+use crate::web::error::ApiError;
+use crate::web::templates::Templates;
+use chrono::Utc;
+use minijinja::context;
+use rumqttc::AsyncClient;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+const SIZE: f64 = 800.0;
+const ROUTER_RADIUS: f64 = 170.0;
+const END_DEVICE_RADIUS: f64 = 340.0;
+
+/// Network map drawn as SVG by `network.html`, routers on the inner ring
+/// and end devices on the outer ring next to their parent
+#[derive(Serialize)]
+struct NetworkMapView {
+    size: f64,
+    nodes: Vec<NodeView>,
+    links: Vec<LinkView>,
+}
+
+#[derive(Serialize)]
+struct NodeView {
+    name: String,
+    kind: String,
+    x: f64,
+    y: f64,
+}
+
+#[derive(Serialize)]
+struct LinkView {
+    x1: f64,
+    y1: f64,
+    x2: f64,
+    y2: f64,
+    linkquality: i64,
+    /// `good`, `fair` or `poor`
+    quality: &'static str,
+}
+
+fn quality(linkquality: i64) -> &'static str {
+    match linkquality {
+        100.. => "good",
+        50..=99 => "fair",
+        _ => "poor",
+    }
+}
+
+fn on_circle(radius: f64, index: usize, count: usize) -> (f64, f64) {
+    let angle = 2.0 * PI * index as f64 / count.max(1) as f64 - PI / 2.0;
+    (
+        SIZE / 2.0 + radius * angle.cos(),
+        SIZE / 2.0 + radius * angle.sin(),
+    )
+}
+
+impl NetworkMapView {
+    fn new(map: &NetworkMap) -> NetworkMapView {
+        // Both ends of a link can report it, the better one is drawn
+        let mut links: HashMap<(&str, &str), i64> = HashMap::new();
+        for link in &map.links {
+            let (a, b) = (
+                link.source_ieee_addr.as_str(),
+                link.target_ieee_addr.as_str(),
+            );
+            let key = if a < b { (a, b) } else { (b, a) };
+            let linkquality = links.entry(key).or_default();
+            *linkquality = (*linkquality).max(link.linkquality);
+        }
+
+        let mut routers: Vec<_> = map.nodes.iter().filter(|n| n.kind == "Router").collect();
+        routers.sort_by(|a, b| a.friendly_name.cmp(&b.friendly_name));
+        let router_index = |ieee_addr: &str| routers.iter().position(|r| r.ieee_addr == ieee_addr);
+
+        // End devices are sorted by the router with the best link to them
+        let mut end_devices: Vec<_> = map
+            .nodes
+            .iter()
+            .filter(|n| n.kind != "Router" && n.kind != "Coordinator")
+            .map(|node| {
+                let parent = links
+                    .iter()
+                    .filter(|((a, b), _)| *a == node.ieee_addr || *b == node.ieee_addr)
+                    .filter_map(|((a, b), lq)| {
+                        let other = if *a == node.ieee_addr { b } else { a };
+                        router_index(other).map(|index| (index, *lq))
+                    })
+                    .max_by_key(|(_, lq)| *lq)
+                    .map(|(index, _)| index);
+                (parent, node)
+            })
+            .collect();
+        end_devices.sort_by(|(a, a_node), (b, b_node)| {
+            a.cmp(b)
+                .then(a_node.friendly_name.cmp(&b_node.friendly_name))
+        });
+
+        let mut placed: Vec<(&network::Node, (f64, f64))> = map
+            .nodes
+            .iter()
+            .filter(|n| n.kind == "Coordinator")
+            .map(|node| (node, (SIZE / 2.0, SIZE / 2.0)))
+            .collect();
+        for (index, node) in routers.iter().enumerate() {
+            placed.push((node, on_circle(ROUTER_RADIUS, index, routers.len())));
+        }
+        for (index, (_, node)) in end_devices.iter().enumerate() {
+            placed.push((node, on_circle(END_DEVICE_RADIUS, index, end_devices.len())));
+        }
+        let positions: HashMap<&str, (f64, f64)> = placed
+            .iter()
+            .map(|(node, position)| (node.ieee_addr.as_str(), *position))
+            .collect();
+        let nodes = placed
+            .iter()
+            .map(|(node, (x, y))| NodeView {
+                name: node.friendly_name.clone(),
+                kind: node.kind.clone(),
+                x: *x,
+                y: *y,
+            })
+            .collect();
+
+        let mut links: Vec<LinkView> = links
+            .into_iter()
+            .filter_map(|((a, b), linkquality)| {
+                let (x1, y1) = *positions.get(a)?;
+                let (x2, y2) = *positions.get(b)?;
+                Some(LinkView {
+                    x1,
+                    y1,
+                    x2,
+                    y2,
+                    linkquality,
+                    quality: quality(linkquality),
+                })
+            })
+            .collect();
+        // Poor links are drawn last so they are not hidden
+        links.sort_by_key(|link| std::cmp::Reverse(link.linkquality));
+
+        NetworkMapView {
+            size: SIZE,
+            nodes,
+            links,
+        }
+    }
+}
+
+pub async fn get_network(
+    pool: SqlitePool,
+    templates: Templates,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Getting network health");
+    let history = network::linkquality_history(&pool).map_err(ApiError::from)?;
+    let now = Utc::now();
+
+    let mut trends: Vec<LinkqualityTrend> = history
+        .iter()
+        .filter_map(|(device_id, history)| LinkqualityTrend::new(device_id, history, now))
+        .collect();
+    trends.sort_by_key(|trend| (!trend.deteriorating, trend.latest));
+
+    let formatter = templates.formatter();
+    let charts: Vec<LineChart> = trends
+        .iter()
+        .map(|trend| {
+            LineChart::new(
+                &trend.device_id,
+                vec![Series {
+                    name: "linkquality",
+                    color: "gray",
+                    points: history[&trend.device_id]
+                        .iter()
+                        .map(|(time, lq)| (formatter.local(*time), *lq as f64))
+                        .collect(),
+                }],
+            )
+        })
+        .collect();
+
+    let (map, map_received_at) = match network::latest(&pool).map_err(ApiError::from)? {
+        Some((map, received_at)) => (Some(NetworkMapView::new(&map)), Some(received_at)),
+        None => (None, None),
+    };
+
+    let html = templates.render(
+        "network.html",
+        context! { trends, charts, map, map_received_at },
+    )?;
+    Ok(warp::reply::html(html))
+}
+
+/// The map is stored when zigbee2mqtt responds, which can take a minute on large networks
+pub async fn request_network_map(
+    client: AsyncClient,
+    base_topic: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Requesting the network map");
+    network::request_map(&client, &base_topic).map_err(ApiError::from)?;
+    Ok(warp::redirect::see_other(warp::http::Uri::from_static(
+        "/network",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{Link, Node};
+
+    fn node(ieee_addr: &str, name: &str, kind: &str) -> Node {
+        Node {
+            ieee_addr: ieee_addr.to_string(),
+            friendly_name: name.to_string(),
+            kind: kind.to_string(),
+        }
+    }
+
+    fn link(source: &str, target: &str, linkquality: i64) -> Link {
+        Link {
+            source_ieee_addr: source.to_string(),
+            target_ieee_addr: target.to_string(),
+            linkquality,
+        }
+    }
+
+    #[test]
+    fn test_network_map_layout() {
+        let map = NetworkMap {
+            nodes: vec![
+                node("0x00", "Coordinator", "Coordinator"),
+                node("0x01", "plug", "Router"),
+                node("0x02", "kitchen", "EndDevice"),
+            ],
+            links: vec![
+                link("0x01", "0x00", 200),
+                link("0x00", "0x01", 180),
+                link("0x02", "0x01", 40),
+                link("0x02", "0x99", 100),
+            ],
+        };
+
+        let view = NetworkMapView::new(&map);
+        assert_eq!(view.nodes.len(), 3);
+        assert_eq!((view.nodes[0].x, view.nodes[0].y), (400.0, 400.0));
+        // Straight above the coordinator
+        assert_eq!((view.nodes[1].x.round(), view.nodes[1].y), (400.0, 230.0));
+
+        // Links to unknown nodes are left out, the best of both directions is kept
+        assert_eq!(view.links.len(), 2);
+        assert_eq!(view.links[0].linkquality, 200);
+        assert_eq!(view.links[1].quality, "poor");
+    }
+}
@@ -0,0 +1,24 @@
+use crate::conn::SqlitePool;
+use crate::plausibility;
+use crate::web::device::Range;
+use crate::web::error::ApiError;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct RejectedReadingsQuery {
+    /// Every device when missing
+    device_id: Option<String>,
+    #[serde(default)]
+    range: Range,
+}
+
+/// Values that the plausibility rules rejected, latest first
+pub async fn get_rejected_readings(
+    query: RejectedReadingsQuery,
+    pool: SqlitePool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    println!("Getting rejected readings");
+    let rejected = plausibility::rejected(&pool, query.device_id.as_deref(), query.range.modifier())
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&rejected))
+}
@@ -1,72 +1,301 @@
-use crate::config::Config;
+use crate::config::{Config, MouldConfig};
 use crate::conn::{get_conn, SqlitePool};
+use crate::events::EventSender;
+use crate::ingest::IngestMetrics;
 use crate::model::SensorData;
+use rumqttc::AsyncClient;
 use rusqlite::Result;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use warp::Filter;
+use crate::web::actions::{get_action_events, ActionEventsQuery};
+use crate::web::admin::get_topic_admin;
+use crate::web::auth::{
+    create_api_token, delete_api_token, list_api_tokens, with_auth, Authenticator, Scope,
+};
+use crate::web::battery::get_battery_overview;
+use crate::web::contact::{get_contact_events, ContactEventsQuery};
+use crate::web::critical::{acknowledge_critical_alarm, get_critical_alarms};
+use crate::web::device::{get_device, DeviceQuery};
+use crate::web::display::{DisplayQuery, Formatter};
+use crate::web::energy::{get_device_energy, get_energy_overview, EnergyQuery};
+use crate::web::error::{handle_rejection, ApiError};
+use crate::web::live::get_live_events;
+use crate::web::network::{get_network, request_network_map};
+use crate::web::plausibility::{get_rejected_readings, RejectedReadingsQuery};
 use crate::web::status::get_sensor_data_status;
+use crate::web::templates::Templates;
+use crate::web::tls;
+use crate::web::topics::{
+    create_topic_configuration, delete_topic_configuration, get_topic_configurations,
+    put_topic_configuration, reorder_topic_configurations,
+};
 
-async fn get_sensor_data(pool: SqlitePool) -> Result<impl warp::Reply, warp::Rejection> {
+async fn get_sensor_data(
+    query: DisplayQuery,
+    pool: SqlitePool,
+    formatter: Formatter,
+) -> Result<impl warp::Reply, warp::Rejection> {
     let conn = get_conn(&pool);
     println!("Getting sensor data");
 
-    let mut stmt = match conn.prepare(
-        "
+    let mut stmt = conn
+        .prepare(
+            "
         SELECT * FROM sensor_data
-        WHERE received_at >= datetime('now', '-3 days')
+        WHERE received_at >= unixepoch('now', '-3 days') * 1000
         order by device_id, received_at
         "
-    ) {
-        Ok(stmt) => stmt,
-        Err(_) => return Err(warp::reject::custom(MyError::QueryPreparationError)),
-    };
-
-    let sensor_data_iter = match stmt.query_map([], |row| SensorData::from_row(row)) {
-        Ok(iter) => iter,
-        Err(_) => return Err(warp::reject::custom(MyError::QueryExecutionError)),
-    };
-
-    let mut sensor_data_vec = Vec::new();
-    for sensor_data in sensor_data_iter {
-        match sensor_data {
-            Ok(data) => sensor_data_vec.push(data),
-            Err(_) => return Err(warp::reject::custom(MyError::DataMappingError)),
-        }
-    }
+        )
+        .map_err(ApiError::from)?;
+
+    let sensor_data_vec = stmt
+        .query_map([], SensorData::from_row)
+        .and_then(|rows| rows.collect::<Result<Vec<_>>>())
+        .map_err(ApiError::from)?;
 
+    if query.enabled() {
+        let readings: Vec<_> = sensor_data_vec.iter().map(|data| formatter.reading(data)).collect();
+        return Ok(warp::reply::json(&readings));
+    }
     Ok(warp::reply::json(&sensor_data_vec))
 }
 
-#[derive(Debug)]
-pub enum MyError {
-    QueryPreparationError,
-    QueryExecutionError,
-    DataMappingError,
+/// Counters of the queue between the MQTT client and the database writer
+async fn get_ingest_stats(metrics: Arc<IngestMetrics>) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&metrics.stats()))
 }
 
-impl warp::reject::Reject for MyError {}
-
-pub async fn start_web_server(config: &Config, pool: &SqlitePool) {
+pub async fn start_web_server(
+    config: &Config,
+    pool: &SqlitePool,
+    events: &EventSender,
+    mqtt_client: &AsyncClient,
+    ingest_metrics: &Arc<IngestMetrics>,
+) {
     let ip = config.web_server_ip.clone();
     let port = config.web_server_port;
     println!("Starting web server on {}:{}", ip, port);
 
+    let auth = config.auth.clone().map(|auth| Arc::new(Authenticator::new(auth)));
+    if auth.is_none() {
+        println!("Authentication is not configured, web server is open to everyone");
+    }
+
+    let formatter = Formatter::new(&config.display).expect("Invalid display configuration");
+    let templates = Templates::load(config.templates_dir.as_deref(), formatter.clone())
+        .expect("Failed to load templates");
+
     let sensor_data_route = warp::path("sensor_data")
         .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(warp::query::<DisplayQuery>())
         .and(with_db(pool.clone()))
+        .and(with_formatter(formatter.clone()))
         .and_then(get_sensor_data);
 
     let sensor_data_status_route = warp::path("sensor_data_status")
         .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
         .and(with_db(pool.clone()))
+        .and(with_templates(templates.clone()))
+        .and(with_mould(config.mould.clone()))
+        .and(with_value(config.air_quality.clone()))
         .and_then(get_sensor_data_status);
 
-    let routes = sensor_data_route.or(sensor_data_status_route);
+    let device_route = warp::path!("device" / String)
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(warp::query::<DeviceQuery>())
+        .and(with_db(pool.clone()))
+        .and(with_templates(templates.clone()))
+        .and_then(get_device);
+
+    let battery_route = warp::path!("battery")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(with_db(pool.clone()))
+        .and(with_templates(templates.clone()))
+        .and_then(get_battery_overview);
+
+    let contact_events_route = warp::path!("contact_events")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(warp::query::<ContactEventsQuery>())
+        .and(with_db(pool.clone()))
+        .and_then(get_contact_events);
+
+    let action_events_route = warp::path!("action_events")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(warp::query::<ActionEventsQuery>())
+        .and(with_db(pool.clone()))
+        .and_then(get_action_events);
+
+    let rejected_readings_route = warp::path!("rejected_readings")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(warp::query::<RejectedReadingsQuery>())
+        .and(with_db(pool.clone()))
+        .and_then(get_rejected_readings);
+
+    let critical_alarms_route = warp::path!("critical_alarms")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(with_db(pool.clone()))
+        .and_then(get_critical_alarms);
+
+    let acknowledge_critical_alarm_route = warp::path!("critical_alarms" / i64 / "acknowledge")
+        .and(warp::post())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(with_db(pool.clone()))
+        .and(with_events(events.clone()))
+        .and_then(acknowledge_critical_alarm);
+
+    let energy_route = warp::path!("energy")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(with_db(pool.clone()))
+        .and(with_templates(templates.clone()))
+        .and(with_value(config.tariff.clone()))
+        .and_then(get_energy_overview);
+
+    let device_energy_route = warp::path!("energy" / String)
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(warp::query::<EnergyQuery>())
+        .and(with_db(pool.clone()))
+        .and(with_templates(templates.clone()))
+        .and(with_value(config.tariff.clone()))
+        .and_then(get_device_energy);
+
+    let network_route = warp::path!("network")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(with_db(pool.clone()))
+        .and(with_templates(templates.clone()))
+        .and_then(get_network);
+
+    let network_map_request_route = warp::path!("network" / "map")
+        .and(warp::post())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(with_mqtt(mqtt_client.clone()))
+        .and(with_value(config.zigbee2mqtt_topic.clone()))
+        .and_then(request_network_map);
+
+    let ingest_route = warp::path!("ingest")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(with_value(ingest_metrics.clone()))
+        .and_then(get_ingest_stats);
+
+    let live_events_route = warp::path("events")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Read))
+        .and(warp::query::<DisplayQuery>())
+        .and(with_events(events.clone()))
+        .and(with_formatter(formatter.clone()))
+        .and_then(get_live_events);
+
+    let list_api_tokens_route = warp::path!("api_tokens")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(with_db(pool.clone()))
+        .and_then(list_api_tokens);
+
+    let create_api_token_route = warp::path!("api_tokens")
+        .and(warp::post())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(with_db(pool.clone()))
+        .and_then(create_api_token);
+
+    let delete_api_token_route = warp::path!("api_tokens" / i64)
+        .and(warp::delete())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(with_db(pool.clone()))
+        .and_then(delete_api_token);
+
+    let list_topic_configurations_route = warp::path!("topic_configuration")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(with_db(pool.clone()))
+        .and_then(get_topic_configurations);
+
+    let create_topic_configuration_route = warp::path!("topic_configuration")
+        .and(warp::post())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(with_db(pool.clone()))
+        .and_then(create_topic_configuration);
+
+    let reorder_topic_configurations_route = warp::path!("topic_configuration" / "order")
+        .and(warp::put())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(with_db(pool.clone()))
+        .and_then(reorder_topic_configurations);
+
+    let update_topic_configuration_route = warp::path!("topic_configuration" / i64)
+        .and(warp::put())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(warp::body::json())
+        .and(with_db(pool.clone()))
+        .and_then(put_topic_configuration);
+
+    let delete_topic_configuration_route = warp::path!("topic_configuration" / i64)
+        .and(warp::delete())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and(with_db(pool.clone()))
+        .and_then(delete_topic_configuration);
+
+    let topic_admin_route = warp::path!("admin" / "topics")
+        .and(warp::get())
+        .and(with_auth(auth.clone(), pool.clone(), Scope::Admin))
+        .and_then(get_topic_admin);
+
+    let routes = sensor_data_route
+        .or(sensor_data_status_route)
+        .or(device_route)
+        .or(battery_route)
+        .or(contact_events_route)
+        .or(action_events_route)
+        .or(rejected_readings_route)
+        .or(critical_alarms_route)
+        .or(acknowledge_critical_alarm_route)
+        .or(energy_route)
+        .or(device_energy_route)
+        .or(network_route)
+        .or(network_map_request_route)
+        .or(ingest_route)
+        .or(live_events_route)
+        .or(list_api_tokens_route)
+        .or(create_api_token_route)
+        .or(delete_api_token_route)
+        .or(list_topic_configurations_route)
+        .or(create_topic_configuration_route)
+        .or(reorder_topic_configurations_route)
+        .or(update_topic_configuration_route)
+        .or(delete_topic_configuration_route)
+        .or(topic_admin_route)
+        .recover(handle_rejection);
 
     let addr: SocketAddr = format!("{}:{}", ip, port)
         .parse()
         .expect("Invalid IP address or port");
-    warp::serve(routes).run(addr).await;
+
+    match &config.tls {
+        None => warp::serve(routes).run(addr).await,
+        Some(tls_config) => {
+            if let Some(http_port) = tls_config.redirect_http_port {
+                tokio::spawn(tls::redirect_http_to_https(ip.clone(), http_port, port));
+            }
+
+            println!("Serving HTTPS with certificate {}", tls_config.cert_path);
+            let incoming = tls::incoming(addr, tls_config).await;
+            warp::serve(routes).run_incoming(incoming).await;
+        }
+    }
 }
 
 fn with_db(
@@ -74,3 +303,39 @@ fn with_db(
 ) -> impl Filter<Extract = (SqlitePool,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || pool.clone())
 }
+
+fn with_events(
+    events: EventSender,
+) -> impl Filter<Extract = (EventSender,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || events.clone())
+}
+
+fn with_formatter(
+    formatter: Formatter,
+) -> impl Filter<Extract = (Formatter,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || formatter.clone())
+}
+
+fn with_templates(
+    templates: Templates,
+) -> impl Filter<Extract = (Templates,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || templates.clone())
+}
+
+fn with_mqtt(
+    client: AsyncClient,
+) -> impl Filter<Extract = (AsyncClient,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || client.clone())
+}
+
+fn with_value<T: Clone + Send>(
+    value: T,
+) -> impl Filter<Extract = (T,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || value.clone())
+}
+
+fn with_mould(
+    mould: Option<MouldConfig>,
+) -> impl Filter<Extract = (Option<MouldConfig>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || mould.clone())
+}
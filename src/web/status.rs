@@ -1,59 +1,133 @@
+use crate::air_quality::{self, AirQualityAssessment};
+use crate::alerts::{active_alerts, outside_limits, Metric};
+use crate::config::{AirQualityConfig, MouldConfig};
 use crate::conn::{get_conn, SqlitePool};
-use crate::model::SensorData;
-use std::collections::HashMap;
+use crate::contact::{self, ContactEvent};
+use crate::critical;
+use crate::devices::is_online;
+use crate::model::{SensorData, StatusType, TopicConfiguration};
+use crate::mould::{self, MouldAssessment};
+use crate::web::display::Formatter;
+use crate::web::error::ApiError;
+use crate::web::templates::Templates;
+use crate::web::topics::device_topic_configurations;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use minijinja::context;
+use rusqlite::OptionalExtension;
+use serde::Serialize;
 
-enum StatusType {
-    Basic,
-    Boolean,
-    None,
+/// A topic on the status page, the fields depend on the status type
+#[derive(Serialize)]
+#[serde(tag = "status_type", rename_all = "lowercase")]
+enum DeviceView {
+    Basic {
+        device_id: String,
+        title: String,
+        online: Option<bool>,
+        readings: Vec<ReadingView>,
+    },
+    Boolean {
+        device_id: String,
+        title: String,
+        online: Option<bool>,
+        latest: Option<SensorData>,
+        limit_hit: bool,
+        metric: &'static str,
+        /// Formatted with the unit
+        value: Option<String>,
+        limits: String,
+    },
+    Mould {
+        device_id: String,
+        title: String,
+        online: Option<bool>,
+        /// None when there is no recent reading or no surface temperature
+        assessment: Option<MouldAssessment>,
+        configured: bool,
+    },
+    Contact {
+        device_id: String,
+        title: String,
+        online: Option<bool>,
+        /// None before the first open or close
+        latest: Option<ContactEvent>,
+        open_minutes: Option<i64>,
+        /// Local days, today first
+        daily: Vec<DailyOpenings>,
+    },
+    #[serde(rename = "air_quality")]
+    AirQuality {
+        device_id: String,
+        title: String,
+        online: Option<bool>,
+        received_at: Option<DateTime<Utc>>,
+        /// None before the first report
+        assessment: Option<AirQualityAssessment>,
+    },
 }
 
-pub async fn get_sensor_data_status(pool: SqlitePool) -> Result<impl warp::Reply, warp::Rejection> {
-    let topics = fetch_topics(&pool);
+#[derive(Serialize, Debug, PartialEq)]
+struct DailyOpenings {
+    day: String,
+    openings: usize,
+}
 
-    let topics: HashMap<String, StatusType> = match topics {
-        None => return Ok(warp::reply::html("No topics configured")),
-        Some(t) => t,
-    };
+#[derive(Serialize)]
+struct ReadingView {
+    title: String,
+    /// Lets the live update script find the row that a new reading might replace
+    kind: &'static str,
+    data: SensorData,
+}
 
-    let mut html = html_start();
+pub async fn get_sensor_data_status(
+    pool: SqlitePool,
+    templates: Templates,
+    mould: Option<MouldConfig>,
+    air_quality: AirQualityConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let topics = device_topic_configurations(&pool).map_err(ApiError::from)?;
+    let alerts = active_alerts(&pool).map_err(ApiError::from)?;
+    let critical_alarms = critical::pending(&pool).map_err(ApiError::from)?;
 
+    let mut devices = Vec::new();
     for t in topics {
-        match t.1 {
-            StatusType::Basic => html.push_str(basic(t.0, &pool).as_str()),
-            StatusType::Boolean => html.push_str(boolean(t.0, &pool).as_str()),
-            StatusType::None => println!("No status type configured for topic: {}", t.0),
+        match t.status_type {
+            StatusType::Basic => devices.push(basic(&t, &pool)?),
+            StatusType::Boolean => devices.push(boolean(&t, &pool, &mould, &air_quality, templates.formatter())?),
+            StatusType::Mould => devices.push(mould_risk(&t, &pool, &mould)?),
+            StatusType::Contact => devices.push(contact_state(&t, &pool, templates.formatter())?),
+            StatusType::AirQuality => devices.push(air_quality_bands(&t, &pool, &air_quality)?),
+            StatusType::None => println!("No status type configured for topic: {}", t.topic_name),
         }
     }
 
-    html.push_str(html_end().as_str());
-    let html: &'static str = Box::leak(html.into_boxed_str());
+    let html = templates.render("status.html", context! { devices, alerts, critical_alarms })?;
     Ok(warp::reply::html(html))
 }
 
-fn basic(device_id: String, pool: &SqlitePool) -> String {
-    let conn = get_conn(&pool);
+fn basic(topic: &TopicConfiguration, pool: &SqlitePool) -> Result<DeviceView, ApiError> {
+    let device_id = &topic.topic_name;
+    let online = is_online(pool, device_id);
+    let conn = get_conn(pool);
     println!("Getting basic sensor data for device: {}", device_id);
 
-    let mut html = format!("<div class=\"device-data\"> \
-    <h2>Basic Sensor Data for Device: {}</h2>", device_id);
-
-    let mut stmt = match conn.prepare(
+    let mut stmt = conn.prepare(
         "
         SELECT *, 1 as data_type, 'Lowest Temperature in the Last 3 Days' as title FROM (
-            SELECT * FROM sensor_data WHERE device_id = ?1 AND received_at >= datetime('now', '-3 days') ORDER BY temperature ASC LIMIT 1
+            SELECT * FROM sensor_data WHERE device_id = ?1 AND received_at >= unixepoch('now', '-3 days') * 1000 ORDER BY temperature ASC LIMIT 1
         )
         UNION
         SELECT *, 2 as data_type, 'Highest Temperature in the Last 3 Days' as title FROM (
-            SELECT * FROM sensor_data WHERE device_id = ?1 AND received_at >= datetime('now', '-3 days') ORDER BY temperature DESC LIMIT 1
+            SELECT * FROM sensor_data WHERE device_id = ?1 AND received_at >= unixepoch('now', '-3 days') * 1000 ORDER BY temperature DESC LIMIT 1
         )
         UNION
         SELECT *, 3 as data_type, 'Lowest Humidity in the Last 3 Days' as title FROM (
-            SELECT * FROM sensor_data WHERE device_id = ?1 AND received_at >= datetime('now', '-3 days') ORDER BY humidity ASC LIMIT 1
+            SELECT * FROM sensor_data WHERE device_id = ?1 AND received_at >= unixepoch('now', '-3 days') * 1000 ORDER BY humidity ASC LIMIT 1
         )
         UNION
         SELECT *, 4 as data_type, 'Highest Humidity in the Last 3 Days' as title FROM (
-            SELECT * FROM sensor_data WHERE device_id = ?1 AND received_at >= datetime('now', '-3 days') ORDER BY humidity DESC LIMIT 1
+            SELECT * FROM sensor_data WHERE device_id = ?1 AND received_at >= unixepoch('now', '-3 days') * 1000 ORDER BY humidity DESC LIMIT 1
         )
         UNION
         SELECT *, 5 as data_type, 'Latest Reading' as title FROM (
@@ -61,130 +135,216 @@ fn basic(device_id: String, pool: &SqlitePool) -> String {
         )
         ORDER BY data_type;
     "
-    ) {
-        Ok(stmt) => stmt,
-        Err(_) => return format!("Error preparing query for device: {}", device_id).to_string()
-    };
+    )?;
 
-    let query_data = match stmt.query_map([&device_id], |row| {
-        let sensor_data = SensorData::from_row(row)?;
-        let title: String = row.get("title")?;
-        Ok((sensor_data, title))
-    }) {
-        Ok(iter) => iter,
-        Err(_) => return format!("Error querying data for device: {}", device_id).to_string()
-    };
+    let readings = stmt
+        .query_map([device_id], |row| {
+            let data = SensorData::from_row(row)?;
+            let title: String = row.get("title")?;
+            let data_type: i32 = row.get("data_type")?;
+            let kind = match data_type {
+                1 => "min-temperature",
+                2 => "max-temperature",
+                3 => "min-humidity",
+                4 => "max-humidity",
+                _ => "latest",
+            };
+            Ok(ReadingView { title, kind, data })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
 
-    let mut sensor_data_with_titles = Vec::new();
-    for result in query_data {
-        match result {
-            Ok((data, title)) => {
-                sensor_data_with_titles.push((data, title));
-            }
-            Err(_) => return format!("Error fetching data for device: {}", device_id).to_string()
+    Ok(DeviceView::Basic {
+        device_id: device_id.clone(),
+        title: topic.title().to_string(),
+        online,
+        readings,
+    })
+}
+
+fn boolean(
+    topic: &TopicConfiguration,
+    pool: &SqlitePool,
+    mould: &Option<MouldConfig>,
+    air_quality: &AirQualityConfig,
+    formatter: &Formatter,
+) -> Result<DeviceView, ApiError> {
+    let device_id = &topic.topic_name;
+    let online = is_online(pool, device_id);
+    println!("Getting boolean sensor data for device: {}", device_id);
+
+    let latest = get_conn(pool)
+        .query_row(
+            "SELECT * FROM sensor_data WHERE device_id = ?1 ORDER BY received_at DESC LIMIT 1",
+            [device_id],
+            SensorData::from_row,
+        )
+        .optional()?;
+
+    let metric = topic.limit_metric.unwrap_or(Metric::Temperature);
+    let value = match &latest {
+        Some(data) => metric_value(pool, mould, air_quality, data, metric)?,
+        None => None,
+    };
+    let limit_hit =
+        value.is_some_and(|value| outside_limits(value, topic.min_limit, topic.max_limit));
+    // Limits of temperature metrics are in °C like the readings
+    let format = |value: f64| {
+        if metric.is_temperature() {
+            formatter.temperature(value)
+        } else {
+            format!(
+                "{}{}",
+                formatter.number(value),
+                topic.unit.as_deref().unwrap_or_default()
+            )
         }
-    }
+    };
+    let limits = match (topic.min_limit, topic.max_limit) {
+        (Some(min), Some(max)) => format!("{} - {}", format(min), format(max)),
+        (Some(min), None) => format!("at least {}", format(min)),
+        (None, Some(max)) => format!("at most {}", format(max)),
+        (None, None) => "none".to_string(),
+    };
 
-    if sensor_data_with_titles.len() == 0 {
-        return format!("No data found for device: {}", device_id);
+    Ok(DeviceView::Boolean {
+        device_id: device_id.clone(),
+        title: topic.title().to_string(),
+        online,
+        limit_hit,
+        latest,
+        metric: metric.as_str(),
+        value: value.map(format),
+        limits,
+    })
+}
+
+/// The mould metrics are only available when the mould risk is configured,
+/// the air quality metrics come from the latest air quality report
+fn metric_value(
+    pool: &SqlitePool,
+    mould: &Option<MouldConfig>,
+    air_quality: &AirQualityConfig,
+    data: &SensorData,
+    metric: Metric,
+) -> Result<Option<f64>, ApiError> {
+    if let Some(value) = metric.value(data) {
+        return Ok(Some(value));
+    }
+    if let Some((_, report)) = air_quality::latest(pool, &data.device_id)? {
+        let value = report
+            .values(air_quality)
+            .into_iter()
+            .find(|(m, _)| *m == metric)
+            .map(|(_, value)| value);
+        if value.is_some() {
+            return Ok(value);
+        }
     }
 
-    html.push_str(format!(
-        "
-            {}",
-        sensor_data_with_titles
-            .iter()
-            .map(|(data, title)| create_table(data, title))
-            .collect::<String>()
-    ).as_str());
-
-    html.push_str("</div>");
-    html
+    let assessment = match mould {
+        Some(mould) => mould::assess(&get_conn(pool), mould, &data.device_id)?,
+        None => None,
+    };
+    Ok(assessment.and_then(|assessment| {
+        assessment
+            .values()
+            .into_iter()
+            .find(|(m, _)| *m == metric)
+            .map(|(_, value)| value)
+    }))
 }
 
-fn boolean(topic: String, pool: &SqlitePool) -> String {
-    "".to_string()
-}
+fn mould_risk(
+    topic: &TopicConfiguration,
+    pool: &SqlitePool,
+    mould: &Option<MouldConfig>,
+) -> Result<DeviceView, ApiError> {
+    let device_id = &topic.topic_name;
+    println!("Getting mould risk for device: {}", device_id);
+
+    let assessment = match mould {
+        Some(mould) => mould::assess(&get_conn(pool), mould, device_id)?,
+        None => None,
+    };
 
-fn create_table(sensor_data: &SensorData, title: &str) -> String {
-    format!(
-        "<h3>{}</h3>\
-        <table border = \"1\">\
-        <tr>\
-            <th>Temperature</th>\
-            <th>Humidity</th>\
-            <th>Link Quality</th>\
-            <th>Device ID</th>\
-            <th>Received At</th>\
-        </tr>\
-        <tr>
-            <td>{}</td>
-            <td>{}</td>
-            <td>{}</td>
-            <td>{}</td>
-            <td>{}</td>
-        </tr>
-        </table>",
-        title,
-        sensor_data.temperature,
-        sensor_data.humidity,
-        sensor_data.linkquality,
-        sensor_data.device_id,
-        sensor_data.received_at
-    )
+    Ok(DeviceView::Mould {
+        device_id: device_id.clone(),
+        title: topic.title().to_string(),
+        online: is_online(pool, device_id),
+        assessment,
+        configured: mould.is_some(),
+    })
 }
 
-fn fetch_topics(pool: &SqlitePool) -> Option<HashMap<String, StatusType>> {
-    let conn = get_conn(pool);
-    let mut stmt = conn
-        .prepare("SELECT topic_name, status_type FROM topic_configuration \
-        order by status_type, topic_name;")
-        .ok()?;
-
-    let query_data = stmt
-        .query_map([], |row| {
-            let topic: String = row.get("topic_name")?;
-            let status_type = match row.get::<_, String>("status_type")?.as_str() {
-                "basic" => StatusType::Basic,
-                "boolean" => StatusType::Boolean,
-                _ => StatusType::None,
-            };
-            Ok((topic, status_type))
-        })
-        .ok()?;
+/// Days of open counts on the status page
+const OPENINGS_DAYS: i64 = 7;
+
+fn contact_state(
+    topic: &TopicConfiguration,
+    pool: &SqlitePool,
+    formatter: &Formatter,
+) -> Result<DeviceView, ApiError> {
+    let device_id = &topic.topic_name;
+    println!("Getting contact state for device: {}", device_id);
 
-    Some(query_data.filter_map(Result::ok).collect())
+    let latest = contact::latest(&get_conn(pool), device_id)?;
+    let now = Utc::now();
+    let open_minutes = latest
+        .as_ref()
+        .filter(|event| event.open)
+        .map(|event| (now - event.received_at).num_minutes().max(0));
+    // One extra day covers the start of the oldest local day
+    let events = contact::events(
+        pool,
+        Some(device_id),
+        &format!("-{} days", OPENINGS_DAYS + 1),
+    )?;
+    let today = formatter.local(now).date();
+
+    Ok(DeviceView::Contact {
+        device_id: device_id.clone(),
+        title: topic.title().to_string(),
+        online: is_online(pool, device_id),
+        latest,
+        open_minutes,
+        daily: daily_openings(&events, formatter, today),
+    })
 }
 
-fn html_start() -> String {
-    "<html>
-        <head>
-            <title>Sensor Data Status</title>
-            <style>
-                body {{
-                    font-family: Arial, sans-serif;
-                }}
-                table {{
-                    border-collapse: collapse;
-                }}
-                th, td {{
-                    padding: 8px;
-                }}
-                .device-data {
-                    border: 1px solid #ddd;
-                    margin-bottom: 20px;
-                    padding: 10px;
-                    border-radius: 5px;
-                    box-shadow: 0 2px 4px rgba(0, 0, 0, 0.1);
-                }
-            </style>
-        </head>
-        <body>
-            <h1>Sensor Data Status</h1>
-    "
-    .to_string()
+fn daily_openings(
+    events: &[ContactEvent],
+    formatter: &Formatter,
+    today: NaiveDate,
+) -> Vec<DailyOpenings> {
+    (0..OPENINGS_DAYS)
+        .map(|days_ago| {
+            let day = today - Duration::days(days_ago);
+            DailyOpenings {
+                day: day.to_string(),
+                openings: events
+                    .iter()
+                    .filter(|event| event.open && formatter.local(event.received_at).date() == day)
+                    .count(),
+            }
+        })
+        .collect()
 }
 
-fn html_end() -> String {
-    "</body></html>".to_string()
+fn air_quality_bands(
+    topic: &TopicConfiguration,
+    pool: &SqlitePool,
+    config: &AirQualityConfig,
+) -> Result<DeviceView, ApiError> {
+    let device_id = &topic.topic_name;
+    println!("Getting air quality for device: {}", device_id);
+
+    let latest = air_quality::latest(pool, device_id)?;
+
+    Ok(DeviceView::AirQuality {
+        device_id: device_id.clone(),
+        title: topic.title().to_string(),
+        online: is_online(pool, device_id),
+        received_at: latest.as_ref().map(|(received_at, _)| *received_at),
+        assessment: latest.and_then(|(_, report)| report.assess(config)),
+    })
 }
@@ -0,0 +1,204 @@
+use crate::timestamps;
+use crate::web::display::Formatter;
+use crate::web::error::ApiError;
+use minijinja::Environment;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Templates compiled into the binary, `templates_dir` can override each of them by name
+const EMBEDDED_TEMPLATES: &[(&str, &str)] = &[
+    ("battery.html", include_str!("templates/battery.html")),
+    ("device.html", include_str!("templates/device.html")),
+    ("energy.html", include_str!("templates/energy.html")),
+    ("energy_device.html", include_str!("templates/energy_device.html")),
+    ("layout.html", include_str!("templates/layout.html")),
+    ("macros.html", include_str!("templates/macros.html")),
+    ("network.html", include_str!("templates/network.html")),
+    ("status.html", include_str!("templates/status.html")),
+];
+
+/// HTML templates, escaping is on for every template ending in `.html`.
+/// The `temperature`, `number` and `datetime` filters format values with the display configuration.
+#[derive(Clone)]
+pub struct Templates {
+    env: Arc<Environment<'static>>,
+    formatter: Formatter,
+}
+
+impl Templates {
+    /// Templates in `dir` take precedence over the embedded ones.
+    /// Every template is parsed here so mistakes show up when starting instead of on a request.
+    pub fn load(dir: Option<&str>, formatter: Formatter) -> Result<Templates, minijinja::Error> {
+        let dir = dir.map(PathBuf::from);
+        let mut env = Environment::new();
+
+        // Missing values, e.g. a dew point at 0 % humidity, are shown as "-"
+        let f = formatter.clone();
+        env.add_filter("temperature", move |celsius: Option<f64>| {
+            celsius.map_or("-".to_string(), |celsius| f.temperature(celsius))
+        });
+        let f = formatter.clone();
+        env.add_filter("number", move |value: Option<f64>| {
+            value.map_or("-".to_string(), |value| f.number(value))
+        });
+        let f = formatter.clone();
+        // Timestamps that can't be parsed are shown as they are
+        env.add_filter("datetime", move |value: Option<String>| match value {
+            Some(value) => match timestamps::parse(&value) {
+                Some(time) => f.datetime(time),
+                None => value,
+            },
+            None => "-".to_string(),
+        });
+        env.add_global("precision", formatter.precision());
+
+        env.set_loader(move |name| {
+            if let Some(dir) = &dir {
+                let path = dir.join(name);
+                if path.is_file() {
+                    println!("Using template {}", path.display());
+                    return std::fs::read_to_string(&path).map(Some).map_err(|e| {
+                        minijinja::Error::new(
+                            minijinja::ErrorKind::InvalidOperation,
+                            format!("Failed to read template {}", path.display()),
+                        )
+                        .with_source(e)
+                    });
+                }
+            }
+
+            Ok(EMBEDDED_TEMPLATES
+                .iter()
+                .find(|(embedded, _)| *embedded == name)
+                .map(|(_, source)| source.to_string()))
+        });
+
+        for (name, _) in EMBEDDED_TEMPLATES {
+            env.get_template(name)?;
+        }
+
+        Ok(Templates {
+            env: Arc::new(env),
+            formatter,
+        })
+    }
+
+    pub fn formatter(&self) -> &Formatter {
+        &self.formatter
+    }
+
+    pub fn render<S: Serialize>(&self, name: &str, context: S) -> Result<String, ApiError> {
+        let template = self.env.get_template(name)?;
+        Ok(template.render(context)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DisplayConfig, TemperatureUnit};
+    use minijinja::context;
+
+    fn formatter() -> Formatter {
+        Formatter::new(&DisplayConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_embedded_templates_escape_html() {
+        let templates = Templates::load(None, formatter()).unwrap();
+        let html = templates
+            .render(
+                "status.html",
+                context! { alerts => Vec::<String>::new(), devices => vec![context! {
+                    status_type => "basic",
+                    device_id => "kitchen",
+                    title => "<script>alert(1)</script>",
+                    online => true,
+                    readings => Vec::<String>::new(),
+                }] },
+            )
+            .unwrap();
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;&#x2f;script&gt;"));
+        assert!(html.contains("No data found for device: kitchen"));
+        assert!(html.contains("device-status online"));
+
+        let html = templates
+            .render(
+                "status.html",
+                context! { alerts => Vec::<String>::new(), devices => Vec::<String>::new() },
+            )
+            .unwrap();
+        assert!(html.contains("No topics configured"));
+    }
+
+    #[test]
+    fn test_values_use_display_configuration() {
+        let formatter = Formatter::new(&DisplayConfig {
+            temperature_unit: TemperatureUnit::Fahrenheit,
+            precision: 1,
+            datetime_format: "%d.%m.%Y %H:%M".to_string(),
+            timezone: Some("Europe/Helsinki".to_string()),
+        })
+        .unwrap();
+        let templates = Templates::load(None, formatter).unwrap();
+        let html = templates
+            .render(
+                "status.html",
+                context! { alerts => Vec::<String>::new(), devices => vec![context! {
+                    status_type => "basic",
+                    device_id => "kitchen",
+                    title => "Kitchen",
+                    online => true,
+                    readings => vec![context! {
+                        title => "Latest Reading",
+                        kind => "latest",
+                        data => context! {
+                            temperature => 20.0,
+                            humidity => 50,
+                            linkquality => 100,
+                            device_id => "kitchen",
+                            received_at => "2024-07-01T12:00:00Z",
+                        },
+                    }],
+                }] },
+            )
+            .unwrap();
+        assert!(html.contains("<td>68.0 °F</td>"));
+        assert!(html.contains("<td>01.07.2024 15:00</td>"));
+        assert!(html.contains("const precision = 1;"));
+    }
+
+    #[test]
+    fn test_override_from_directory() {
+        let dir =
+            std::env::temp_dir().join(format!("ru-berry-templates-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("layout.html"),
+            "<main>{% block content %}{% endblock %}</main>",
+        )
+        .unwrap();
+
+        let templates = Templates::load(dir.to_str(), formatter()).unwrap();
+        let html = templates
+            .render(
+                "status.html",
+                context! { devices => Vec::<String>::new(), alerts => vec![context! {
+                    rule => "<b>rule</b>",
+                    device_id => "kitchen",
+                    value => 1.0,
+                    started_at => "now",
+                }] },
+            )
+            .unwrap();
+        assert!(html.starts_with("<main>"));
+        assert!(html.contains("&lt;b&gt;rule&lt;"));
+        assert!(!html.contains("<b>rule</b>"));
+
+        std::fs::write(dir.join("status.html"), "{% if %}").unwrap();
+        assert!(Templates::load(dir.to_str(), formatter()).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,442 @@
+use crate::alerts::Metric;
+use crate::conn::{get_conn, SqlitePool};
+use crate::devices;
+use crate::mapping;
+use crate::model::{StatusType, TopicConfiguration};
+use crate::web::error::ApiError;
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+const SELECT_TOPIC_CONFIGURATION: &str = "SELECT id, topic_name, status_type, display_name, \
+    limit_metric, min_limit, max_limit, unit, position FROM topic_configuration";
+
+/// Body for creating and updating a topic configuration.
+/// Unknown status types and metrics are rejected when deserializing.
+#[derive(Deserialize, Debug)]
+pub struct TopicConfigurationInput {
+    topic_name: String,
+    status_type: StatusType,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    limit_metric: Option<Metric>,
+    #[serde(default)]
+    min_limit: Option<f64>,
+    #[serde(default)]
+    max_limit: Option<f64>,
+    #[serde(default)]
+    unit: Option<String>,
+}
+
+impl TopicConfigurationInput {
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.topic_name.trim().is_empty() {
+            return Err("topic_name can't be empty");
+        }
+        if !mapping::is_valid_filter(self.topic_name.trim()) {
+            return Err("wildcards in topic_name have to be whole levels and # has to be last");
+        }
+        if let (Some(min), Some(max)) = (self.min_limit, self.max_limit) {
+            if min > max {
+                return Err("min_limit can't be greater than max_limit");
+            }
+        }
+        if self.status_type == StatusType::Boolean
+            && self.min_limit.is_none()
+            && self.max_limit.is_none()
+        {
+            return Err("boolean status type needs min_limit or max_limit");
+        }
+        Ok(())
+    }
+
+    /// Empty strings from the admin page mean that the value is not set
+    fn non_empty(value: &Option<String>) -> Option<&str> {
+        value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+    }
+}
+
+pub fn list_topic_configurations(pool: &SqlitePool) -> rusqlite::Result<Vec<TopicConfiguration>> {
+    let conn = get_conn(pool);
+    let mut stmt = conn.prepare(&format!("{} ORDER BY position, id", SELECT_TOPIC_CONFIGURATION))?;
+    let topics = stmt.query_map([], TopicConfiguration::from_row)?.collect();
+    topics
+}
+
+fn find_topic_configuration(
+    pool: &SqlitePool,
+    id: i64,
+) -> rusqlite::Result<Option<TopicConfiguration>> {
+    let conn = get_conn(pool);
+    conn.query_row(
+        &format!("{} WHERE id = ?1", SELECT_TOPIC_CONFIGURATION),
+        [id],
+        TopicConfiguration::from_row,
+    )
+    .optional()
+}
+
+/// The configuration for the device from a wildcard topic configuration
+fn for_device(topic: &TopicConfiguration, device_id: &str) -> TopicConfiguration {
+    TopicConfiguration {
+        topic_name: device_id.to_string(),
+        // The display name of the wildcard would be the same for every device
+        display_name: None,
+        ..topic.clone()
+    }
+}
+
+/// Topic configurations with the wildcard ones replaced by one for every device that sent
+/// messages on a matching topic. Devices with their own topic configuration are skipped.
+pub fn device_topic_configurations(pool: &SqlitePool) -> rusqlite::Result<Vec<TopicConfiguration>> {
+    let topics = list_topic_configurations(pool)?;
+    if !topics.iter().any(|t| mapping::is_wildcard(&t.topic_name)) {
+        return Ok(topics);
+    }
+
+    let device_topics = devices::topics(pool)?;
+    let mut configured: Vec<&str> = topics
+        .iter()
+        .filter(|t| !mapping::is_wildcard(&t.topic_name))
+        .map(|t| t.topic_name.as_str())
+        .collect();
+    let mut resolved = Vec::new();
+    for t in &topics {
+        if !mapping::is_wildcard(&t.topic_name) {
+            resolved.push(t.clone());
+            continue;
+        }
+        for (device_id, topic) in &device_topics {
+            if mapping::matches(&t.topic_name, topic) && !configured.contains(&device_id.as_str()) {
+                configured.push(device_id);
+                resolved.push(for_device(t, device_id));
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// The topic configuration of the device, or the first wildcard one that matches its topic
+pub fn find_topic_configuration_for_device(
+    pool: &SqlitePool,
+    device_id: &str,
+) -> rusqlite::Result<Option<TopicConfiguration>> {
+    let topic = get_conn(pool)
+        .query_row(
+            &format!("{} WHERE topic_name = ?1", SELECT_TOPIC_CONFIGURATION),
+            [device_id],
+            TopicConfiguration::from_row,
+        )
+        .optional()?;
+    if topic.is_some() {
+        return Ok(topic);
+    }
+
+    let device_topic = devices::topics(pool)?
+        .into_iter()
+        .find(|(id, _)| id == device_id)
+        .map(|(_, topic)| topic);
+    let Some(device_topic) = device_topic else {
+        return Ok(None);
+    };
+    Ok(list_topic_configurations(pool)?
+        .iter()
+        .find(|t| mapping::is_wildcard(&t.topic_name) && mapping::matches(&t.topic_name, &device_topic))
+        .map(|t| for_device(t, device_id)))
+}
+
+fn insert_topic_configuration(
+    pool: &SqlitePool,
+    input: &TopicConfigurationInput,
+) -> rusqlite::Result<i64> {
+    let conn = get_conn(pool);
+    conn.execute(
+        "INSERT INTO topic_configuration
+        (topic_name, status_type, display_name, limit_metric, min_limit, max_limit, unit, position)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7,
+            (SELECT COALESCE(MAX(position) + 1, 0) FROM topic_configuration))",
+        params![
+            input.topic_name.trim(),
+            input.status_type.as_str(),
+            TopicConfigurationInput::non_empty(&input.display_name),
+            input.limit_metric.map(|m| m.as_str()),
+            input.min_limit,
+            input.max_limit,
+            TopicConfigurationInput::non_empty(&input.unit),
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Returns false if there is no topic configuration with the id
+fn update_topic_configuration(
+    pool: &SqlitePool,
+    id: i64,
+    input: &TopicConfigurationInput,
+) -> rusqlite::Result<bool> {
+    let conn = get_conn(pool);
+    let updated = conn.execute(
+        "UPDATE topic_configuration SET topic_name = ?2, status_type = ?3, display_name = ?4,
+        limit_metric = ?5, min_limit = ?6, max_limit = ?7, unit = ?8
+        WHERE id = ?1",
+        params![
+            id,
+            input.topic_name.trim(),
+            input.status_type.as_str(),
+            TopicConfigurationInput::non_empty(&input.display_name),
+            input.limit_metric.map(|m| m.as_str()),
+            input.min_limit,
+            input.max_limit,
+            TopicConfigurationInput::non_empty(&input.unit),
+        ],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Set the order of the status page, `ids` first to last. Returns false and changes
+/// nothing unless `ids` has every topic configuration exactly once.
+fn reorder(pool: &SqlitePool, ids: &[i64]) -> rusqlite::Result<bool> {
+    let mut conn = get_conn(pool);
+    let tx = conn.transaction()?;
+    let mut existing = tx
+        .prepare("SELECT id FROM topic_configuration ORDER BY id")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut requested = ids.to_vec();
+    existing.sort_unstable();
+    requested.sort_unstable();
+    if requested != existing {
+        return Ok(false);
+    }
+
+    for (position, id) in ids.iter().enumerate() {
+        tx.execute(
+            "UPDATE topic_configuration SET position = ?1 WHERE id = ?2",
+            params![position as i64, id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+pub async fn get_topic_configurations(pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    let topics = list_topic_configurations(&pool).map_err(ApiError::from)?;
+    Ok(warp::reply::json(&topics))
+}
+
+pub async fn create_topic_configuration(
+    input: TopicConfigurationInput,
+    pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    input
+        .validate()
+        .map_err(|message| ApiError::BadRequest(message.to_string()))?;
+
+    let topic = insert_topic_configuration(&pool, &input)
+        .and_then(|id| find_topic_configuration(&pool, id))
+        .map_err(ApiError::from)?;
+    println!("Added topic configuration for {}", input.topic_name);
+
+    Ok(warp::reply::with_status(warp::reply::json(&topic), StatusCode::CREATED))
+}
+
+pub async fn put_topic_configuration(
+    id: i64,
+    input: TopicConfigurationInput,
+    pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    input
+        .validate()
+        .map_err(|message| ApiError::BadRequest(message.to_string()))?;
+
+    let updated = update_topic_configuration(&pool, id, &input).map_err(ApiError::from)?;
+    if !updated {
+        return Err(ApiError::NotFound.into());
+    }
+
+    let topic = find_topic_configuration(&pool, id).map_err(ApiError::from)?;
+    Ok(warp::reply::json(&topic))
+}
+
+pub async fn delete_topic_configuration(id: i64, pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    let conn = get_conn(&pool);
+    let deleted = conn
+        .execute("DELETE FROM topic_configuration WHERE id = ?1", [id])
+        .map_err(ApiError::from)?;
+
+    if deleted == 0 {
+        return Err(ApiError::NotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn reorder_topic_configurations(
+    ids: Vec<i64>,
+    pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if !reorder(&pool, &ids).map_err(ApiError::from)? {
+        return Err(ApiError::BadRequest(
+            "ids must contain every topic configuration exactly once".to_string(),
+        )
+        .into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::get_test_pool;
+
+    fn input(topic_name: &str, status_type: StatusType) -> TopicConfigurationInput {
+        TopicConfigurationInput {
+            topic_name: topic_name.to_string(),
+            status_type,
+            display_name: Some("".to_string()),
+            limit_metric: None,
+            min_limit: None,
+            max_limit: None,
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn test_status_type_is_validated() {
+        let result = serde_json::from_str::<TopicConfigurationInput>(
+            r#"{"topic_name": "bathroom", "status_type": "fancy"}"#,
+        );
+        assert!(result.is_err());
+
+        let result = serde_json::from_str::<TopicConfigurationInput>(
+            r#"{"topic_name": "bathroom", "status_type": "none"}"#,
+        );
+        assert!(result.is_err());
+
+        let result = serde_json::from_str::<TopicConfigurationInput>(
+            r#"{"topic_name": "bathroom", "status_type": "basic"}"#,
+        );
+        assert_eq!(result.unwrap().status_type, StatusType::Basic);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(input("bathroom", StatusType::Basic).validate().is_ok());
+        assert!(input(" ", StatusType::Basic).validate().is_err());
+        assert!(input("bathroom", StatusType::Boolean).validate().is_err());
+
+        assert!(input("zigbee2mqtt/+", StatusType::Basic).validate().is_ok());
+        assert!(input("zigbee2mqtt/#/state", StatusType::Basic).validate().is_err());
+
+        let mut limits = input("bathroom", StatusType::Boolean);
+        limits.min_limit = Some(30.0);
+        limits.max_limit = Some(20.0);
+        assert!(limits.validate().is_err());
+
+        limits.max_limit = Some(60.0);
+        assert!(limits.validate().is_ok());
+    }
+
+    #[test]
+    fn test_insert_update_and_delete() {
+        let pool = get_test_pool();
+
+        let id = insert_topic_configuration(&pool, &input("bathroom", StatusType::Basic)).unwrap();
+        let topic = find_topic_configuration(&pool, id).unwrap().unwrap();
+        assert_eq!(topic.topic_name, "bathroom");
+        // Empty display name is not stored
+        assert_eq!(topic.display_name, None);
+        assert_eq!(topic.title(), "bathroom");
+
+        let mut updated = input("bathroom", StatusType::Boolean);
+        updated.display_name = Some("Bathroom".to_string());
+        updated.limit_metric = Some(Metric::Humidity);
+        updated.max_limit = Some(70.0);
+        updated.unit = Some("%".to_string());
+        assert!(update_topic_configuration(&pool, id, &updated).unwrap());
+        assert!(!update_topic_configuration(&pool, id + 1, &updated).unwrap());
+
+        let topic = find_topic_configuration(&pool, id).unwrap().unwrap();
+        assert_eq!(topic.status_type, StatusType::Boolean);
+        assert_eq!(topic.title(), "Bathroom");
+        assert_eq!(topic.limit_metric, Some(Metric::Humidity));
+        assert_eq!(topic.max_limit, Some(70.0));
+
+        get_conn(&pool)
+            .execute("DELETE FROM topic_configuration WHERE id = ?1", [id])
+            .unwrap();
+        assert!(list_topic_configurations(&pool).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reorder() {
+        let pool = get_test_pool();
+        let first = insert_topic_configuration(&pool, &input("first", StatusType::Basic)).unwrap();
+        let second = insert_topic_configuration(&pool, &input("second", StatusType::Basic)).unwrap();
+        let third = insert_topic_configuration(&pool, &input("third", StatusType::Basic)).unwrap();
+
+        let names = |pool: &SqlitePool| -> Vec<String> {
+            list_topic_configurations(pool)
+                .unwrap()
+                .into_iter()
+                .map(|t| t.topic_name)
+                .collect()
+        };
+        assert_eq!(names(&pool), ["first", "second", "third"]);
+
+        assert!(reorder(&pool, &[third, first, second]).unwrap());
+        assert_eq!(names(&pool), ["third", "first", "second"]);
+
+        assert!(!reorder(&pool, &[first, second]).unwrap());
+        assert!(!reorder(&pool, &[first, first, second]).unwrap());
+        assert!(!reorder(&pool, &[first, second, third, third + 1]).unwrap());
+        assert_eq!(names(&pool), ["third", "first", "second"]);
+    }
+
+    #[test]
+    fn test_wildcard_topic_configurations() {
+        let pool = get_test_pool();
+        for (device_id, topic) in [
+            ("bathroom", "zigbee2mqtt/bathroom"),
+            ("kitchen", "zigbee2mqtt/kitchen"),
+            ("attic", "home/attic/climate"),
+        ] {
+            devices::mark_seen(&get_conn(&pool), device_id, topic, chrono::Utc::now(), 120).unwrap();
+        }
+
+        let mut bathroom = input("bathroom", StatusType::Mould);
+        bathroom.display_name = Some("Bathroom".to_string());
+        insert_topic_configuration(&pool, &bathroom).unwrap();
+        let mut wildcard = input("zigbee2mqtt/+", StatusType::Basic);
+        wildcard.display_name = Some("Sensors".to_string());
+        insert_topic_configuration(&pool, &wildcard).unwrap();
+
+        let topics = device_topic_configurations(&pool).unwrap();
+        let names: Vec<_> = topics.iter().map(|t| t.title()).collect();
+        assert_eq!(names, ["Bathroom", "kitchen"]);
+        assert_eq!(topics[1].status_type, StatusType::Basic);
+
+        let kitchen = find_topic_configuration_for_device(&pool, "kitchen").unwrap().unwrap();
+        assert_eq!(kitchen.topic_name, "kitchen");
+        let bathroom = find_topic_configuration_for_device(&pool, "bathroom").unwrap().unwrap();
+        assert_eq!(bathroom.status_type, StatusType::Mould);
+        assert!(find_topic_configuration_for_device(&pool, "attic").unwrap().is_none());
+        // The API still returns the wildcard
+        assert_eq!(list_topic_configurations(&pool).unwrap()[1].topic_name, "zigbee2mqtt/+");
+    }
+
+    #[test]
+    fn test_unknown_status_type_in_database() {
+        let pool = get_test_pool();
+        get_conn(&pool)
+            .execute(
+                "INSERT INTO topic_configuration (topic_name, status_type) VALUES ('old', 'graph')",
+                [],
+            )
+            .unwrap();
+
+        let topics = list_topic_configurations(&pool).unwrap();
+        assert_eq!(topics[0].status_type, StatusType::None);
+    }
+}
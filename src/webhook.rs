@@ -0,0 +1,129 @@
+use hyper::body::HttpBody;
+use hyper::client::conn;
+use hyper::{Body, Request, StatusCode, Uri};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Webhooks that take longer are given up on
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POSTs `body` as JSON to an `http` or `https` URL, certificates are verified
+/// with the root certificates of the system
+pub async fn post(url: &str, body: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    tokio::time::timeout(TIMEOUT, send(url, body))
+        .await
+        .map_err(|_| format!("Webhook {} timed out", url))?
+}
+
+async fn send(url: &str, body: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let uri: Uri = url.parse()?;
+    let host = uri.host().ok_or("Webhook URL has no host")?.to_string();
+    let https = match uri.scheme_str() {
+        Some("http") => false,
+        Some("https") => true,
+        _ => return Err(format!("Webhook URL {} is not http or https", url).into()),
+    };
+    let default_port = if https { 443 } else { 80 };
+    let port = uri.port_u16().unwrap_or(default_port);
+    // Virtual hosts and reverse proxies need the port unless it is the default one
+    let host_header = if port == default_port {
+        host.clone()
+    } else {
+        format!("{}:{}", host, port)
+    };
+
+    let request = Request::post(uri.path_and_query().map_or("/", |p| p.as_str()))
+        .header(hyper::header::HOST, host_header)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))?;
+
+    let stream = TcpStream::connect((host.as_str(), port)).await?;
+    let status = if https {
+        let stream = tls_connector()?
+            .connect(ServerName::try_from(host)?, stream)
+            .await?;
+        send_request(stream, request).await?
+    } else {
+        send_request(stream, request).await?
+    };
+
+    if !status.is_success() {
+        return Err(format!("Webhook {} responded with {}", url, status).into());
+    }
+    Ok(())
+}
+
+async fn send_request<S>(
+    stream: S,
+    request: Request<Body>,
+) -> Result<StatusCode, Box<dyn Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(connection);
+    let mut response = sender.send_request(request).await?;
+    // The connection is closed once the response has been read
+    while response.body_mut().data().await.is_some() {}
+    Ok(response.status())
+}
+
+fn tls_connector() -> Result<TlsConnector, Box<dyn Error + Send + Sync>> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs()? {
+        // Certificates that rustls doesn't support are skipped like browsers do
+        let _ = roots.add(cert);
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_post_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = String::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with('}') {
+                let length = stream.read(&mut buffer).await.unwrap();
+                request.push_str(&String::from_utf8_lossy(&buffer[..length]));
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            request
+        });
+
+        post(
+            &format!("http://127.0.0.1:{}/hooks/button", port),
+            r#"{"action":"single"}"#.to_string(),
+        )
+        .await
+        .unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hooks/button HTTP/1.1"));
+        assert!(request
+            .to_lowercase()
+            .contains(&format!("host: 127.0.0.1:{}\r\n", port)));
+        assert!(request.ends_with(r#"{"action":"single"}"#));
+
+        assert!(post("ftp://127.0.0.1/", String::new()).await.is_err());
+    }
+}